@@ -34,3 +34,195 @@ CREATE TABLE refresh_tokens (
     revocation_time timestamp,
     token varchar NOT NULL
 );
+
+-- Add token families to refresh tokens, allowing rotation and reuse detection.
+-- down: ALTER TABLE refresh_tokens DROP COLUMN parent_id, DROP COLUMN family;
+CREATE EXTENSION IF NOT EXISTS pgcrypto;
+ALTER TABLE refresh_tokens
+    ADD COLUMN family UUID DEFAULT gen_random_uuid() NOT NULL,
+    ADD COLUMN parent_id integer REFERENCES refresh_tokens (id);
+CREATE INDEX refresh_tokens_family_idx ON refresh_tokens (family);
+CREATE INDEX refresh_tokens_parent_id_idx ON refresh_tokens (parent_id);
+
+-- Generate account and refresh token IDs, which inserts leave to the database.
+-- down: ALTER TABLE accounts ALTER COLUMN id DROP DEFAULT; ALTER TABLE refresh_tokens ALTER COLUMN id DROP DEFAULT; DROP SEQUENCE accounts_id_seq, refresh_tokens_id_seq;
+CREATE SEQUENCE accounts_id_seq OWNED BY accounts.id;
+SELECT setval('accounts_id_seq', coalesce(max(id), 0) + 1, false) FROM accounts;
+ALTER TABLE accounts ALTER COLUMN id SET DEFAULT nextval('accounts_id_seq');
+CREATE SEQUENCE refresh_tokens_id_seq OWNED BY refresh_tokens.id;
+SELECT setval('refresh_tokens_id_seq', coalesce(max(id), 0) + 1, false) FROM refresh_tokens;
+ALTER TABLE refresh_tokens ALTER COLUMN id SET DEFAULT nextval('refresh_tokens_id_seq');
+
+-- Store refresh tokens as keyed HMAC-SHA256 digests, with a short plain-text prefix for lookups.
+-- Existing tokens are digested in place, so the migrating session must first provide the server's
+-- TOKEN_SECRET with: SET auth.token_secret = '<TOKEN_SECRET>';
+-- down: ALTER TABLE refresh_tokens DROP COLUMN token_prefix, DROP COLUMN token_hash, ADD COLUMN token varchar NOT NULL DEFAULT '';
+ALTER TABLE refresh_tokens
+    ADD COLUMN token_prefix varchar,
+    ADD COLUMN token_hash bytea;
+UPDATE refresh_tokens SET
+    token_prefix = left(token, 8),
+    token_hash = hmac(convert_to(token, 'UTF8'), convert_to(current_setting('auth.token_secret'), 'UTF8'), 'sha256');
+ALTER TABLE refresh_tokens
+    ALTER COLUMN token_prefix SET NOT NULL,
+    ALTER COLUMN token_hash SET NOT NULL,
+    DROP COLUMN token;
+CREATE INDEX refresh_tokens_token_prefix_idx ON refresh_tokens (token_prefix);
+
+-- Create signing keys table, recording when each JWT signing key is used and retired.
+-- The private keys themselves are stored as PEM files in JWT_KEYS_DIR, named by kid.
+-- down: DROP TABLE signing_keys;
+CREATE TABLE signing_keys (
+    kid varchar PRIMARY KEY,
+    algorithm varchar NOT NULL,
+    created_at timestamp DEFAULT now() NOT NULL,
+    activates_at timestamp NOT NULL,
+    retires_at timestamp
+);
+
+-- Record the identity provider's unique subject identifier for each identity.
+-- down: ALTER TABLE identities DROP COLUMN subject;
+ALTER TABLE identities ADD COLUMN subject varchar;
+CREATE UNIQUE INDEX identities_source_subject_idx ON identities (source, subject);
+
+-- Replace the IdentitySource enum with the name of a configured identity provider.
+-- down: see the IdentitySource and identities migrations above.
+ALTER TABLE identities ADD COLUMN provider varchar;
+UPDATE identities SET provider = source::text;
+ALTER TABLE identities ALTER COLUMN provider SET NOT NULL;
+-- Dropping the column also drops the primary key and subject index that include it.
+ALTER TABLE identities DROP COLUMN source;
+ALTER TABLE identities ADD PRIMARY KEY (account_id, provider);
+CREATE UNIQUE INDEX identities_provider_subject_idx ON identities (provider, subject);
+DROP TYPE IdentitySource;
+
+-- Record a password identity for every account registered with a password.
+-- down: DELETE FROM identities WHERE provider = 'password';
+INSERT INTO identities (account_id, provider)
+SELECT id, 'password' FROM accounts WHERE hash IS NOT NULL
+ON CONFLICT DO NOTHING;
+
+-- Record when each account's email address was verified.
+-- Accounts with a third-party identity were verified by the identity provider.
+-- down: ALTER TABLE accounts DROP COLUMN email_verified_at;
+ALTER TABLE accounts ADD COLUMN email_verified_at timestamp;
+UPDATE accounts SET email_verified_at = created_at
+WHERE id IN (SELECT account_id FROM identities WHERE provider <> 'password');
+
+-- Create email verification tokens table.
+-- down: DROP TABLE email_verifications;
+CREATE TABLE email_verifications (
+    id serial PRIMARY KEY,
+    account_id integer REFERENCES accounts (id) NOT NULL,
+    email varchar NOT NULL,
+    token_hash bytea NOT NULL UNIQUE,
+    issued_at timestamp NOT NULL,
+    expires timestamp NOT NULL,
+    used_at timestamp
+);
+
+-- Create invitations table, used when registration is invite-only.
+-- down: DROP TABLE invitations;
+CREATE TABLE invitations (
+    id serial PRIMARY KEY,
+    code_hash bytea NOT NULL UNIQUE,
+    inviter_id integer REFERENCES accounts (id),
+    email varchar,
+    max_uses integer NOT NULL,
+    uses integer DEFAULT 0 NOT NULL,
+    expires timestamp,
+    created_at timestamp NOT NULL,
+    revoked_at timestamp
+);
+
+-- Create password reset tokens table.
+-- down: DROP TABLE password_resets;
+CREATE TABLE password_resets (
+    id serial PRIMARY KEY,
+    account_id integer REFERENCES accounts (id) NOT NULL,
+    token_hash bytea NOT NULL UNIQUE,
+    issued_at timestamp NOT NULL,
+    expires timestamp NOT NULL,
+    used_at timestamp
+);
+
+-- Record when each account's password was found in a data breach.
+-- down: ALTER TABLE accounts DROP COLUMN password_breached_at;
+ALTER TABLE accounts ADD COLUMN password_breached_at timestamp;
+
+-- Make account email addresses unique regardless of case. Fails, listing the addresses, if
+-- existing accounts differ only in case; those must be merged or changed by hand first.
+-- Domains are not converted with IDNA here, so this also fails, listing the addresses, if any has
+-- a non-ASCII domain; those must be changed by hand to their canonical form first, with the domain
+-- in Punycode as the server stores it, e.g. anna@xn--bcher-kva.example for anna@bücher.example.
+-- down: DROP INDEX accounts_email_lower_key; ALTER TABLE accounts ADD CONSTRAINT accounts_email_key UNIQUE (email);
+DO $$
+DECLARE
+    collisions text;
+    unicode_domains text;
+BEGIN
+    SELECT string_agg(emails, '; ') INTO collisions FROM (
+        SELECT string_agg(email, ', ' ORDER BY id) AS emails
+        FROM accounts
+        GROUP BY lower(email)
+        HAVING count(*) > 1
+    ) AS collision;
+
+    IF collisions IS NOT NULL THEN
+        RAISE EXCEPTION 'accounts have email addresses differing only in case: %', collisions;
+    END IF;
+
+    -- The domain follows the last @, as a quoted local part can contain one.
+    SELECT string_agg(email, ', ' ORDER BY id) INTO unicode_domains FROM (
+        SELECT id, email, substring(email FROM '@([^@]*)$') AS domain FROM accounts
+    ) AS address
+    WHERE octet_length(domain) <> char_length(domain);
+
+    IF unicode_domains IS NOT NULL THEN
+        RAISE EXCEPTION 'accounts have email addresses with non-ASCII domains: %', unicode_domains;
+    END IF;
+END
+$$;
+UPDATE accounts SET email = lower(email) WHERE email <> lower(email);
+UPDATE email_verifications SET email = lower(email) WHERE email <> lower(email);
+ALTER TABLE accounts DROP CONSTRAINT accounts_email_key;
+CREATE UNIQUE INDEX accounts_email_lower_key ON accounts (lower(email));
+
+-- Create login throttles table, counting failed sign ins per email address and per IP address.
+-- down: DROP TABLE login_throttles;
+CREATE TABLE login_throttles (
+    scope varchar NOT NULL,
+    key varchar NOT NULL,
+    failures integer NOT NULL,
+    last_failure_at timestamp NOT NULL,
+    locked_until timestamp,
+    PRIMARY KEY (scope, key)
+);
+
+-- Create audit events table, recording security relevant events for compliance. Accounts are not
+-- referenced by a foreign key, so their events outlive them.
+-- down: DROP TABLE audit_events;
+CREATE TABLE audit_events (
+    id bigserial PRIMARY KEY,
+    kind varchar NOT NULL,
+    account_id integer,
+    ip_address varchar,
+    user_agent varchar,
+    outcome varchar NOT NULL,
+    reason varchar,
+    created_at timestamp NOT NULL
+);
+CREATE INDEX audit_events_account_id_idx ON audit_events (account_id, id);
+CREATE INDEX audit_events_created_at_idx ON audit_events (created_at);
+
+-- Chain audit events by their hashes, and create the table of signed checkpoints of the chain.
+-- Events recorded before this have no hashes, and the chain starts after them.
+-- down: DROP TABLE audit_checkpoints; ALTER TABLE audit_events DROP COLUMN prev_hash, DROP COLUMN hash;
+ALTER TABLE audit_events ADD COLUMN prev_hash bytea, ADD COLUMN hash bytea;
+CREATE TABLE audit_checkpoints (
+    id serial PRIMARY KEY,
+    event_id bigint NOT NULL,
+    event_hash bytea NOT NULL,
+    signature varchar NOT NULL,
+    created_at timestamp NOT NULL
+);
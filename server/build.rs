@@ -1,4 +1,15 @@
 fn main() {
     tonic_build::compile_protos("../proto/auth.proto")
         .unwrap_or_else(|e| panic!("Failed to compile protos {:?}", e));
+    tonic_build::configure()
+        .build_client(false)
+        .build_server(false)
+        .compile(
+            &[
+                "../proto/google/rpc/status.proto",
+                "../proto/google/rpc/error_details.proto",
+            ],
+            &["../proto"],
+        )
+        .unwrap_or_else(|e| panic!("Failed to compile protos {:?}", e));
 }
@@ -2,13 +2,16 @@
 use std::convert::TryFrom;
 
 use super::model::{
-    Account, AccountAuthenticate, AccountInsert, AccountRegister, AccountRepository,
+    Account, AccountAuthenticate, AccountId, AccountInsert, AccountRegister, AccountRepository,
 };
 
+use crate::database::postgres::QueryResultExt;
+use crate::email;
 use crate::error::AuthError;
 use crate::hashing::Argon2id;
 
 use async_trait::async_trait;
+use chrono::Utc;
 use sqlx::PgConnection;
 
 #[async_trait]
@@ -31,7 +34,8 @@ impl AccountRepository for PgConnection {
             account.hash,
         )
         .fetch_one(self)
-        .await?;
+        .await
+        .on_unique_violation(|| AuthError::UserAlreadyExists(account.email.clone()))?;
 
         Ok(registered_account)
     }
@@ -40,27 +44,359 @@ impl AccountRepository for PgConnection {
         &mut self,
         account_auth: &AccountAuthenticate,
     ) -> Result<Account, AuthError> {
-        // Get the account struct
-        let account = sqlx::query_as!(
+        // Every failure verifies the password, against a dummy hash if there is no account or it
+        // has no password, so the response time does not reveal whether the email is registered.
+        let account = match email::canonicalize("email", &account_auth.email) {
+            Ok(email) => {
+                sqlx::query_as!(
+                    Account,
+                    r#"
+                    SELECT * FROM accounts WHERE lower(email) = $1
+                    "#,
+                    email
+                )
+                .fetch_optional(self)
+                .await?
+            }
+            Err(_) => None,
+        };
+
+        let hash = account.as_ref().and_then(|account| account.hash.as_deref());
+        let verified = Argon2id::verify_password_or_dummy(&account_auth.password, hash)?;
+
+        match account {
+            Some(account) if verified => Ok(account),
+            _ => Err(AuthError::InvalidUsernameOrPassword),
+        }
+    }
+
+    async fn get_account(&mut self, account_id: AccountId) -> Result<Account, AuthError> {
+        sqlx::query_as!(
             Account,
             r#"
-            SELECT * FROM accounts WHERE email = $1
+            SELECT * FROM accounts WHERE id = $1
             "#,
-            account_auth.email
+            account_id
         )
         .fetch_one(self)
+        .await
+        .on_row_not_found(|| AuthError::AccountNotFound(account_id))
+    }
+
+    async fn find_account_by_email(&mut self, email: &str) -> Result<Option<Account>, AuthError> {
+        let email = match email::canonicalize("email", email) {
+            Ok(email) => email,
+            Err(_) => return Ok(None),
+        };
+
+        Ok(sqlx::query_as!(
+            Account,
+            r#"
+            SELECT * FROM accounts WHERE lower(email) = $1
+            "#,
+            email
+        )
+        .fetch_optional(self)
+        .await?)
+    }
+
+    async fn lock_account(&mut self, account_id: AccountId) -> Result<(), AuthError> {
+        sqlx::query!(
+            r#"
+            SELECT id FROM accounts WHERE id = $1 FOR UPDATE
+            "#,
+            account_id
+        )
+        .fetch_one(self)
+        .await
+        .on_row_not_found(|| AuthError::AccountNotFound(account_id))?;
+
+        Ok(())
+    }
+
+    async fn set_password(
+        &mut self,
+        account_id: AccountId,
+        password: Option<&str>,
+    ) -> Result<(), AuthError> {
+        let hash = match password {
+            Some(password) => Some(Argon2id::hash_password(password)?),
+            None => None,
+        };
+
+        let updated = sqlx::query!(
+            r#"
+            UPDATE accounts SET hash = $2, password_breached_at = NULL WHERE id = $1
+            "#,
+            account_id,
+            hash
+        )
+        .execute(self)
         .await?;
 
-        let password_hash = match &account.hash {
-            Some(hash) => Ok(hash),
-            None => Err(AuthError::InvalidUsernameOrPassword),
-        }?;
+        if updated == 0 {
+            return Err(AuthError::AccountNotFound(account_id));
+        }
+
+        Ok(())
+    }
+
+    async fn flag_breached_password(&mut self, account_id: AccountId) -> Result<(), AuthError> {
+        sqlx::query!(
+            r#"
+            UPDATE accounts SET password_breached_at = $2
+            WHERE id = $1 AND password_breached_at IS NULL
+            "#,
+            account_id,
+            Utc::now().naive_utc(),
+        )
+        .execute(self)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn mark_email_verified(
+        &mut self,
+        account_id: AccountId,
+        email: &str,
+    ) -> Result<Option<Account>, AuthError> {
+        Ok(sqlx::query_as!(
+            Account,
+            r#"
+            UPDATE accounts SET email_verified_at = $3
+            WHERE id = $1 AND email = $2
+            RETURNING *
+            "#,
+            account_id,
+            email,
+            Utc::now().naive_utc(),
+        )
+        .fetch_optional(self)
+        .await?)
+    }
+}
 
-        // First check if we need to verify password hashes
-        match Argon2id::verify_password(&account_auth.password, &password_hash) {
-            Ok(true) => Ok(account),
-            Ok(false) => Err(AuthError::InvalidUsernameOrPassword),
-            Err(e) => Err(e),
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::database::postgres::{register_test_account, test_transaction};
+    use crate::hashing::DUMMY_VERIFICATIONS;
+
+    use std::time::{Duration, Instant};
+
+    const PASSWORD: &str = "Correct7Horse";
+
+    fn authentication(email: &str, password: &str) -> AccountAuthenticate {
+        AccountAuthenticate {
+            email: email.to_string(),
+            password: password.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_email_in_any_case_already_exists() {
+        let mut tx = test_transaction().await;
+        let account = register_test_account(&mut tx, Some(PASSWORD)).await;
+        assert!(account.email.ends_with("@example.com"));
+
+        let result = tx
+            .register_new_account(&AccountRegister {
+                given_name: "Other".to_string(),
+                email: account.email.to_uppercase(),
+                password: None,
+            })
+            .await;
+        assert!(
+            matches!(result, Err(AuthError::UserAlreadyExists(email)) if email == account.email)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_authenticate_account() {
+        let mut tx = test_transaction().await;
+        let account = register_test_account(&mut tx, Some(PASSWORD)).await;
+        let passwordless = register_test_account(&mut tx, None).await;
+
+        let authenticated = tx
+            .authenticate_account(&authentication(&account.email.to_uppercase(), PASSWORD))
+            .await
+            .expect("authenticate_account returned an error");
+        assert_eq!(authenticated, account);
+
+        for (email, password) in &[
+            (account.email.as_str(), "Wrong7Horse"),
+            ("unknown@example.com", PASSWORD),
+            ("not an email address", PASSWORD),
+            (passwordless.email.as_str(), ""),
+        ] {
+            let result = tx
+                .authenticate_account(&authentication(email, password))
+                .await;
+            assert!(matches!(result, Err(AuthError::InvalidUsernameOrPassword)));
         }
     }
+
+    #[tokio::test]
+    async fn test_failed_authentications_verify_a_hash() {
+        let mut tx = test_transaction().await;
+        let account = register_test_account(&mut tx, Some(PASSWORD)).await;
+        let passwordless = register_test_account(&mut tx, None).await;
+
+        // Only a wrong password has a real hash to verify, the other failures use the dummy hash.
+        for (attempt, uses_dummy) in &[
+            (authentication(&account.email, "Wrong7Horse"), false),
+            (authentication("unknown@example.com", PASSWORD), true),
+            (authentication(&passwordless.email, PASSWORD), true),
+            (authentication("not an email address", PASSWORD), true),
+        ] {
+            let before = DUMMY_VERIFICATIONS.with(|count| count.get());
+            let result = tx.authenticate_account(attempt).await;
+            let after = DUMMY_VERIFICATIONS.with(|count| count.get());
+
+            assert!(matches!(result, Err(AuthError::InvalidUsernameOrPassword)));
+            assert_eq!(
+                after - before,
+                usize::from(*uses_dummy),
+                "{}",
+                attempt.email
+            );
+        }
+    }
+
+    fn median(mut timings: Vec<Duration>) -> Duration {
+        timings.sort();
+        timings[timings.len() / 2]
+    }
+
+    /// Timings vary with the machine's load, so the bounds are generous: skipping the hash makes
+    /// a failure many times faster, which they still catch.
+    /// `test_failed_authentications_verify_a_hash` checks exactly that the same work is done.
+    #[tokio::test]
+    async fn test_failed_authentications_take_the_same_time() {
+        const ROUNDS: usize = 21;
+
+        let mut tx = test_transaction().await;
+        let account = register_test_account(&mut tx, Some(PASSWORD)).await;
+        let passwordless = register_test_account(&mut tx, None).await;
+        Argon2id::init_dummy_hash();
+
+        // A wrong password is compared with each failure that has no hash to verify.
+        let attempts = [
+            authentication(&account.email, "Wrong7Horse"),
+            authentication("unknown@example.com", PASSWORD),
+            authentication(&passwordless.email, PASSWORD),
+            authentication("not an email address", PASSWORD),
+        ];
+
+        // The attempts are interleaved, so that any slowdown during the test affects them all.
+        let mut timings = vec![Vec::with_capacity(ROUNDS); attempts.len()];
+        for _ in 0..ROUNDS {
+            for (attempt, timings) in attempts.iter().zip(&mut timings) {
+                let start = Instant::now();
+                let result = tx.authenticate_account(attempt).await;
+                timings.push(start.elapsed());
+
+                assert!(matches!(result, Err(AuthError::InvalidUsernameOrPassword)));
+            }
+        }
+
+        // Medians are compared, as unlike means they are not skewed by the odd slow attempt.
+        let medians: Vec<Duration> = timings.into_iter().map(median).collect();
+        for (attempt, median) in attempts.iter().zip(&medians).skip(1) {
+            let ratio = median.as_secs_f64() / medians[0].as_secs_f64();
+            assert!(
+                (0.5..2.0).contains(&ratio),
+                "failing with {} took {:?}, but a wrong password took {:?}",
+                attempt.email,
+                median,
+                medians[0]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_and_lock_account() {
+        let mut tx = test_transaction().await;
+        let account = register_test_account(&mut tx, None).await;
+
+        assert_eq!(tx.get_account(account.id).await.unwrap(), account);
+        assert!(tx.lock_account(account.id).await.is_ok());
+
+        assert!(matches!(
+            tx.get_account(-1).await,
+            Err(AuthError::AccountNotFound(-1))
+        ));
+        assert!(matches!(
+            tx.lock_account(-1).await,
+            Err(AuthError::AccountNotFound(-1))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_find_account_by_email_ignores_case() {
+        let mut tx = test_transaction().await;
+        let account = register_test_account(&mut tx, None).await;
+
+        let found = tx
+            .find_account_by_email(&account.email.to_uppercase())
+            .await
+            .unwrap();
+        assert_eq!(found, Some(account));
+        assert_eq!(
+            tx.find_account_by_email("unknown@example.com")
+                .await
+                .unwrap(),
+            None
+        );
+        assert_eq!(tx.find_account_by_email("unknown").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_set_password_clears_breached_flag() {
+        let mut tx = test_transaction().await;
+        let account = register_test_account(&mut tx, Some(PASSWORD)).await;
+
+        tx.flag_breached_password(account.id).await.unwrap();
+        let flagged = tx.get_account(account.id).await.unwrap();
+        assert!(flagged.password_breached_at.is_some());
+
+        tx.set_password(account.id, Some("Battery7Staple"))
+            .await
+            .unwrap();
+        let updated = tx
+            .authenticate_account(&authentication(&account.email, "Battery7Staple"))
+            .await
+            .unwrap();
+        assert_eq!(updated.password_breached_at, None);
+
+        tx.set_password(account.id, None).await.unwrap();
+        assert_eq!(tx.get_account(account.id).await.unwrap().hash, None);
+
+        assert!(matches!(
+            tx.set_password(-1, None).await,
+            Err(AuthError::AccountNotFound(-1))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_mark_email_verified_only_for_current_email() {
+        let mut tx = test_transaction().await;
+        let account = register_test_account(&mut tx, None).await;
+
+        assert_eq!(
+            tx.mark_email_verified(account.id, "old@example.com")
+                .await
+                .unwrap(),
+            None
+        );
+
+        let verified = tx
+            .mark_email_verified(account.id, &account.email)
+            .await
+            .unwrap()
+            .expect("the account was not updated");
+        assert!(verified.email_verified_at.is_some());
+    }
 }
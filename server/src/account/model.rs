@@ -1,6 +1,7 @@
 /// Defines identity models.
 use std::convert::TryFrom;
 
+use crate::email;
 use crate::error::AuthError;
 use crate::hashing::Argon2id;
 
@@ -23,6 +24,11 @@ pub struct Account {
     pub hash: Option<String>,
     pub avatar_url: Option<String>,
     pub created_at: NaiveDateTime,
+    /// When the user proved they own the email address, unset if they have not.
+    pub email_verified_at: Option<NaiveDateTime>,
+    /// When the password was found in a data breach, unset if it has not been. The password must
+    /// be replaced before it can be used to sign in again.
+    pub password_breached_at: Option<NaiveDateTime>,
 }
 
 /// Defines an account structure that can be inserted into the database.
@@ -45,7 +51,7 @@ pub struct AccountRegister {
 
 /// Provide the conversion of an AccountRegister structure to AccountInsert.
 ///
-/// This will hash the provided password.
+/// This will hash the provided password, and convert the email address to its canonical form.
 impl TryFrom<&AccountRegister> for AccountInsert {
     type Error = AuthError;
 
@@ -57,6 +63,7 @@ impl TryFrom<&AccountRegister> for AccountInsert {
             ..
         } = account_register;
 
+        let email = email::canonicalize("email", email)?;
         let hash = match password {
             Some(password) => Some(Argon2id::hash_password(&password)?),
             None => None,
@@ -65,7 +72,7 @@ impl TryFrom<&AccountRegister> for AccountInsert {
         Ok(Self {
             uuid: Uuid::new_v4(),
             given_name: given_name.to_string(),
-            email: email.to_string(),
+            email,
             hash,
             created_at: chrono::Local::now().naive_utc(),
         })
@@ -92,8 +99,8 @@ pub(crate) trait AccountRepository: Send + Sync + 'static {
     /// A struct containing the authenticated users account details, with a valid JWT.
     ///
     /// ## Errors
-    /// An error could occur if the user has already been registered, or a failure occured with the
-    /// database.
+    /// An error could occur if the email address is invalid, the user has already been registered
+    /// with the same address in any case, or a failure occured with the database.
     async fn register_new_account(
         &mut self,
         account_register: &AccountRegister,
@@ -116,4 +123,100 @@ pub(crate) trait AccountRepository: Send + Sync + 'static {
         &mut self,
         account_auth: &AccountAuthenticate,
     ) -> Result<Account, AuthError>;
+
+    /// Gets an existing account by its ID.
+    ///
+    /// # Parameters
+    /// The ID of the account to get.
+    ///
+    /// # Return Values
+    ///
+    /// ## Success
+    /// A struct containing the account details.
+    ///
+    /// ## Errors
+    /// If the account was not found, or a failure occured with the database.
+    async fn get_account(&mut self, account_id: AccountId) -> Result<Account, AuthError>;
+
+    /// Finds an existing account by its email address, ignoring case.
+    ///
+    /// # Parameters
+    /// The email address of the account.
+    ///
+    /// # Return Values
+    ///
+    /// ## Success
+    /// The account details, if an account with the email address exists. `None` if the address
+    /// is invalid.
+    ///
+    /// ## Errors
+    /// If a failure occured with the database.
+    async fn find_account_by_email(&mut self, email: &str) -> Result<Option<Account>, AuthError>;
+
+    /// Locks an account until the end of the current transaction, serializing changes to it.
+    ///
+    /// # Parameters
+    /// The ID of the account to lock.
+    ///
+    /// # Return Values
+    ///
+    /// ## Success
+    /// Nothing, once the lock has been acquired.
+    ///
+    /// ## Errors
+    /// If the account was not found, or a failure occured with the database.
+    async fn lock_account(&mut self, account_id: AccountId) -> Result<(), AuthError>;
+
+    /// Sets or removes an account's password, clearing any breached password flag.
+    ///
+    /// # Parameters
+    /// The ID of the account, and the new password, which will be hashed. `None` removes the
+    /// password.
+    ///
+    /// # Return Values
+    ///
+    /// ## Success
+    /// Nothing, once the password has been stored.
+    ///
+    /// ## Errors
+    /// If the account was not found, hashing the password failed, or a failure occured with the
+    /// database.
+    async fn set_password(
+        &mut self,
+        account_id: AccountId,
+        password: Option<&str>,
+    ) -> Result<(), AuthError>;
+
+    /// Flags an account's password as breached, so it must be replaced before the next sign in.
+    ///
+    /// # Parameters
+    /// The ID of the account.
+    ///
+    /// # Return Values
+    ///
+    /// ## Success
+    /// Nothing, once the account has been flagged.
+    ///
+    /// ## Errors
+    /// If a failure occured with the database.
+    async fn flag_breached_password(&mut self, account_id: AccountId) -> Result<(), AuthError>;
+
+    /// Marks an account's email address as verified.
+    ///
+    /// # Parameters
+    /// The ID of the account, and the email address that was verified.
+    ///
+    /// # Return Values
+    ///
+    /// ## Success
+    /// The updated account details, or `None` if the account's email address is no longer the one
+    /// that was verified.
+    ///
+    /// ## Errors
+    /// If a failure occured with the database.
+    async fn mark_email_verified(
+        &mut self,
+        account_id: AccountId,
+        email: &str,
+    ) -> Result<Option<Account>, AuthError>;
 }
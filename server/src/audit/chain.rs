@@ -0,0 +1,541 @@
+/// Chains audit events together by their hashes, signs checkpoints of the chain, and verifies it.
+///
+/// Each event records the hash of the event before it, and its own hash covers that, so altering,
+/// deleting or reordering an event changes every hash after it. Deleting the newest events leaves
+/// a valid, shorter chain, so the newest event's hash is periodically signed with the service's
+/// signing key as a checkpoint, which vouches for every event up to it.
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use super::model::{AuditCheckpoint, AuditCheckpointCreate, AuditEvent, AuditRepository};
+
+use crate::database::Db;
+use crate::error::AuthError;
+use crate::jwt::model::JWT_ISSUER;
+use crate::keys::manager::KeyManager;
+use crate::keys::model::SigningKey;
+
+use chrono::Utc;
+use jsonwebtoken::{decode, decode_header, encode, Header, Validation};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{PgConnection, PgPool};
+
+/// Define the hash the first chained event follows.
+pub const GENESIS_HASH: [u8; 32] = [0; 32];
+
+/// Define the number of events verified at a time.
+const VERIFY_BATCH_SIZE: i64 = 1000;
+
+/// The claims of a signed checkpoint.
+#[derive(Debug, Serialize, Deserialize)]
+struct CheckpointClaims {
+    iss: String,
+    iat: i64,
+    /// The ID of the newest event when the checkpoint was made.
+    event_id: i64,
+    /// The hash of that event, as URL safe base64.
+    event_hash: String,
+}
+
+/// Describes the first point at which the audit log was found to have been tampered with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChainBreak {
+    /// The ID of the first event that could not be verified.
+    pub event_id: i64,
+    pub reason: String,
+}
+
+/// The result of verifying the audit log.
+#[derive(Debug, Default)]
+pub struct ChainVerification {
+    /// The number of chained events verified before the first break, if any.
+    pub events_verified: u64,
+    /// The number of events recorded before events were chained, which cannot be verified.
+    pub unchained_events: u64,
+    /// The number of checkpoints verified before the first break, if any.
+    pub checkpoints_verified: u64,
+    /// The first break, unset if the audit log is intact.
+    pub first_break: Option<ChainBreak>,
+}
+
+/// Computes the hash of an audit event, from its canonical serialization.
+///
+/// Every field but the ID and the hash itself is serialized, in order: the kind, account ID, IP
+/// address, user agent, outcome, reason, creation time in microseconds since the Unix epoch, and
+/// previous hash. Unset fields are a 0 byte, and set fields a 1 byte followed by the value:
+/// integers as big-endian, and strings and hashes as their big-endian 32-bit length followed by
+/// their bytes. This is hashed with SHA-256.
+pub fn event_hash(event: &AuditEvent) -> Vec<u8> {
+    let mut serialized = Vec::new();
+
+    put_bytes(&mut serialized, Some(event.kind.as_bytes()));
+    match event.account_id {
+        Some(account_id) => {
+            serialized.push(1);
+            serialized.extend_from_slice(&account_id.to_be_bytes());
+        }
+        None => serialized.push(0),
+    }
+    put_bytes(
+        &mut serialized,
+        event.ip_address.as_ref().map(String::as_bytes),
+    );
+    put_bytes(
+        &mut serialized,
+        event.user_agent.as_ref().map(String::as_bytes),
+    );
+    put_bytes(&mut serialized, Some(event.outcome.as_bytes()));
+    put_bytes(&mut serialized, event.reason.as_ref().map(String::as_bytes));
+    let created_at = event.created_at.timestamp() * 1_000_000
+        + i64::from(event.created_at.timestamp_subsec_micros());
+    serialized.push(1);
+    serialized.extend_from_slice(&created_at.to_be_bytes());
+    put_bytes(&mut serialized, event.prev_hash.as_deref());
+
+    Sha256::digest(&serialized).to_vec()
+}
+
+fn put_bytes(serialized: &mut Vec<u8>, bytes: Option<&[u8]>) {
+    match bytes {
+        Some(bytes) => {
+            serialized.push(1);
+            serialized.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+            serialized.extend_from_slice(bytes);
+        }
+        None => serialized.push(0),
+    }
+}
+
+/// Signs a checkpoint of the audit log, as a JWS that anyone with the JWK Set can verify.
+///
+/// # Parameters
+/// The key to sign with, and the ID and hash of the newest event.
+pub(crate) fn sign_checkpoint(
+    key: &SigningKey,
+    event_id: i64,
+    event_hash: &[u8],
+) -> Result<String, AuthError> {
+    let claims = CheckpointClaims {
+        iss: JWT_ISSUER.to_string(),
+        iat: Utc::now().timestamp(),
+        event_id,
+        event_hash: base64::encode_config(event_hash, base64::URL_SAFE_NO_PAD),
+    };
+
+    let mut header = Header::new(key.algorithm.jwt_algorithm());
+    header.kid = Some(key.kid.clone());
+
+    encode(&header, &claims, key.encoding_key()).map_err(AuthError::InvalidToken)
+}
+
+/// Checks that a checkpoint was signed by one of the service's keys, for an event with a hash.
+///
+/// # Return Values
+/// Why the checkpoint is not valid, or `None` if it is.
+fn check_checkpoint<F>(
+    checkpoint: &AuditCheckpoint,
+    event_hash: &[u8],
+    load_key: &F,
+) -> Option<String>
+where
+    F: Fn(&str) -> Result<SigningKey, AuthError>,
+{
+    let kid = match decode_header(&checkpoint.signature).map(|header| header.kid) {
+        Ok(Some(kid)) => kid,
+        _ => return Some(format!("checkpoint {} is not signed", checkpoint.id)),
+    };
+    let key = match load_key(&kid) {
+        Ok(key) => key,
+        Err(_) => {
+            return Some(format!(
+                "checkpoint {} is signed by unknown key {}",
+                checkpoint.id, kid
+            ))
+        }
+    };
+
+    let mut validation = Validation::new(key.algorithm.jwt_algorithm());
+    validation.iss = Some(JWT_ISSUER.to_string());
+    // Checkpoints vouch for the events forever.
+    validation.validate_exp = false;
+    let claims =
+        match decode::<CheckpointClaims>(&checkpoint.signature, key.decoding_key(), &validation) {
+            Ok(data) => data.claims,
+            Err(_) => {
+                return Some(format!(
+                    "checkpoint {} has an invalid signature",
+                    checkpoint.id
+                ))
+            }
+        };
+
+    let signed_hash = base64::decode_config(&claims.event_hash, base64::URL_SAFE_NO_PAD);
+    if claims.event_id != checkpoint.event_id
+        || checkpoint.event_hash != event_hash
+        || signed_hash.ok().as_deref() != Some(event_hash)
+    {
+        return Some(format!(
+            "event {} does not match the hash signed by checkpoint {}",
+            checkpoint.event_id, checkpoint.id
+        ));
+    }
+
+    None
+}
+
+/// Walks the audit log from its oldest event, checking every event's hash and every checkpoint's
+/// signature, until the first break.
+///
+/// # Parameters
+/// A connection to the database, and a function that loads a signing key by its key ID, including
+/// keys that are no longer published.
+///
+/// # Return Values
+/// ## Success
+/// A ChainVerification structure, with the first break if there is one.
+///
+/// ## Errors
+/// If a failure occured with the database.
+pub async fn verify_chain<F>(
+    conn: &mut PgConnection,
+    load_key: F,
+) -> Result<ChainVerification, AuthError>
+where
+    F: Fn(&str) -> Result<SigningKey, AuthError>,
+{
+    let mut checkpoints: VecDeque<_> = conn.list_audit_checkpoints().await?.into();
+    let mut verification = ChainVerification::default();
+    // The hash of the previous chained event, unset until the first one.
+    let mut prev_hash: Option<Vec<u8>> = None;
+    let mut last_id = 0;
+
+    loop {
+        let events = conn
+            .list_chained_audit_events(last_id, VERIFY_BATCH_SIZE)
+            .await?;
+        if events.is_empty() {
+            break;
+        }
+
+        for event in events {
+            last_id = event.id;
+            let broken = |reason: String| ChainBreak {
+                event_id: event.id,
+                reason,
+            };
+
+            let hash = match (&event.hash, &prev_hash) {
+                (None, None) => {
+                    verification.unchained_events += 1;
+                    continue;
+                }
+                (None, Some(_)) => {
+                    verification.first_break =
+                        Some(broken(format!("event {} is not chained", event.id)));
+                    return Ok(verification);
+                }
+                (Some(hash), _) => hash,
+            };
+
+            let expected_prev_hash = prev_hash.as_deref().unwrap_or(&GENESIS_HASH);
+            if event.prev_hash.as_deref() != Some(expected_prev_hash) {
+                verification.first_break = Some(broken(format!(
+                    "event {} does not follow the event before it, which has been altered, \
+                     deleted or reordered",
+                    event.id
+                )));
+                return Ok(verification);
+            }
+            if &event_hash(&event) != hash {
+                verification.first_break =
+                    Some(broken(format!("event {} has been altered", event.id)));
+                return Ok(verification);
+            }
+            verification.events_verified += 1;
+
+            // Checkpoints of events that are no longer in the log are reported where they were.
+            while let Some(checkpoint) = checkpoints.front() {
+                if checkpoint.event_id > event.id {
+                    break;
+                }
+
+                let reason = if checkpoint.event_id < event.id {
+                    Some(format!(
+                        "event {} signed by checkpoint {} has been deleted",
+                        checkpoint.event_id, checkpoint.id
+                    ))
+                } else {
+                    check_checkpoint(checkpoint, hash, &load_key)
+                };
+                if let Some(reason) = reason {
+                    verification.first_break = Some(ChainBreak {
+                        event_id: checkpoint.event_id,
+                        reason,
+                    });
+                    return Ok(verification);
+                }
+
+                verification.checkpoints_verified += 1;
+                checkpoints.pop_front();
+            }
+
+            prev_hash = Some(hash.clone());
+        }
+    }
+
+    // Checkpoints after the newest event mean the newest events were deleted.
+    if let Some(checkpoint) = checkpoints.front() {
+        verification.first_break = Some(ChainBreak {
+            event_id: checkpoint.event_id,
+            reason: format!(
+                "event {} signed by checkpoint {} has been deleted",
+                checkpoint.event_id, checkpoint.id
+            ),
+        });
+    }
+
+    Ok(verification)
+}
+
+/// Signs a checkpoint of the newest event, unless it has no hash or already has a checkpoint.
+async fn checkpoint(pool: &PgPool, keys: &KeyManager) -> Result<(), AuthError> {
+    let mut tx = pool.transaction().await?;
+    // Other replicas checkpoint too, so this waits for any checkpoint they are making.
+    tx.lock_audit_events().await?;
+
+    let newest = match tx.get_newest_audit_event().await? {
+        Some(newest) => newest,
+        None => return Ok(()),
+    };
+    let hash = match newest.hash {
+        Some(hash) => hash,
+        None => return Ok(()),
+    };
+    let checkpointed = tx
+        .list_audit_checkpoints()
+        .await?
+        .last()
+        .map_or(false, |checkpoint| checkpoint.event_id == newest.id);
+    if checkpointed {
+        return Ok(());
+    }
+
+    let signature = sign_checkpoint(keys.store().await.active_key()?, newest.id, &hash)?;
+    tx.add_audit_checkpoint(&AuditCheckpointCreate {
+        event_id: newest.id,
+        event_hash: hash,
+        signature,
+    })
+    .await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Periodically signs a checkpoint of the newest audit event.
+///
+/// This never returns, so it should be spawned as a separate task.
+pub async fn run_checkpoints(pool: PgPool, keys: Arc<KeyManager>, interval: chrono::Duration) {
+    let interval = interval
+        .to_std()
+        .expect("the checkpoint interval must not be negative");
+    let mut interval = tokio::time::interval(interval);
+
+    loop {
+        interval.tick().await;
+
+        if let Err(e) = checkpoint(&pool, &keys).await {
+            println!("Failed to checkpoint the audit log: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::audit::model::{AuditEventCreate, AuditEventKind};
+    use crate::database::postgres::test_transaction;
+    use crate::keys::model::KeyAlgorithm;
+
+    use chrono::naive::NaiveDateTime;
+
+    fn event() -> AuditEvent {
+        AuditEvent {
+            id: 1,
+            kind: "login".to_string(),
+            account_id: Some(7),
+            ip_address: Some("192.0.2.1".to_string()),
+            user_agent: None,
+            outcome: "success".to_string(),
+            reason: None,
+            created_at: NaiveDateTime::from_timestamp(1_600_000_000, 123_000),
+            prev_hash: Some(GENESIS_HASH.to_vec()),
+            hash: None,
+        }
+    }
+
+    #[test]
+    fn test_hash_covers_every_field_but_the_id() {
+        let hash = event_hash(&event());
+        assert_eq!(hash.len(), 32);
+        assert_eq!(event_hash(&AuditEvent { id: 2, ..event() }), hash);
+
+        let changed = vec![
+            AuditEvent {
+                kind: "logout".to_string(),
+                ..event()
+            },
+            AuditEvent {
+                account_id: None,
+                ..event()
+            },
+            AuditEvent {
+                ip_address: Some("192.0.2.2".to_string()),
+                ..event()
+            },
+            AuditEvent {
+                user_agent: Some(String::new()),
+                ..event()
+            },
+            AuditEvent {
+                outcome: "failure".to_string(),
+                ..event()
+            },
+            // A field's value cannot be moved into the next field.
+            AuditEvent {
+                ip_address: None,
+                user_agent: Some("192.0.2.1".to_string()),
+                ..event()
+            },
+            AuditEvent {
+                created_at: NaiveDateTime::from_timestamp(1_600_000_000, 124_000),
+                ..event()
+            },
+            AuditEvent {
+                prev_hash: Some(vec![1; 32]),
+                ..event()
+            },
+        ];
+        for event in &changed {
+            assert_ne!(event_hash(event), hash, "{:?} has the same hash", event);
+        }
+    }
+
+    /// Records events, and signs a checkpoint of the newest with a new key.
+    ///
+    /// Returns the IDs of the events, and the key's PEM.
+    async fn record_checkpointed_events(
+        conn: &mut PgConnection,
+        count: usize,
+    ) -> (Vec<i64>, Vec<u8>) {
+        let event = AuditEventCreate::new(AuditEventKind::Login, None, None);
+        let mut ids = Vec::new();
+        for _ in 0..count {
+            ids.push(conn.record_audit_event(&event).await.unwrap().id);
+        }
+
+        let (key, pem) = SigningKey::generate("audittest", KeyAlgorithm::ES256).unwrap();
+        let newest = conn.get_newest_audit_event().await.unwrap().unwrap();
+        let hash = newest.hash.unwrap();
+        conn.add_audit_checkpoint(&AuditCheckpointCreate {
+            event_id: newest.id,
+            signature: sign_checkpoint(&key, newest.id, &hash).unwrap(),
+            event_hash: hash,
+        })
+        .await
+        .unwrap();
+
+        (ids, pem)
+    }
+
+    #[tokio::test]
+    async fn test_altered_events_are_reported() {
+        let mut tx = test_transaction().await;
+        let conn: &mut PgConnection = &mut tx;
+        let (ids, pem) = record_checkpointed_events(conn, 3).await;
+        let load_key = |kid: &str| SigningKey::from_pem(kid, &pem);
+
+        let verification = verify_chain(conn, &load_key).await.unwrap();
+        assert_eq!(verification.first_break, None);
+        assert!(verification.events_verified >= 3);
+        assert!(verification.checkpoints_verified >= 1);
+
+        sqlx::query!(
+            r#"
+            UPDATE audit_events SET reason = 'forged' WHERE id = $1
+            "#,
+            ids[1],
+        )
+        .execute(&mut *conn)
+        .await
+        .unwrap();
+        let first_break = verify_chain(conn, &load_key)
+            .await
+            .unwrap()
+            .first_break
+            .expect("the altered event was not reported");
+        assert_eq!(first_break.event_id, ids[1]);
+        assert!(first_break.reason.contains("altered"));
+    }
+
+    #[tokio::test]
+    async fn test_deleted_events_are_reported() {
+        let mut tx = test_transaction().await;
+        let conn: &mut PgConnection = &mut tx;
+        let (ids, pem) = record_checkpointed_events(conn, 3).await;
+        let load_key = |kid: &str| SigningKey::from_pem(kid, &pem);
+
+        sqlx::query!(
+            r#"
+            DELETE FROM audit_events WHERE id = $1
+            "#,
+            ids[1],
+        )
+        .execute(&mut *conn)
+        .await
+        .unwrap();
+        let first_break = verify_chain(conn, &load_key)
+            .await
+            .unwrap()
+            .first_break
+            .expect("the deleted event was not reported");
+        assert_eq!(first_break.event_id, ids[2]);
+
+        // Deleting the newest events leaves a valid chain, but not the checkpoint.
+        sqlx::query!(
+            r#"
+            DELETE FROM audit_events WHERE id >= $1
+            "#,
+            ids[1],
+        )
+        .execute(&mut *conn)
+        .await
+        .unwrap();
+        let first_break = verify_chain(conn, &load_key)
+            .await
+            .unwrap()
+            .first_break
+            .expect("the deleted events were not reported");
+        assert_eq!(first_break.event_id, ids[2]);
+        assert!(first_break.reason.contains("deleted"));
+    }
+
+    #[tokio::test]
+    async fn test_checkpoints_signed_by_other_keys_are_reported() {
+        let mut tx = test_transaction().await;
+        let conn: &mut PgConnection = &mut tx;
+        let (ids, _) = record_checkpointed_events(conn, 1).await;
+        let (_, other_pem) = SigningKey::generate("audittest", KeyAlgorithm::ES256).unwrap();
+        let load_key = |kid: &str| SigningKey::from_pem(kid, &other_pem);
+
+        let first_break = verify_chain(conn, &load_key)
+            .await
+            .unwrap()
+            .first_break
+            .expect("the forged checkpoint was not reported");
+        assert_eq!(first_break.event_id, ids[0]);
+        assert!(first_break.reason.contains("invalid signature"));
+    }
+}
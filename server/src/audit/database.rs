@@ -0,0 +1,287 @@
+use super::chain::{self, GENESIS_HASH};
+use super::model::{
+    AuditCheckpoint, AuditCheckpointCreate, AuditEvent, AuditEventCreate, AuditEventFilter,
+    AuditRepository,
+};
+
+use crate::error::AuthError;
+
+use async_trait::async_trait;
+use chrono::{Duration, Utc};
+use sqlx::PgConnection;
+
+/// The key of the transaction-level advisory lock held while the newest audit event is read and
+/// the next one is chained to it. Any constant works, as long as nothing else locks it.
+const AUDIT_CHAIN_LOCK_KEY: i64 = 0x6175_6469_745f_6c6f;
+
+#[async_trait]
+impl AuditRepository for PgConnection {
+    async fn lock_audit_events(&mut self) -> Result<(), AuthError> {
+        // Only writers of the chain take this lock, so reads and every other table are unaffected.
+        // The function returns void, which the query macro cannot describe, so a constant is
+        // selected instead.
+        sqlx::query!(
+            r#"
+            SELECT 1 AS locked FROM (SELECT pg_advisory_xact_lock($1)) AS chain_head
+            "#,
+            AUDIT_CHAIN_LOCK_KEY
+        )
+        .fetch_one(self)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn record_audit_event(
+        &mut self,
+        event: &AuditEventCreate,
+    ) -> Result<AuditEvent, AuthError> {
+        self.lock_audit_events().await?;
+
+        // Events recorded before events were chained have no hash, so the chain starts again.
+        let prev_hash = self
+            .get_newest_audit_event()
+            .await?
+            .and_then(|newest| newest.hash)
+            .unwrap_or_else(|| GENESIS_HASH.to_vec());
+
+        // The database stores microseconds, so anything finer would change the hash.
+        let now = Utc::now().naive_utc();
+        let created_at =
+            now - Duration::nanoseconds(i64::from(now.timestamp_subsec_nanos() % 1000));
+
+        let mut chained = AuditEvent {
+            id: 0,
+            kind: event.kind.as_str().to_string(),
+            account_id: event.account_id,
+            ip_address: event.ip_address.clone(),
+            user_agent: event.user_agent.clone(),
+            outcome: event.outcome.as_str().to_string(),
+            reason: event.reason.clone(),
+            created_at,
+            prev_hash: Some(prev_hash),
+            hash: None,
+        };
+        chained.hash = Some(chain::event_hash(&chained));
+
+        Ok(sqlx::query_as!(
+            AuditEvent,
+            r#"
+            INSERT INTO audit_events
+                (kind, account_id, ip_address, user_agent, outcome, reason, created_at, prev_hash,
+                 hash)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            RETURNING *
+            "#,
+            chained.kind,
+            chained.account_id,
+            chained.ip_address,
+            chained.user_agent,
+            chained.outcome,
+            chained.reason,
+            chained.created_at,
+            chained.prev_hash,
+            chained.hash,
+        )
+        .fetch_one(self)
+        .await?)
+    }
+
+    async fn list_audit_events(
+        &mut self,
+        filter: &AuditEventFilter,
+    ) -> Result<Vec<AuditEvent>, AuthError> {
+        Ok(sqlx::query_as!(
+            AuditEvent,
+            r#"
+            SELECT * FROM audit_events
+            WHERE ($1::integer IS NULL OR account_id = $1)
+                AND ($2::varchar IS NULL OR kind = $2)
+                AND ($3::varchar IS NULL OR outcome = $3)
+                AND ($4::varchar IS NULL OR ip_address = $4)
+                AND ($5::timestamp IS NULL OR created_at >= $5)
+                AND ($6::timestamp IS NULL OR created_at < $6)
+                AND ($7::bigint IS NULL OR id < $7)
+            ORDER BY id DESC
+            LIMIT $8
+            "#,
+            filter.account_id,
+            filter.kind.map(|kind| kind.as_str()),
+            filter.outcome.map(|outcome| outcome.as_str()),
+            filter.ip_address,
+            filter.since,
+            filter.until,
+            filter.before_id,
+            filter.limit,
+        )
+        .fetch_all(self)
+        .await?)
+    }
+
+    async fn list_chained_audit_events(
+        &mut self,
+        after_id: i64,
+        limit: i64,
+    ) -> Result<Vec<AuditEvent>, AuthError> {
+        Ok(sqlx::query_as!(
+            AuditEvent,
+            r#"
+            SELECT * FROM audit_events WHERE id > $1 ORDER BY id LIMIT $2
+            "#,
+            after_id,
+            limit,
+        )
+        .fetch_all(self)
+        .await?)
+    }
+
+    async fn get_newest_audit_event(&mut self) -> Result<Option<AuditEvent>, AuthError> {
+        Ok(sqlx::query_as!(
+            AuditEvent,
+            r#"
+            SELECT * FROM audit_events ORDER BY id DESC LIMIT 1
+            "#
+        )
+        .fetch_optional(self)
+        .await?)
+    }
+
+    async fn add_audit_checkpoint(
+        &mut self,
+        checkpoint: &AuditCheckpointCreate,
+    ) -> Result<AuditCheckpoint, AuthError> {
+        Ok(sqlx::query_as!(
+            AuditCheckpoint,
+            r#"
+            INSERT INTO audit_checkpoints (event_id, event_hash, signature, created_at)
+            VALUES ($1, $2, $3, $4)
+            RETURNING *
+            "#,
+            checkpoint.event_id,
+            checkpoint.event_hash,
+            checkpoint.signature,
+            Utc::now().naive_utc(),
+        )
+        .fetch_one(self)
+        .await?)
+    }
+
+    async fn list_audit_checkpoints(&mut self) -> Result<Vec<AuditCheckpoint>, AuthError> {
+        Ok(sqlx::query_as!(
+            AuditCheckpoint,
+            r#"
+            SELECT * FROM audit_checkpoints ORDER BY event_id, id
+            "#
+        )
+        .fetch_all(self)
+        .await?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::audit::model::{AuditEventKind, AuditOutcome};
+    use crate::database::postgres::{register_test_account, test_transaction};
+
+    #[tokio::test]
+    async fn test_events_are_listed_newest_first_by_page() {
+        let mut tx = test_transaction().await;
+        let account = register_test_account(&mut tx, None).await;
+
+        let mut recorded = Vec::new();
+        for kind in &[
+            AuditEventKind::Registration,
+            AuditEventKind::TokenIssue,
+            AuditEventKind::Login,
+        ] {
+            let mut event = AuditEventCreate::new(
+                *kind,
+                Some("192.0.2.1".to_string()),
+                Some("grpc-test".to_string()),
+            );
+            event.account_id = Some(account.id);
+            recorded.push(tx.record_audit_event(&event).await.unwrap().id);
+        }
+
+        let first_page = tx
+            .list_audit_events(&AuditEventFilter {
+                account_id: Some(account.id),
+                limit: 2,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let ids: Vec<_> = first_page.iter().map(|event| event.id).collect();
+        assert_eq!(ids, vec![recorded[2], recorded[1]]);
+        assert_eq!(first_page[0].kind, "login");
+        assert_eq!(first_page[0].user_agent.as_deref(), Some("grpc-test"));
+
+        let second_page = tx
+            .list_audit_events(&AuditEventFilter {
+                account_id: Some(account.id),
+                before_id: Some(recorded[1]),
+                limit: 2,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let ids: Vec<_> = second_page.iter().map(|event| event.id).collect();
+        assert_eq!(ids, vec![recorded[0]]);
+    }
+
+    #[tokio::test]
+    async fn test_events_are_chained_to_the_newest_event() {
+        let mut tx = test_transaction().await;
+
+        let event = AuditEventCreate::new(AuditEventKind::Login, None, None);
+        let first = tx.record_audit_event(&event).await.unwrap();
+        let second = tx.record_audit_event(&event).await.unwrap();
+
+        assert_eq!(second.prev_hash, first.hash);
+        assert_eq!(second.hash, Some(chain::event_hash(&second)));
+        assert_ne!(second.hash, first.hash);
+    }
+
+    #[tokio::test]
+    async fn test_events_are_filtered_by_every_field_set() {
+        let mut tx = test_transaction().await;
+        let account = register_test_account(&mut tx, None).await;
+
+        let mut event = AuditEventCreate::new(AuditEventKind::Login, None, None);
+        event.account_id = Some(account.id);
+        let succeeded = tx.record_audit_event(&event).await.unwrap();
+        let failed = tx
+            .record_audit_event(&event.clone().failed("invalid username or password"))
+            .await
+            .unwrap();
+        assert_eq!(failed.outcome, "failure");
+        assert_eq!(failed.ip_address, None);
+
+        let listed = tx
+            .list_audit_events(&AuditEventFilter {
+                account_id: Some(account.id),
+                kind: Some(AuditEventKind::Login),
+                outcome: Some(AuditOutcome::Success),
+                since: Some(succeeded.created_at),
+                limit: 10,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].id, succeeded.id);
+
+        let listed = tx
+            .list_audit_events(&AuditEventFilter {
+                account_id: Some(account.id),
+                kind: Some(AuditEventKind::PasswordChange),
+                limit: 10,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert!(listed.is_empty());
+    }
+}
@@ -0,0 +1,10 @@
+/// Audit events record who did what to their account, from where, and when.
+///
+/// Registrations, sign ins, the tokens they issue, token refreshes and revocations, and password
+/// changes are each recorded with their outcome, so administrators can answer compliance queries
+/// through the `ListAuditEvents` RPC. Events are only ever inserted, never updated or deleted, and
+/// are chained by their hashes so that the `VerifyAuditLog` RPC can prove they have not been.
+///
+pub mod chain;
+pub mod database;
+pub mod model;
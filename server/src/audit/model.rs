@@ -0,0 +1,352 @@
+/// Data models for Audit Events.
+use crate::account::model::AccountId;
+use crate::error::AuthError;
+
+use async_trait::async_trait;
+use chrono::naive::NaiveDateTime;
+
+/// Define the number of events listed per page if the request does not say.
+pub const DEFAULT_PAGE_SIZE: i64 = 100;
+
+/// Define the most events that can be listed per page.
+pub const MAX_PAGE_SIZE: i64 = 1000;
+
+/// Defines what happened in an audit event.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuditEventKind {
+    /// An account was registered, with a password or an identity provider.
+    Registration,
+    /// A user signed in, with a password or an identity provider.
+    Login,
+    /// A new session was started, i.e. a refresh token family was issued.
+    TokenIssue,
+    /// A refresh token was rotated for a new one.
+    TokenRefresh,
+    /// One or more refresh tokens were revoked.
+    TokenRevocation,
+    /// An account's password was changed or reset.
+    PasswordChange,
+}
+
+impl AuditEventKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AuditEventKind::Registration => "registration",
+            AuditEventKind::Login => "login",
+            AuditEventKind::TokenIssue => "token_issue",
+            AuditEventKind::TokenRefresh => "token_refresh",
+            AuditEventKind::TokenRevocation => "token_revocation",
+            AuditEventKind::PasswordChange => "password_change",
+        }
+    }
+
+    /// Parses the name of a kind, as returned by `as_str`.
+    pub fn parse(kind: &str) -> Option<Self> {
+        [
+            AuditEventKind::Registration,
+            AuditEventKind::Login,
+            AuditEventKind::TokenIssue,
+            AuditEventKind::TokenRefresh,
+            AuditEventKind::TokenRevocation,
+            AuditEventKind::PasswordChange,
+        ]
+        .iter()
+        .copied()
+        .find(|candidate| candidate.as_str() == kind)
+    }
+}
+
+/// Defines whether what was attempted in an audit event happened.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuditOutcome {
+    Success,
+    Failure,
+}
+
+impl AuditOutcome {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AuditOutcome::Success => "success",
+            AuditOutcome::Failure => "failure",
+        }
+    }
+
+    /// Parses the name of an outcome, as returned by `as_str`.
+    pub fn parse(outcome: &str) -> Option<Self> {
+        [AuditOutcome::Success, AuditOutcome::Failure]
+            .iter()
+            .copied()
+            .find(|candidate| candidate.as_str() == outcome)
+    }
+}
+
+/// Defines a recorded audit event.
+#[derive(Debug)]
+pub struct AuditEvent {
+    /// The event's ID, which increases with each event, so orders events.
+    pub id: i64,
+    pub kind: String,
+    /// The account the event was for, unset if it is not known, e.g. a sign in with an
+    /// unregistered email address.
+    pub account_id: Option<AccountId>,
+    /// The IP address of the client, unset if it is not known.
+    pub ip_address: Option<String>,
+    /// The `user-agent` metadata sent by the client, unset if none was sent.
+    pub user_agent: Option<String>,
+    pub outcome: String,
+    /// Why the event failed, or for some that succeeded why they happened, e.g. why tokens were
+    /// revoked.
+    pub reason: Option<String>,
+    pub created_at: NaiveDateTime,
+    /// The hash of the previous event, or of nothing if this is the first. Unset, like `hash`,
+    /// for events recorded before events were chained.
+    pub prev_hash: Option<Vec<u8>>,
+    /// The hash of this event, see `chain::event_hash`.
+    pub hash: Option<Vec<u8>>,
+}
+
+/// Defines the data required to record an audit event.
+#[derive(Debug, Clone)]
+pub struct AuditEventCreate {
+    pub kind: AuditEventKind,
+    pub account_id: Option<AccountId>,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub outcome: AuditOutcome,
+    pub reason: Option<String>,
+}
+
+impl AuditEventCreate {
+    /// Creates a new AuditEventCreate instance, for a successful event of an unknown account.
+    ///
+    /// # Parameters
+    /// What happened, and the IP address and user agent of the client it happened for.
+    pub fn new(
+        kind: AuditEventKind,
+        ip_address: Option<String>,
+        user_agent: Option<String>,
+    ) -> Self {
+        Self {
+            kind,
+            account_id: None,
+            ip_address,
+            user_agent,
+            outcome: AuditOutcome::Success,
+            reason: None,
+        }
+    }
+
+    /// Creates a copy of the event, with another kind.
+    pub fn with_kind(&self, kind: AuditEventKind) -> Self {
+        Self {
+            kind,
+            ..self.clone()
+        }
+    }
+
+    /// Sets the outcome of the event from the result of what was attempted.
+    ///
+    /// A failed result is recorded with the error as its reason.
+    pub fn with_result<T>(self, result: &Result<T, AuthError>) -> Self {
+        match result {
+            Ok(_) => self,
+            Err(e) => self.failed(&e.to_string()),
+        }
+    }
+
+    /// Sets why the event happened.
+    pub fn because(self, reason: &str) -> Self {
+        Self {
+            reason: Some(reason.to_string()),
+            ..self
+        }
+    }
+
+    /// Sets the outcome of the event to a failure, for a reason.
+    pub fn failed(self, reason: &str) -> Self {
+        Self {
+            outcome: AuditOutcome::Failure,
+            reason: Some(reason.to_string()),
+            ..self
+        }
+    }
+}
+
+/// Defines which audit events are listed, and where the page of them starts.
+#[derive(Debug, Default)]
+pub struct AuditEventFilter {
+    pub account_id: Option<AccountId>,
+    pub kind: Option<AuditEventKind>,
+    pub outcome: Option<AuditOutcome>,
+    pub ip_address: Option<String>,
+    /// Only events created at or after this time are listed.
+    pub since: Option<NaiveDateTime>,
+    /// Only events created before this time are listed.
+    pub until: Option<NaiveDateTime>,
+    /// Only events older than the event with this ID are listed, i.e. the ID of the last event of
+    /// the previous page.
+    pub before_id: Option<i64>,
+    /// The most events to list.
+    pub limit: i64,
+}
+
+/// Defines a signed checkpoint of the audit log, which vouches for every event up to its event.
+#[derive(Debug)]
+pub struct AuditCheckpoint {
+    pub id: i32,
+    /// The ID of the newest event when the checkpoint was made.
+    pub event_id: i64,
+    /// The hash of that event.
+    pub event_hash: Vec<u8>,
+    /// A JWS of the event's ID and hash, signed with the service's signing key.
+    pub signature: String,
+    pub created_at: NaiveDateTime,
+}
+
+/// Defines the data required to record a checkpoint.
+#[derive(Debug)]
+pub struct AuditCheckpointCreate {
+    pub event_id: i64,
+    pub event_hash: Vec<u8>,
+    pub signature: String,
+}
+
+#[async_trait]
+pub(crate) trait AuditRepository: Send + Sync + 'static {
+    /// Locks the head of the audit chain against other writers until the end of the transaction,
+    /// so events are chained one at a time. Reads of the audit log are not blocked.
+    ///
+    /// # Return Values
+    /// ## Success
+    /// Ok, but empty.
+    ///
+    /// ## Errors
+    /// If a failure occured with the database.
+    async fn lock_audit_events(&mut self) -> Result<(), AuthError>;
+
+    /// Records an audit event, chained to the newest event.
+    ///
+    /// This must be called in a transaction, as it locks the head of the chain with
+    /// `lock_audit_events`.
+    ///
+    /// # Parameters
+    /// An AuditEventCreate structure, describing the event.
+    ///
+    /// # Return Values
+    /// ## Success
+    /// The recorded AuditEvent structure.
+    ///
+    /// ## Errors
+    /// If a failure occured with the database.
+    async fn record_audit_event(
+        &mut self,
+        event: &AuditEventCreate,
+    ) -> Result<AuditEvent, AuthError>;
+
+    /// Lists audit events, newest first.
+    ///
+    /// # Parameters
+    /// An AuditEventFilter structure, saying which events to list.
+    ///
+    /// # Return Values
+    /// ## Success
+    /// The events that match every field of the filter that is set, up to its limit.
+    ///
+    /// ## Errors
+    /// If a failure occured with the database.
+    async fn list_audit_events(
+        &mut self,
+        filter: &AuditEventFilter,
+    ) -> Result<Vec<AuditEvent>, AuthError>;
+
+    /// Lists audit events in the order they were chained, oldest first.
+    ///
+    /// # Parameters
+    /// The ID of the last event of the previous page, 0 for the first page, and the most events to
+    /// list.
+    ///
+    /// # Return Values
+    /// ## Success
+    /// The events after the given event, up to the limit.
+    ///
+    /// ## Errors
+    /// If a failure occured with the database.
+    async fn list_chained_audit_events(
+        &mut self,
+        after_id: i64,
+        limit: i64,
+    ) -> Result<Vec<AuditEvent>, AuthError>;
+
+    /// Gets the newest audit event.
+    ///
+    /// # Return Values
+    /// ## Success
+    /// The newest AuditEvent structure, or `None` if no event has been recorded.
+    ///
+    /// ## Errors
+    /// If a failure occured with the database.
+    async fn get_newest_audit_event(&mut self) -> Result<Option<AuditEvent>, AuthError>;
+
+    /// Records a signed checkpoint of the audit log.
+    ///
+    /// # Parameters
+    /// An AuditCheckpointCreate structure, with the newest event and its signature.
+    ///
+    /// # Return Values
+    /// ## Success
+    /// The recorded AuditCheckpoint structure.
+    ///
+    /// ## Errors
+    /// If a failure occured with the database.
+    async fn add_audit_checkpoint(
+        &mut self,
+        checkpoint: &AuditCheckpointCreate,
+    ) -> Result<AuditCheckpoint, AuthError>;
+
+    /// Lists every checkpoint of the audit log, in the order of their events.
+    ///
+    /// # Return Values
+    /// ## Success
+    /// The AuditCheckpoint structures.
+    ///
+    /// ## Errors
+    /// If a failure occured with the database.
+    async fn list_audit_checkpoints(&mut self) -> Result<Vec<AuditCheckpoint>, AuthError>;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_kinds_and_outcomes_are_parsed_from_their_names() {
+        for kind in &[
+            AuditEventKind::Registration,
+            AuditEventKind::Login,
+            AuditEventKind::TokenIssue,
+            AuditEventKind::TokenRefresh,
+            AuditEventKind::TokenRevocation,
+            AuditEventKind::PasswordChange,
+        ] {
+            assert_eq!(AuditEventKind::parse(kind.as_str()), Some(*kind));
+        }
+        assert_eq!(AuditEventKind::parse("Login"), None);
+
+        assert_eq!(AuditOutcome::parse("failure"), Some(AuditOutcome::Failure));
+        assert_eq!(AuditOutcome::parse(""), None);
+    }
+
+    #[test]
+    fn test_failed_results_are_recorded_with_their_reason() {
+        let event =
+            AuditEventCreate::new(AuditEventKind::TokenRevocation, None, None).because("logout");
+
+        let succeeded = event.clone().with_result(&Ok(()));
+        assert_eq!(succeeded.outcome, AuditOutcome::Success);
+        assert_eq!(succeeded.reason.as_deref(), Some("logout"));
+
+        let failed = event.with_result::<()>(&Err(AuthError::InvalidRefreshToken));
+        assert_eq!(failed.outcome, AuditOutcome::Failure);
+        assert_eq!(failed.reason.as_deref(), Some("invalid refresh token"));
+    }
+}
@@ -0,0 +1,251 @@
+/// Checks passwords against an offline index of passwords from known data breaches.
+///
+/// The index is built from the Have I Been Pwned Pwned Passwords dataset, downloaded as "range"
+/// files: one file per 5 hex character SHA-1 prefix, named by the prefix, with a
+/// `SUFFIX:COUNT` line for each breached password hash with that prefix.
+///
+/// The index file holds, for each prefix, the next 64 bits of each hash, sorted. A table of where
+/// each prefix's entries start is kept in memory, so a lookup reads only the entries sharing the
+/// password's prefix, on a blocking thread so other requests are not held up by the disk.
+/// Truncating the hashes makes false positives possible, but with fewer than 2^32 hashes the
+/// chance of one is below 1 in 2^32.
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::error::AuthError;
+
+/// Define the bytes every index file starts with.
+const MAGIC: &[u8; 8] = b"HIBPIDX1";
+
+/// Define the number of hash prefixes, i.e. 5 hex characters.
+const BUCKETS: usize = 1 << 20;
+
+/// Define the number of hex characters in a range file's hash prefix and suffix.
+const PREFIX_LENGTH: usize = 5;
+const SUFFIX_LENGTH: usize = 35;
+
+/// Define the size of an index file before its entries.
+const HEADER_LENGTH: u64 = (8 + (BUCKETS + 1) * 8) as u64;
+
+pub struct BreachedPasswords {
+    /// Shared with the blocking threads that read it.
+    file: Arc<File>,
+    /// The index of the first entry of each bucket, followed by the total number of entries.
+    offsets: Vec<u64>,
+}
+
+impl BreachedPasswords {
+    /// Opens an index file built by `build`.
+    pub fn open(path: &Path) -> Result<Self, AuthError> {
+        let mut reader = BufReader::new(File::open(path).map_err(io_error)?);
+
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic).map_err(io_error)?;
+        if &magic != MAGIC {
+            return Err(invalid_data(format!(
+                "{} is not a breached passwords index",
+                path.display()
+            )));
+        }
+
+        let mut offsets = Vec::with_capacity(BUCKETS + 1);
+        let mut offset = [0; 8];
+        for _ in 0..=BUCKETS {
+            reader.read_exact(&mut offset).map_err(io_error)?;
+            offsets.push(u64::from_be_bytes(offset));
+        }
+
+        Ok(Self {
+            file: Arc::new(reader.into_inner()),
+            offsets,
+        })
+    }
+
+    /// Checks whether a password appears in the index.
+    pub async fn contains(&self, password: &str) -> Result<bool, AuthError> {
+        let hash = openssl::sha::sha1(password.as_bytes());
+        let bucket =
+            (usize::from(hash[0]) << 12) | (usize::from(hash[1]) << 4) | usize::from(hash[2] >> 4);
+        // The 64 bits following the 20 bit prefix.
+        let key = (hash[2..11]
+            .iter()
+            .fold(0u128, |key, &byte| (key << 8) | u128::from(byte))
+            >> 4) as u64;
+
+        let start = self.offsets[bucket];
+        let end = self.offsets[bucket + 1];
+        let file = Arc::clone(&self.file);
+        let entries = tokio::task::spawn_blocking(move || {
+            let mut entries = vec![0u8; ((end - start) * 8) as usize];
+            file.read_exact_at(&mut entries, HEADER_LENGTH + start * 8)
+                .map(|_| entries)
+        })
+        .await
+        .map_err(|e| AuthError::Unknown(Box::new(e)))?
+        .map_err(io_error)?;
+
+        let entries: Vec<u64> = entries
+            .chunks_exact(8)
+            .map(|entry| u64::from_be_bytes(entry.try_into().expect("entries are 8 bytes")))
+            .collect();
+
+        Ok(entries.binary_search(&key).is_ok())
+    }
+
+    /// Builds an index file from a directory of range files.
+    ///
+    /// # Parameters
+    /// The directory of range files, the path to write the index to, and the number of breaches
+    /// a password must have appeared in to be included.
+    ///
+    /// # Return Values
+    /// ## Success
+    /// The number of password hashes in the index.
+    ///
+    /// ## Errors
+    /// If a file could not be read or written, or a range file was malformed.
+    pub fn build(range_dir: &Path, index_path: &Path, min_count: u64) -> Result<u64, AuthError> {
+        let mut range_files = vec![None; BUCKETS];
+        for entry in fs::read_dir(range_dir).map_err(io_error)? {
+            let path = entry.map_err(io_error)?.path();
+            let prefix = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .filter(|stem| stem.len() == PREFIX_LENGTH)
+                .and_then(|stem| usize::from_str_radix(stem, 16).ok());
+
+            if let Some(prefix) = prefix {
+                range_files[prefix] = Some(path);
+            }
+        }
+
+        let mut writer = BufWriter::new(File::create(index_path).map_err(io_error)?);
+        writer.write_all(MAGIC).map_err(io_error)?;
+        writer
+            .write_all(&vec![0u8; (BUCKETS + 1) * 8])
+            .map_err(io_error)?;
+
+        let mut offsets = Vec::with_capacity(BUCKETS + 1);
+        let mut count = 0;
+        for range_file in range_files {
+            offsets.push(count);
+
+            let mut keys = match range_file {
+                Some(path) => read_range_file(&path, min_count)?,
+                None => Vec::new(),
+            };
+            keys.sort_unstable();
+            keys.dedup();
+
+            for key in &keys {
+                writer.write_all(&key.to_be_bytes()).map_err(io_error)?;
+            }
+            count += keys.len() as u64;
+        }
+        offsets.push(count);
+
+        writer
+            .seek(SeekFrom::Start(MAGIC.len() as u64))
+            .map_err(io_error)?;
+        for offset in offsets {
+            writer.write_all(&offset.to_be_bytes()).map_err(io_error)?;
+        }
+        writer.flush().map_err(io_error)?;
+
+        Ok(count)
+    }
+}
+
+/// Reads the keys of the hashes in a range file that appeared in at least `min_count` breaches.
+fn read_range_file(path: &Path, min_count: u64) -> Result<Vec<u64>, AuthError> {
+    let reader = BufReader::new(File::open(path).map_err(io_error)?);
+    let mut keys = Vec::new();
+
+    for line in reader.lines() {
+        let line = line.map_err(io_error)?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let mut parts = line.splitn(2, ':');
+        let suffix = parts.next().unwrap_or_default();
+        let count = parts.next().and_then(|count| count.parse::<u64>().ok());
+        let key = suffix
+            .get(..16)
+            .filter(|_| suffix.len() == SUFFIX_LENGTH)
+            .and_then(|key| u64::from_str_radix(key, 16).ok());
+
+        match (key, count) {
+            (Some(key), Some(count)) if count >= min_count => keys.push(key),
+            (Some(_), Some(_)) => {}
+            _ => {
+                return Err(invalid_data(format!(
+                    "malformed line in {}: {}",
+                    path.display(),
+                    line
+                )))
+            }
+        }
+    }
+
+    Ok(keys)
+}
+
+fn io_error(e: io::Error) -> AuthError {
+    AuthError::Unknown(Box::new(e))
+}
+
+fn invalid_data(message: String) -> AuthError {
+    io_error(io::Error::new(io::ErrorKind::InvalidData, message))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::env;
+
+    use uuid::Uuid;
+
+    /// Builds an index of the SHA-1 hash of "password", which has appeared in 3730471 breaches.
+    fn build_index(min_count: u64) -> BreachedPasswords {
+        let dir = env::temp_dir().join(Uuid::new_v4().to_string());
+        let range_dir = dir.join("ranges");
+        fs::create_dir_all(&range_dir).unwrap();
+        fs::write(
+            range_dir.join("5BAA6.txt"),
+            "003D68EB55068C33ACE09247EE4C639306B:3\n\
+             1E4C9B93F3F0682250B6CF8331B7EE68FD8:3730471\n",
+        )
+        .unwrap();
+
+        let index_path = dir.join("index");
+        BreachedPasswords::build(&range_dir, &index_path, min_count).unwrap();
+        let breached_passwords = BreachedPasswords::open(&index_path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        breached_passwords
+    }
+
+    #[tokio::test]
+    async fn test_breached_passwords_are_found() {
+        let breached_passwords = build_index(1);
+
+        assert!(breached_passwords.contains("password").await.unwrap());
+        assert!(!breached_passwords.contains("Password").await.unwrap());
+        assert!(!breached_passwords
+            .contains("correct horse battery staple")
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_rarely_breached_passwords_can_be_excluded() {
+        assert!(!build_index(5_000_000).contains("password").await.unwrap());
+    }
+}
@@ -5,11 +5,22 @@ use async_trait::async_trait;
 pub mod postgres;
 
 /// A type that abstracts a database.
+///
+/// The repository traits are implemented for the underlying connection type, which both `Conn`
+/// and `Tx` dereference to, so the same operations can be used on their own or combined in a
+/// transaction.
 #[async_trait]
 pub trait Db {
     /// A connection to the database.
     type Conn;
 
+    /// A transaction on a connection to the database, which is rolled back if it is dropped
+    /// without being committed.
+    type Tx;
+
     /// Creates a new database pool connection.
     async fn conn(&self) -> Result<Self::Conn, AuthError>;
+
+    /// Begins a new transaction on a database pool connection.
+    async fn transaction(&self) -> Result<Self::Tx, AuthError>;
 }
@@ -3,7 +3,11 @@ use crate::error::AuthError;
 
 use async_trait::async_trait;
 use sqlx::pool::PoolConnection;
-use sqlx::{PgConnection, PgPool};
+use sqlx::{PgConnection, PgPool, Transaction};
+
+/// Define the SQLSTATE codes of the constraint violations translated into domain errors.
+const FOREIGN_KEY_VIOLATION: &str = "23503";
+const UNIQUE_VIOLATION: &str = "23505";
 
 /// Open a connection to a postgres database
 pub async fn connect(db_url: &str) -> Result<PgPool, AuthError> {
@@ -16,8 +20,165 @@ pub async fn connect(db_url: &str) -> Result<PgPool, AuthError> {
 #[async_trait]
 impl Db for PgPool {
     type Conn = PoolConnection<PgConnection>;
+    type Tx = Transaction<PoolConnection<PgConnection>>;
 
     async fn conn(&self) -> Result<Self::Conn, AuthError> {
         self.acquire().await.map_err(AuthError::DatabaseError)
     }
+
+    async fn transaction(&self) -> Result<Self::Tx, AuthError> {
+        self.begin().await.map_err(AuthError::DatabaseError)
+    }
+}
+
+/// Translates the database errors a query is expected to cause into domain errors.
+///
+/// Constraint violations and missing rows are caused by the request, not by a failing database,
+/// so they must not be returned as `AuthError::DatabaseError`, which clients retry.
+pub(crate) trait QueryResultExt<T> {
+    /// Replaces a unique constraint violation, e.g. a duplicate email address.
+    fn on_unique_violation(self, error: impl FnOnce() -> AuthError) -> Result<T, AuthError>;
+
+    /// Replaces a foreign key violation, e.g. a reference to an account that does not exist.
+    fn on_foreign_key_violation(self, error: impl FnOnce() -> AuthError) -> Result<T, AuthError>;
+
+    /// Replaces the error returned when a query expected to return a row returned none.
+    fn on_row_not_found(self, error: impl FnOnce() -> AuthError) -> Result<T, AuthError>;
+}
+
+impl<T, E: Into<AuthError>> QueryResultExt<T> for Result<T, E> {
+    fn on_unique_violation(self, error: impl FnOnce() -> AuthError) -> Result<T, AuthError> {
+        replace_error(self, |e| sqlstate(e) == Some(UNIQUE_VIOLATION), error)
+    }
+
+    fn on_foreign_key_violation(self, error: impl FnOnce() -> AuthError) -> Result<T, AuthError> {
+        replace_error(self, |e| sqlstate(e) == Some(FOREIGN_KEY_VIOLATION), error)
+    }
+
+    fn on_row_not_found(self, error: impl FnOnce() -> AuthError) -> Result<T, AuthError> {
+        replace_error(
+            self,
+            |e| match e {
+                sqlx::Error::RowNotFound => true,
+                _ => false,
+            },
+            error,
+        )
+    }
+}
+
+/// Replaces a database error matching a predicate, leaving any other error as it is.
+fn replace_error<T, E: Into<AuthError>>(
+    result: Result<T, E>,
+    matches: impl FnOnce(&sqlx::Error) -> bool,
+    error: impl FnOnce() -> AuthError,
+) -> Result<T, AuthError> {
+    match result.map_err(Into::into) {
+        Err(AuthError::DatabaseError(e)) if matches(&e) => Err(error()),
+        result => result,
+    }
+}
+
+/// Gets the SQLSTATE code of an error returned by Postgres.
+fn sqlstate(error: &sqlx::Error) -> Option<&str> {
+    match error {
+        sqlx::Error::Database(e) => e.code(),
+        _ => None,
+    }
+}
+
+/// Begins a transaction on the database at `DATABASE_URL` for a test, which is rolled back when
+/// it is dropped.
+#[cfg(test)]
+pub async fn test_transaction() -> Transaction<PoolConnection<PgConnection>> {
+    let database_url = dotenv::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    connect(&database_url)
+        .await
+        .expect("failed to connect to the test database")
+        .begin()
+        .await
+        .expect("failed to begin a test transaction")
+}
+
+/// Registers an account with a new email address for a test, written with an uppercase domain.
+#[cfg(test)]
+pub(crate) async fn register_test_account(
+    conn: &mut PgConnection,
+    password: Option<&str>,
+) -> crate::account::model::Account {
+    use crate::account::model::{AccountRegister, AccountRepository};
+
+    conn.register_new_account(&AccountRegister {
+        given_name: "Test".to_string(),
+        email: format!("{}@Example.com", uuid::Uuid::new_v4()),
+        password: password.map(str::to_string),
+    })
+    .await
+    .expect("register_new_account returned an error")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::account::model::AccountId;
+
+    /// Adds a password identity with a query that is not translated, so its errors are raw.
+    async fn add_password_identity(
+        conn: &mut PgConnection,
+        account_id: AccountId,
+    ) -> Result<u64, sqlx::Error> {
+        sqlx::query("INSERT INTO identities (account_id, provider) VALUES ($1, 'password')")
+            .bind(account_id)
+            .execute(conn)
+            .await
+    }
+
+    /// Runs a query returning one integer, whose errors are raw.
+    async fn select_integer(conn: &mut PgConnection, query: &str) -> Result<(i32,), sqlx::Error> {
+        sqlx::query_as(query).fetch_one(conn).await
+    }
+
+    #[tokio::test]
+    async fn test_unique_violation_is_translated() {
+        let mut tx = test_transaction().await;
+        let account = register_test_account(&mut tx, None).await;
+        add_password_identity(&mut tx, account.id).await.unwrap();
+
+        // The primary key of identities is the account and provider.
+        let result = add_password_identity(&mut tx, account.id)
+            .await
+            .on_foreign_key_violation(|| AuthError::AccountNotFound(account.id))
+            .on_unique_violation(|| AuthError::IdentityAlreadyLinked("password".to_string()));
+        assert!(matches!(result, Err(AuthError::IdentityAlreadyLinked(_))));
+    }
+
+    #[tokio::test]
+    async fn test_foreign_key_violation_is_translated() {
+        let mut tx = test_transaction().await;
+
+        let result = add_password_identity(&mut tx, -1)
+            .await
+            .on_unique_violation(|| AuthError::IdentityAlreadyLinked("password".to_string()))
+            .on_foreign_key_violation(|| AuthError::AccountNotFound(-1));
+        assert!(matches!(result, Err(AuthError::AccountNotFound(-1))));
+    }
+
+    #[tokio::test]
+    async fn test_only_expected_errors_are_translated() {
+        let mut tx = test_transaction().await;
+
+        let result = select_integer(&mut tx, "SELECT 1 WHERE false")
+            .await
+            .on_row_not_found(|| AuthError::AccountNotFound(-1));
+        assert!(matches!(result, Err(AuthError::AccountNotFound(-1))));
+
+        // A division by zero is none of the translated errors.
+        let result = select_integer(&mut tx, "SELECT 1 / 0")
+            .await
+            .on_row_not_found(|| AuthError::AccountNotFound(-1))
+            .on_unique_violation(|| AuthError::UserAlreadyExists(String::new()))
+            .on_foreign_key_violation(|| AuthError::AccountNotFound(-1));
+        assert!(matches!(result, Err(AuthError::DatabaseError(_))));
+    }
 }
@@ -0,0 +1,257 @@
+/// Serves the JWK Set and OpenID Connect discovery metadata over HTTP.
+///
+/// Off-the-shelf JWT middleware can only fetch verification keys over HTTP, so these are served
+/// next to the gRPC server. So are the service's metrics, for Prometheus to scrape.
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::jwt::model::JWT_ISSUER;
+use crate::keys::manager::KeyManager;
+use crate::keys::store::KeyStore;
+use crate::rate_limit::RateLimiter;
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Request, Response, Server, StatusCode};
+use serde::Serialize;
+
+/// Define the path the JWK Set is served from.
+const JWKS_PATH: &str = "/.well-known/jwks.json";
+
+/// Define the path the OpenID Connect discovery metadata is served from.
+const OPENID_CONFIGURATION_PATH: &str = "/.well-known/openid-configuration";
+
+/// Define the path the metrics are served from, in the Prometheus text format.
+const METRICS_PATH: &str = "/metrics";
+
+/// Define the hosts that may be served over plain HTTP, for development.
+const LOOPBACK_HOSTS: [&str; 3] = ["localhost", "127.0.0.1", "[::1]"];
+
+/// Defines the OpenID Connect discovery metadata.
+#[derive(Debug, Serialize)]
+struct OpenIdConfiguration {
+    issuer: String,
+    authorization_endpoint: String,
+    jwks_uri: String,
+    response_types_supported: Vec<String>,
+    subject_types_supported: Vec<String>,
+    id_token_signing_alg_values_supported: Vec<String>,
+    claims_supported: Vec<String>,
+}
+
+impl OpenIdConfiguration {
+    /// Creates the metadata for the JWTs signed by the published keys.
+    ///
+    /// # Parameters
+    /// The page users sign in at, and the published keys.
+    fn new(sign_in_url: &str, keys: &KeyStore) -> Self {
+        let mut algorithms = Vec::new();
+        for jwk in keys.jwks().keys {
+            if !algorithms.contains(&jwk.alg) {
+                algorithms.push(jwk.alg);
+            }
+        }
+
+        Self {
+            issuer: JWT_ISSUER.to_string(),
+            authorization_endpoint: sign_in_url.to_string(),
+            jwks_uri: format!("{}{}", *JWT_ISSUER, JWKS_PATH),
+            response_types_supported: vec!["id_token".to_string()],
+            subject_types_supported: vec!["public".to_string()],
+            id_token_signing_alg_values_supported: algorithms,
+            claims_supported: ["iss", "sub", "iat", "exp", "email", "email_verified"]
+                .iter()
+                .map(|claim| claim.to_string())
+                .collect(),
+        }
+    }
+}
+
+/// Checks that the issuer is a URL that its discovery metadata can be fetched from securely.
+///
+/// This must be an https URL without a query or fragment, though plain http is allowed on this
+/// machine for development.
+pub fn check_issuer(issuer: &str) -> Result<(), String> {
+    let (scheme, rest) = match issuer.find("://") {
+        Some(i) => (&issuer[..i], &issuer[i + "://".len()..]),
+        None => return Err(format!("issuer {} is not a URL", issuer)),
+    };
+    let authority = rest.split('/').next().unwrap_or("");
+    let loopback = LOOPBACK_HOSTS
+        .iter()
+        .any(|host| authority == *host || authority.starts_with(&format!("{}:", host)));
+
+    if authority.is_empty() || issuer.contains('?') || issuer.contains('#') {
+        Err(format!(
+            "issuer {} must be a URL without a query or fragment",
+            issuer
+        ))
+    } else if scheme != "https" && !(scheme == "http" && loopback) {
+        Err(format!("issuer {} must be an https URL", issuer))
+    } else {
+        Ok(())
+    }
+}
+
+/// Runs the HTTP server until it fails.
+///
+/// # Parameters
+/// The address to listen on, the signing keys to publish, the page users sign in at, and the rate
+/// limiter to report metrics for.
+pub async fn run_http_server(
+    addr: SocketAddr,
+    keys: Arc<KeyManager>,
+    sign_in_url: String,
+    rate_limiter: Arc<RateLimiter>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let make_service = make_service_fn(move |_| {
+        let keys = keys.clone();
+        let sign_in_url = sign_in_url.clone();
+        let rate_limiter = rate_limiter.clone();
+
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                handle(
+                    request,
+                    keys.clone(),
+                    sign_in_url.clone(),
+                    rate_limiter.clone(),
+                )
+            }))
+        }
+    });
+
+    Server::bind(&addr).serve(make_service).await?;
+
+    Ok(())
+}
+
+async fn handle(
+    request: Request<Body>,
+    keys: Arc<KeyManager>,
+    sign_in_url: String,
+    rate_limiter: Arc<RateLimiter>,
+) -> Result<Response<Body>, Infallible> {
+    if request.method() != Method::GET {
+        return Ok(status(StatusCode::METHOD_NOT_ALLOWED));
+    }
+
+    if request.uri().path() == METRICS_PATH {
+        return Ok(Response::builder()
+            .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(Body::from(rate_limiter.render_metrics()))
+            .unwrap_or_else(|_| status(StatusCode::INTERNAL_SERVER_ERROR)));
+    }
+
+    Ok(respond(
+        request.uri().path(),
+        &*keys.store().await,
+        &sign_in_url,
+    ))
+}
+
+/// Responds to a request for the JWK Set or the discovery metadata.
+fn respond(path: &str, keys: &KeyStore, sign_in_url: &str) -> Response<Body> {
+    let body = match path {
+        JWKS_PATH => serde_json::to_vec(&keys.jwks()),
+        OPENID_CONFIGURATION_PATH => {
+            serde_json::to_vec(&OpenIdConfiguration::new(sign_in_url, keys))
+        }
+        _ => return status(StatusCode::NOT_FOUND),
+    };
+
+    match body {
+        Ok(body) => Response::builder()
+            .header(header::CONTENT_TYPE, "application/json")
+            // Allow verifiers to cache the keys, new keys are published well before they are used.
+            .header(header::CACHE_CONTROL, "public, max-age=300")
+            .body(Body::from(body))
+            .unwrap_or_else(|_| status(StatusCode::INTERNAL_SERVER_ERROR)),
+        Err(_) => status(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+fn status(status_code: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status_code;
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::keys::model::KeyAlgorithm;
+    use crate::keys::store::test_key_store;
+
+    use serde_json::Value;
+
+    const SIGN_IN_URL: &str = "https://example.com/sign-in";
+
+    /// Responds to a request for a path, returning the status and the JSON body.
+    async fn get(path: &str, keys: &KeyStore) -> (StatusCode, Value) {
+        let response = respond(path, keys, SIGN_IN_URL);
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    #[tokio::test]
+    async fn test_jwks_publishes_the_keys() {
+        let keys = test_key_store(KeyAlgorithm::ES256);
+        let kid = keys.active_key().unwrap().kid.clone();
+
+        let (status, jwks) = get(JWKS_PATH, &keys).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(jwks["keys"][0]["kid"], Value::String(kid));
+        assert_eq!(jwks["keys"][0]["alg"], "ES256");
+        assert!(jwks["keys"][0].get("d").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_openid_configuration_matches_the_tokens() {
+        let keys = test_key_store(KeyAlgorithm::RS256);
+
+        let (status, configuration) = get(OPENID_CONFIGURATION_PATH, &keys).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(configuration["issuer"], JWT_ISSUER.as_str());
+        assert_eq!(
+            configuration["jwks_uri"],
+            format!("{}{}", *JWT_ISSUER, JWKS_PATH).as_str()
+        );
+        assert_eq!(configuration["authorization_endpoint"], SIGN_IN_URL);
+        assert_eq!(
+            configuration["response_types_supported"],
+            serde_json::json!(["id_token"])
+        );
+        assert_eq!(
+            configuration["id_token_signing_alg_values_supported"],
+            serde_json::json!(["RS256"])
+        );
+
+        let (status, _) = get("/.well-known/unknown", &keys).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_issuer_must_be_https_except_on_this_machine() {
+        for issuer in &[
+            "https://auth.example.com",
+            "https://auth.example.com/tenant",
+            "http://localhost:8080",
+            "http://127.0.0.1",
+        ] {
+            assert_eq!(check_issuer(issuer), Ok(()), "{}", issuer);
+        }
+        for issuer in &[
+            "http://auth.example.com",
+            "http://localhost.example.com",
+            "https://auth.example.com?tenant=1",
+            "https://",
+            "authentication",
+        ] {
+            assert!(check_issuer(issuer).is_err(), "{}", issuer);
+        }
+    }
+}
@@ -0,0 +1,181 @@
+/// Validates email addresses and converts them to a canonical form.
+///
+/// Addresses are compared case-insensitively, so the canonical form is lowercase. The domain is
+/// normalized with IDNA, e.g. `Bücher.example` becomes `xn--bcher-kva.example`, so the same domain
+/// written in Unicode or Punycode is the same address.
+use crate::error::{AuthError, FieldViolation};
+
+/// Define the maximum lengths of an address and its parts, in bytes, from RFC 5321.
+const MAX_LENGTH: usize = 254;
+const MAX_LOCAL_PART_LENGTH: usize = 64;
+const MAX_DOMAIN_LENGTH: usize = 253;
+const MAX_LABEL_LENGTH: usize = 63;
+
+/// Validates an email address and converts it to its canonical form.
+///
+/// # Parameters
+/// The name of the request field the address was sent in, and the address.
+///
+/// # Return Values
+/// ## Success
+/// The canonical form of the address.
+///
+/// ## Errors
+/// `AuthError::InvalidFields`, if the address is not valid.
+pub fn canonicalize(field: &str, email: &str) -> Result<String, AuthError> {
+    let invalid =
+        |description: &str| AuthError::InvalidFields(vec![FieldViolation::new(field, description)]);
+
+    let email = email.trim();
+    // The local part can contain a quoted @, but the domain cannot.
+    let at = email
+        .rfind('@')
+        .ok_or_else(|| invalid("must contain an @"))?;
+    let (local_part, domain) = (&email[..at], &email[at + 1..]);
+
+    if !is_valid_local_part(local_part) {
+        return Err(invalid("must have a valid name before the @"));
+    }
+    if local_part.len() > MAX_LOCAL_PART_LENGTH {
+        return Err(invalid(&format!(
+            "must have at most {} characters before the @",
+            MAX_LOCAL_PART_LENGTH
+        )));
+    }
+
+    let domain = idna::domain_to_ascii(domain)
+        .ok()
+        .filter(|domain| is_valid_domain(domain))
+        .ok_or_else(|| invalid("must have a valid domain after the @"))?;
+
+    let canonical = format!("{}@{}", local_part.to_lowercase(), domain);
+    if canonical.len() > MAX_LENGTH {
+        return Err(invalid(&format!(
+            "must be at most {} characters",
+            MAX_LENGTH
+        )));
+    }
+
+    Ok(canonical)
+}
+
+/// Checks whether a local part is a dot-atom or a quoted string, as defined by RFC 5322.
+///
+/// Non-ASCII characters are allowed in both, as they are by RFC 6531.
+fn is_valid_local_part(local_part: &str) -> bool {
+    if local_part.len() >= 2 && local_part.starts_with('"') && local_part.ends_with('"') {
+        let mut chars = local_part[1..local_part.len() - 1].chars();
+        while let Some(c) = chars.next() {
+            let valid = match c {
+                // A quoted pair, e.g. \" or \\.
+                '\\' => chars.next().map_or(false, |c| is_printable(c) || c == ' '),
+                '"' => false,
+                c => is_printable(c) || c == ' ',
+            };
+            if !valid {
+                return false;
+            }
+        }
+
+        return true;
+    }
+
+    !local_part.is_empty()
+        && local_part
+            .split('.')
+            .all(|atom| !atom.is_empty() && atom.chars().all(is_atext))
+}
+
+/// Checks whether a character can appear in an atom.
+fn is_atext(c: char) -> bool {
+    c.is_ascii_alphanumeric()
+        || "!#$%&'*+-/=?^_`{|}~".contains(c)
+        || (!c.is_ascii() && is_printable(c))
+}
+
+/// Checks whether a character is printable, excluding spaces.
+fn is_printable(c: char) -> bool {
+    !c.is_control() && !c.is_whitespace()
+}
+
+/// Checks whether an ASCII domain is a valid host name with at least two labels.
+///
+/// Address literals, e.g. `[192.0.2.1]`, are not accepted.
+fn is_valid_domain(domain: &str) -> bool {
+    let labels: Vec<&str> = domain.split('.').collect();
+
+    domain.len() <= MAX_DOMAIN_LENGTH
+        && labels.len() >= 2
+        && labels.iter().all(|label| {
+            !label.is_empty()
+                && label.len() <= MAX_LABEL_LENGTH
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn canonical(email: &str) -> Option<String> {
+        canonicalize("email", email).ok()
+    }
+
+    #[test]
+    fn test_addresses_are_canonicalized() {
+        assert_eq!(
+            canonical(" Bob.Smith+Tag@Example.COM "),
+            Some("bob.smith+tag@example.com".to_string())
+        );
+        assert_eq!(
+            canonical("anna@Bücher.example"),
+            Some("anna@xn--bcher-kva.example".to_string())
+        );
+        assert_eq!(
+            canonical("anna@xn--bcher-kva.example"),
+            canonical("anna@bücher.example")
+        );
+        assert_eq!(
+            canonical(r#""john \"doe\""@example.com"#),
+            Some(r#""john \"doe\""@example.com"#.to_string())
+        );
+    }
+
+    #[test]
+    fn test_invalid_addresses_are_rejected() {
+        for email in &[
+            "",
+            "bob",
+            "@example.com",
+            "bob@",
+            "bob@localhost",
+            "bob@-example.com",
+            "bob@example..com",
+            "bob@[192.0.2.1]",
+            ".bob@example.com",
+            "bob..smith@example.com",
+            "bob smith@example.com",
+            r#""bob"smith"@example.com"#,
+        ] {
+            assert_eq!(canonical(email), None, "{} was accepted", email);
+        }
+
+        let long_local_part = format!("{}@example.com", "a".repeat(65));
+        assert_eq!(canonical(&long_local_part), None);
+    }
+
+    #[test]
+    fn test_invalid_addresses_are_reported_on_the_field() {
+        match canonicalize("invitation.email", "bob") {
+            Err(AuthError::InvalidFields(violations)) => {
+                assert_eq!(violations[0].field, "invitation.email");
+                assert_eq!(violations[0].description, "must contain an @");
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use super::model::{EmailVerification, EmailVerificationCreate, EmailVerificationRepository};
+
+use crate::account::model::AccountId;
+use crate::database::postgres::QueryResultExt;
+use crate::error::AuthError;
+use crate::hashing::TokenDigest;
+
+use async_trait::async_trait;
+use chrono::Utc;
+use sqlx::PgConnection;
+
+#[async_trait]
+impl EmailVerificationRepository for PgConnection {
+    async fn issue_email_verification(
+        &mut self,
+        account_id: AccountId,
+        email: &str,
+    ) -> Result<String, AuthError> {
+        let email_verification = EmailVerificationCreate::new(account_id, email);
+
+        sqlx::query!(
+            r#"
+            INSERT INTO email_verifications (account_id, email, token_hash, issued_at, expires)
+            VALUES($1, $2, $3, $4, $5)
+            "#,
+            email_verification.account_id,
+            email_verification.email,
+            email_verification.token_hash,
+            email_verification.issued_at,
+            email_verification.expires,
+        )
+        .execute(self)
+        .await
+        .on_foreign_key_violation(|| AuthError::AccountNotFound(email_verification.account_id))?;
+
+        Ok(email_verification.token)
+    }
+
+    async fn use_email_verification(
+        &mut self,
+        token: &str,
+    ) -> Result<EmailVerification, AuthError> {
+        let now = Utc::now().naive_utc();
+
+        // Only use the token if it is still unused, so concurrent requests cannot both use it.
+        sqlx::query_as!(
+            EmailVerification,
+            r#"
+            UPDATE email_verifications SET used_at = $2
+            WHERE token_hash = $1 AND used_at IS NULL AND expires > $2
+            RETURNING *
+            "#,
+            TokenDigest::digest(token),
+            now,
+        )
+        .fetch_optional(self)
+        .await?
+        .ok_or(AuthError::InvalidVerificationToken)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::database::postgres::{register_test_account, test_transaction};
+
+    #[tokio::test]
+    async fn test_verification_token_can_be_used_once() {
+        let mut tx = test_transaction().await;
+        let account = register_test_account(&mut tx, None).await;
+
+        let token = tx
+            .issue_email_verification(account.id, &account.email)
+            .await
+            .unwrap();
+        let email_verification = tx.use_email_verification(&token).await.unwrap();
+        assert_eq!(email_verification.account_id, account.id);
+        assert_eq!(email_verification.email, account.email);
+
+        assert!(matches!(
+            tx.use_email_verification(&token).await,
+            Err(AuthError::InvalidVerificationToken)
+        ));
+    }
+}
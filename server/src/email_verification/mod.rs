@@ -0,0 +1,7 @@
+/// Email verification proves that a user owns the email address of their account.
+///
+/// A single-use token is emailed to the address, and presenting it back marks the address as
+/// verified. Only a keyed digest of each token is stored, in the same way as refresh tokens.
+///
+pub mod database;
+pub mod model;
@@ -0,0 +1,114 @@
+/// Data models for email verification tokens.
+use crate::account::model::AccountId;
+use crate::error::AuthError;
+use crate::hashing::TokenDigest;
+
+use async_trait::async_trait;
+use chrono::naive::NaiveDateTime;
+use chrono::{Duration, Utc};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+
+/// Define a custom type for Email Verification IDs.
+pub type EmailVerificationId = i32;
+
+/// Define the number of characters a verification token should contain.
+const TOKEN_LENGTH: usize = 64;
+
+/// Define how long a verification token can be used for, in hours.
+const TOKEN_EXPIRY_HOURS: i64 = 24;
+
+#[derive(Debug)]
+pub struct EmailVerification {
+    pub id: EmailVerificationId,
+    pub account_id: AccountId,
+    /// The email address the token was sent to.
+    pub email: String,
+    pub token_hash: Vec<u8>,
+    pub issued_at: NaiveDateTime,
+    pub expires: NaiveDateTime,
+    pub used_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug)]
+pub struct EmailVerificationCreate {
+    pub account_id: AccountId,
+    pub email: String,
+    pub token: String,
+    pub token_hash: Vec<u8>,
+    pub issued_at: NaiveDateTime,
+    pub expires: NaiveDateTime,
+}
+
+impl EmailVerificationCreate {
+    /// Generates a new verification token for an email address, with the default expiry time.
+    pub fn new(account_id: AccountId, email: &str) -> Self {
+        let issued_at = Utc::now();
+        let expires = issued_at + Duration::hours(TOKEN_EXPIRY_HOURS);
+        let token: String = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(TOKEN_LENGTH)
+            .collect();
+
+        EmailVerificationCreate {
+            account_id,
+            email: email.to_string(),
+            token_hash: TokenDigest::digest(&token),
+            token,
+            issued_at: issued_at.naive_utc(),
+            expires: expires.naive_utc(),
+        }
+    }
+}
+
+#[async_trait]
+pub(crate) trait EmailVerificationRepository {
+    /// Issues a new verification token for an account's email address.
+    ///
+    /// # Parameters
+    /// The ID of the account, and the email address the token will be sent to.
+    ///
+    /// # Returns
+    /// ## Success
+    /// The plain-text token, which is not stored and must be sent to the user.
+    ///
+    /// ## Errors
+    /// If the account was not found, or a database failure occured.
+    async fn issue_email_verification(
+        &mut self,
+        account_id: AccountId,
+        email: &str,
+    ) -> Result<String, AuthError>;
+
+    /// Uses a verification token, so that it cannot be used again.
+    ///
+    /// # Parameters
+    /// The verification token presented by the user.
+    ///
+    /// # Returns
+    /// ## Success
+    /// The EmailVerification structure the token was issued for.
+    ///
+    /// ## Errors
+    /// If the token was not found, has expired or has already been used, or a database failure
+    /// occured.
+    async fn use_email_verification(&mut self, token: &str)
+        -> Result<EmailVerification, AuthError>;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_new_token_is_stored_as_a_digest() {
+        let email_verification = EmailVerificationCreate::new(1, "test@example.com");
+
+        assert_eq!(email_verification.token.len(), TOKEN_LENGTH);
+        assert!(TokenDigest::verify(
+            &email_verification.token,
+            &email_verification.token_hash
+        ));
+        assert!(email_verification.expires > Utc::now().naive_utc());
+    }
+}
@@ -1,4 +1,31 @@
+use crate::account::model::AccountId;
+use crate::invitation::model::InvitationId;
+
+use prost::Message;
 use thiserror::Error;
+use tonic::Code;
+
+/// The google.rpc error model, used to send structured error details.
+pub(crate) mod rpc {
+    tonic::include_proto!("google.rpc");
+}
+
+/// Describes why a request field was rejected.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldViolation {
+    /// The name of the request field.
+    pub field: String,
+    pub description: String,
+}
+
+impl FieldViolation {
+    pub fn new(field: &str, description: &str) -> Self {
+        Self {
+            field: field.to_string(),
+            description: description.to_string(),
+        }
+    }
+}
 
 /// Enum listing possible authentication error codes.
 #[derive(Error, Debug)]
@@ -8,14 +35,68 @@ pub enum AuthError {
     #[error("the request was invalid {0}")]
     InvalidRequest(String),
 
+    /// If one or more request fields were rejected, e.g. a password that breaks the password
+    /// policy. These are sent to the client as a `google.rpc.BadRequest`.
+    #[error("invalid fields {0:?}")]
+    InvalidFields(Vec<FieldViolation>),
+
     /// If the username and password combination did not match when attempting to authenticate.
     #[error("invalid username or password")]
     InvalidUsernameOrPassword,
 
+    /// If the password was correct, but has been found in a data breach and must be reset.
+    #[error("the password has appeared in a data breach and must be reset")]
+    PasswordResetRequired,
+
     /// If a registration was attempted, but the email address already exists in the database.
     #[error("a user with the email {0} already exists")]
     UserAlreadyExists(String),
 
+    /// If an account was not found, e.g. because it was deleted after a token was issued for it.
+    #[error("account {0} not found")]
+    AccountNotFound(AccountId),
+
+    /// If a user signed in with an identity provider that has not verified their email address.
+    #[error("the email address {0} has not been verified")]
+    UnverifiedEmail(String),
+
+    /// If an identity is already linked to an account.
+    #[error("the {0} identity is already linked")]
+    IdentityAlreadyLinked(String),
+
+    /// If a user signed in with an identity provider for the first time, but an account already
+    /// has their email address. The identity must be linked to it with `LinkIdentity`.
+    #[error("an account with the email {0} already exists, sign in to it to link this identity")]
+    EmailNotLinked(String),
+
+    /// If an identity is not linked to the account.
+    #[error("the {0} identity is not linked")]
+    IdentityNotFound(String),
+
+    /// If unlinking an identity would leave the account with no way to sign in.
+    #[error("the last identity of an account cannot be unlinked")]
+    LastIdentity,
+
+    /// If an email verification token was not found, has expired, or has already been used.
+    #[error("invalid verification token")]
+    InvalidVerificationToken,
+
+    /// If a password reset token was not found, has expired, or has already been used.
+    #[error("invalid password reset token")]
+    InvalidPasswordResetToken,
+
+    /// If an invitation was required to register, but was not found or can no longer be used.
+    #[error("invalid invitation")]
+    InvalidInvitation,
+
+    /// If an invitation to revoke was not found, or has already been revoked.
+    #[error("no active invitation with ID {0}")]
+    InvitationNotFound(InvitationId),
+
+    /// An error occured when sending an email.
+    #[error("mail error {0}")]
+    MailError(String),
+
     /// An error occured when connecting to or using the database.
     #[error("database error")]
     DatabaseError(#[from] sqlx::Error),
@@ -24,6 +105,31 @@ pub enum AuthError {
     #[error("invalid token")]
     InvalidToken(#[from] jsonwebtoken::errors::Error),
 
+    /// If a request requiring authentication did not include a bearer token.
+    #[error("missing authorization token")]
+    MissingToken,
+
+    /// If there have been too many failed sign in attempts, and another cannot be made until the
+    /// lockout ends. The delay is sent to the client as a `google.rpc.RetryInfo`.
+    #[error("too many failed attempts, retry after {} seconds", .0.num_seconds())]
+    TooManyAttempts(chrono::Duration),
+
+    /// If the request was authenticated, but is not allowed to perform the operation.
+    #[error("permission denied")]
+    PermissionDenied,
+
+    /// If a refresh token was not found, has expired, or has been revoked.
+    #[error("invalid refresh token")]
+    InvalidRefreshToken,
+
+    /// An error occured when communicating with a third-party identity provider.
+    #[error("identity provider error {0}")]
+    IdentityProviderError(String),
+
+    /// An error occured when loading, generating, or using a signing key.
+    #[error("signing key error {0}")]
+    KeyError(String),
+
     /// An error occured with the Argon2id hashing implementation.
     #[error("hashing error")]
     HashingError,
@@ -39,18 +145,181 @@ impl From<AuthError> for tonic::Status {
             AuthError::InvalidRequest(_) => {
                 tonic::Status::invalid_argument(format!("{:?}", auth_error))
             }
+            AuthError::InvalidFields(violations) => bad_request(violations),
             AuthError::InvalidUsernameOrPassword => {
                 tonic::Status::unauthenticated(format!("{:?}", auth_error))
             }
+            AuthError::PasswordResetRequired => {
+                tonic::Status::failed_precondition(format!("{:?}", auth_error))
+            }
             AuthError::UserAlreadyExists(_) => {
+                tonic::Status::already_exists(format!("{:?}", auth_error))
+            }
+            AuthError::AccountNotFound(_) => tonic::Status::not_found(format!("{:?}", auth_error)),
+            AuthError::UnverifiedEmail(_) => {
+                tonic::Status::failed_precondition(format!("{:?}", auth_error))
+            }
+            AuthError::IdentityAlreadyLinked(_) => {
+                tonic::Status::already_exists(format!("{:?}", auth_error))
+            }
+            AuthError::EmailNotLinked(_) => {
+                tonic::Status::already_exists(format!("{:?}", auth_error))
+            }
+            AuthError::IdentityNotFound(_) => tonic::Status::not_found(format!("{:?}", auth_error)),
+            AuthError::LastIdentity => {
+                tonic::Status::failed_precondition(format!("{:?}", auth_error))
+            }
+            AuthError::InvalidVerificationToken => {
+                tonic::Status::invalid_argument(format!("{:?}", auth_error))
+            }
+            AuthError::InvalidPasswordResetToken => {
                 tonic::Status::invalid_argument(format!("{:?}", auth_error))
             }
+            AuthError::InvalidInvitation => {
+                tonic::Status::permission_denied(format!("{:?}", auth_error))
+            }
+            AuthError::InvitationNotFound(_) => {
+                tonic::Status::not_found(format!("{:?}", auth_error))
+            }
+            AuthError::MailError(_) => tonic::Status::unavailable(format!("{:?}", auth_error)),
             AuthError::DatabaseError(_) => tonic::Status::unavailable(format!("{:?}", auth_error)),
             AuthError::InvalidToken(_) => {
                 tonic::Status::unauthenticated(format!("{:?}", auth_error))
             }
+            AuthError::MissingToken => tonic::Status::unauthenticated(format!("{:?}", auth_error)),
+            AuthError::TooManyAttempts(retry_delay) => too_many_attempts(retry_delay),
+            AuthError::PermissionDenied => {
+                tonic::Status::permission_denied(format!("{:?}", auth_error))
+            }
+            AuthError::InvalidRefreshToken => {
+                tonic::Status::unauthenticated(format!("{:?}", auth_error))
+            }
+            AuthError::IdentityProviderError(_) => {
+                tonic::Status::unavailable(format!("{:?}", auth_error))
+            }
+            AuthError::KeyError(_) => tonic::Status::internal(format!("{:?}", auth_error)),
             AuthError::HashingError => tonic::Status::unavailable(format!("{:?}", auth_error)),
             _ => tonic::Status::unknown(format!("{:?}", auth_error)),
         }
     }
 }
+
+impl From<openssl::error::ErrorStack> for AuthError {
+    fn from(error_stack: openssl::error::ErrorStack) -> AuthError {
+        AuthError::KeyError(error_stack.to_string())
+    }
+}
+
+/// Creates an INVALID_ARGUMENT status, with the field violations in its details.
+fn bad_request(violations: Vec<FieldViolation>) -> tonic::Status {
+    let message = violations
+        .iter()
+        .map(|violation| format!("{}: {}", violation.field, violation.description))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let bad_request = rpc::BadRequest {
+        field_violations: violations
+            .into_iter()
+            .map(|violation| rpc::bad_request::FieldViolation {
+                field: violation.field,
+                description: violation.description,
+            })
+            .collect(),
+    };
+
+    with_detail(Code::InvalidArgument, message, "BadRequest", bad_request)
+}
+
+/// Creates a RESOURCE_EXHAUSTED status, with the delay before the client can retry in its details.
+fn too_many_attempts(retry_delay: chrono::Duration) -> tonic::Status {
+    let message = format!(
+        "too many failed attempts, retry after {} seconds",
+        retry_delay.num_seconds()
+    );
+    let retry_info = rpc::RetryInfo {
+        retry_delay: Some(prost_types::Duration {
+            seconds: retry_delay.num_seconds(),
+            nanos: 0,
+        }),
+    };
+
+    with_detail(Code::ResourceExhausted, message, "RetryInfo", retry_info)
+}
+
+/// Creates a status with a google.rpc error detail message, named without its package.
+fn with_detail(code: Code, message: String, name: &str, detail: impl Message) -> tonic::Status {
+    // Encoding only fails if the buffer is too small, which a Vec never is.
+    let mut detail_bytes = Vec::new();
+    detail
+        .encode(&mut detail_bytes)
+        .expect("failed to encode error detail");
+
+    let status = rpc::Status {
+        code: code as i32,
+        message: message.clone(),
+        details: vec![prost_types::Any {
+            type_url: format!("type.googleapis.com/google.rpc.{}", name),
+            value: detail_bytes,
+        }],
+    };
+    let mut status_bytes = Vec::new();
+    status
+        .encode(&mut status_bytes)
+        .expect("failed to encode Status");
+
+    tonic::Status::with_details(code, message, status_bytes.into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_invalid_fields_are_sent_as_bad_request_details() {
+        let violation = FieldViolation::new("password", "must be at least 8 characters");
+        let status = tonic::Status::from(AuthError::InvalidFields(vec![violation.clone()]));
+
+        assert_eq!(status.code(), Code::InvalidArgument);
+
+        let details = rpc::Status::decode(status.details()).expect("details were not a Status");
+        let bad_request = rpc::BadRequest::decode(&details.details[0].value[..])
+            .expect("detail was not a BadRequest");
+        assert_eq!(bad_request.field_violations[0].field, violation.field);
+        assert_eq!(
+            bad_request.field_violations[0].description,
+            violation.description
+        );
+    }
+
+    #[test]
+    fn test_translated_database_errors_are_not_retryable() {
+        let status = tonic::Status::from(AuthError::UserAlreadyExists("a@example.com".to_string()));
+        assert_eq!(status.code(), Code::AlreadyExists);
+
+        let status = tonic::Status::from(AuthError::InvalidUsernameOrPassword);
+        assert_eq!(status.code(), Code::Unauthenticated);
+
+        let status = tonic::Status::from(AuthError::AccountNotFound(1));
+        assert_eq!(status.code(), Code::NotFound);
+
+        let status = tonic::Status::from(AuthError::InvitationNotFound(1));
+        assert_eq!(status.code(), Code::NotFound);
+    }
+
+    #[test]
+    fn test_too_many_attempts_is_sent_with_retry_info() {
+        let status = tonic::Status::from(AuthError::TooManyAttempts(chrono::Duration::seconds(30)));
+
+        assert_eq!(status.code(), Code::ResourceExhausted);
+
+        let details = rpc::Status::decode(status.details()).expect("details were not a Status");
+        assert_eq!(
+            details.details[0].type_url,
+            "type.googleapis.com/google.rpc.RetryInfo"
+        );
+        let retry_info = rpc::RetryInfo::decode(&details.details[0].value[..])
+            .expect("detail was not a RetryInfo");
+        assert_eq!(retry_info.retry_delay.map(|delay| delay.seconds), Some(30));
+    }
+}
@@ -1,7 +1,28 @@
-/// Defines functions to hash passwords, and verify those hashes.
+/// Defines functions to hash passwords and tokens, and verify those hashes.
 use crate::error::AuthError;
 
 use argonautica::{Hasher, Verifier};
+use hmac::{Hmac, Mac};
+use once_cell::sync::Lazy;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// A hash of a random password, verified against when there is no real hash, so that every failed
+/// sign in does the same work.
+static DUMMY_HASH: Lazy<String> = Lazy::new(|| {
+    let password: String = thread_rng().sample_iter(&Alphanumeric).take(32).collect();
+    Argon2id::hash_password(&password).expect("failed to hash the dummy password")
+});
+
+#[cfg(test)]
+thread_local! {
+    /// The number of passwords verified against the dummy hash on this thread, so tests can check
+    /// that a failure does the same work without timing it.
+    pub(crate) static DUMMY_VERIFICATIONS: std::cell::Cell<usize> = std::cell::Cell::new(0);
+}
 
 pub struct Argon2id;
 
@@ -44,6 +65,72 @@ impl Argon2id {
             Err(_) => Err(AuthError::HashingError),
         }
     }
+
+    /// Verifies a plain-text password against an Argon2id hash, if there is one.
+    ///
+    /// Without a hash, e.g. because the account was not found, the password is verified against a
+    /// dummy hash instead, so the time taken does not reveal that there was no hash.
+    ///
+    /// # Parameters
+    /// A plain-text password and an optional Argon2id hash.
+    ///
+    /// # Return Values
+    /// `true` if there was a hash and the password matched it, `false` otherwise.
+    pub fn verify_password_or_dummy(password: &str, hash: Option<&str>) -> Result<bool, AuthError> {
+        match hash {
+            Some(hash) => Self::verify_password(password, hash),
+            None => {
+                #[cfg(test)]
+                DUMMY_VERIFICATIONS.with(|count| count.set(count.get() + 1));
+
+                Self::verify_password(password, &DUMMY_HASH).map(|_| false)
+            }
+        }
+    }
+
+    /// Generates the dummy hash, so that it is not generated during the first failed sign in.
+    pub fn init_dummy_hash() {
+        Lazy::force(&DUMMY_HASH);
+    }
+}
+
+/// Keyed digests for high-entropy secrets such as refresh tokens.
+///
+/// Unlike passwords these do not need a slow hash, but they are keyed with the `TOKEN_SECRET` so
+/// that a leaked database alone cannot be used to forge or look up tokens.
+pub struct TokenDigest;
+
+impl TokenDigest {
+    /// Computes the HMAC-SHA256 digest of a token.
+    ///
+    /// # Parameters
+    /// The plain-text token to digest.
+    ///
+    /// # Return Values
+    /// The raw digest bytes, these are safe to store in a database.
+    pub fn digest(token: &str) -> Vec<u8> {
+        let mut mac = Self::mac();
+        mac.input(token.as_bytes());
+        mac.result().code().to_vec()
+    }
+
+    /// Verifies a plain-text token against a stored digest, in constant time.
+    ///
+    /// # Parameters
+    /// A plain-text token and an HMAC-SHA256 digest.
+    ///
+    /// # Return Values
+    /// `true` if the token matched the digest, `false` otherwise.
+    pub fn verify(token: &str, digest: &[u8]) -> bool {
+        let mut mac = Self::mac();
+        mac.input(token.as_bytes());
+        mac.verify(digest).is_ok()
+    }
+
+    fn mac() -> HmacSha256 {
+        let secret = dotenv::var("TOKEN_SECRET").expect("TOKEN_SECRET must be set");
+        HmacSha256::new_varkey(secret.as_bytes()).expect("HMAC accepts keys of any length")
+    }
 }
 
 #[cfg(test)]
@@ -61,4 +148,20 @@ mod test {
 
         assert!(is_match);
     }
+
+    #[test]
+    fn test_missing_hash_never_verifies() {
+        assert!(!Argon2id::verify_password_or_dummy("P@ssw0rd", None)
+            .expect("verify_password_or_dummy returned an error"));
+    }
+
+    #[test]
+    fn test_token_digest_verifies_only_the_original_token() {
+        std::env::set_var("TOKEN_SECRET", "test-secret");
+
+        let digest = TokenDigest::digest("token");
+
+        assert!(TokenDigest::verify("token", &digest));
+        assert!(!TokenDigest::verify("other-token", &digest));
+    }
 }
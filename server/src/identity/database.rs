@@ -1,6 +1,7 @@
 use super::model::{Identity, IdentityCreate, IdentityRepository};
 
 use crate::account::model::AccountId;
+use crate::database::postgres::QueryResultExt;
 use crate::error::AuthError;
 
 use async_trait::async_trait;
@@ -12,19 +13,21 @@ impl IdentityRepository for PgConnection {
         &mut self,
         identity_create: &IdentityCreate,
     ) -> Result<Identity, AuthError> {
-        // Unchecked for now as query macros do not appear to support custom enum types.
-        let identity = sqlx::query_as_unchecked!(
+        let identity = sqlx::query_as!(
             Identity,
             r#"
-            INSERT INTO identities(account_id, source)
-            VALUES($1, $2)
+            INSERT INTO identities(account_id, provider, subject)
+            VALUES($1, $2, $3)
             RETURNING *
             "#,
             identity_create.account_id,
-            identity_create.source,
+            identity_create.provider,
+            identity_create.subject,
         )
         .fetch_one(self)
-        .await?;
+        .await
+        .on_unique_violation(|| AuthError::IdentityAlreadyLinked(identity_create.provider.clone()))
+        .on_foreign_key_violation(|| AuthError::AccountNotFound(identity_create.account_id))?;
 
         Ok(identity)
     }
@@ -33,8 +36,7 @@ impl IdentityRepository for PgConnection {
         &mut self,
         account_id: AccountId,
     ) -> Result<Vec<Identity>, AuthError> {
-        // Unchecked for now as query macros do not appear to support custom enum types.
-        Ok(sqlx::query_as_unchecked!(
+        Ok(sqlx::query_as!(
             Identity,
             r#"
             SELECT * FROM identities WHERE account_id = $1
@@ -44,4 +46,126 @@ impl IdentityRepository for PgConnection {
         .fetch_all(self)
         .await?)
     }
+
+    async fn find_identity(
+        &mut self,
+        provider: &str,
+        subject: &str,
+    ) -> Result<Option<Identity>, AuthError> {
+        Ok(sqlx::query_as!(
+            Identity,
+            r#"
+            SELECT * FROM identities WHERE provider = $1 AND subject = $2
+            "#,
+            provider,
+            subject
+        )
+        .fetch_optional(self)
+        .await?)
+    }
+
+    async fn remove_identity(
+        &mut self,
+        account_id: AccountId,
+        provider: &str,
+    ) -> Result<(), AuthError> {
+        let removed = sqlx::query!(
+            r#"
+            DELETE FROM identities WHERE account_id = $1 AND provider = $2
+            "#,
+            account_id,
+            provider
+        )
+        .execute(self)
+        .await?;
+
+        if removed == 0 {
+            return Err(AuthError::IdentityNotFound(provider.to_string()));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::database::postgres::{register_test_account, test_transaction};
+    use crate::identity::model::PASSWORD_PROVIDER;
+
+    async fn register(conn: &mut PgConnection) -> AccountId {
+        register_test_account(conn, None).await.id
+    }
+
+    fn identity(account_id: AccountId, provider: &str, subject: Option<&str>) -> IdentityCreate {
+        IdentityCreate {
+            account_id,
+            provider: provider.to_string(),
+            subject: subject.map(str::to_string),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_add_find_and_remove_identities() {
+        let mut tx = test_transaction().await;
+        let account_id = register(&mut tx).await;
+
+        let password = tx
+            .add_identity(&identity(account_id, PASSWORD_PROVIDER, None))
+            .await
+            .unwrap();
+        let google = tx
+            .add_identity(&identity(account_id, "google", Some("1234")))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            tx.find_identity("google", "1234").await.unwrap(),
+            Some(google)
+        );
+        assert_eq!(tx.find_identity("google", "5678").await.unwrap(), None);
+
+        tx.remove_identity(account_id, "google").await.unwrap();
+        assert_eq!(
+            tx.get_identities_for_account(account_id).await.unwrap(),
+            vec![password]
+        );
+        assert!(matches!(
+            tx.remove_identity(account_id, "google").await,
+            Err(AuthError::IdentityNotFound(provider)) if provider == "google"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_second_identity_from_a_provider_is_already_linked() {
+        let mut tx = test_transaction().await;
+        let account_id = register(&mut tx).await;
+        tx.add_identity(&identity(account_id, "google", Some("1234")))
+            .await
+            .unwrap();
+
+        let result = tx
+            .add_identity(&identity(account_id, "google", Some("5678")))
+            .await;
+        assert!(matches!(
+            result,
+            Err(AuthError::IdentityAlreadyLinked(provider)) if provider == "google"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_subject_linked_to_another_account_is_already_linked() {
+        let mut tx = test_transaction().await;
+        let account_id = register(&mut tx).await;
+        let other_account_id = register(&mut tx).await;
+        tx.add_identity(&identity(account_id, "google", Some("1234")))
+            .await
+            .unwrap();
+
+        let result = tx
+            .add_identity(&identity(other_account_id, "google", Some("1234")))
+            .await;
+        assert!(matches!(result, Err(AuthError::IdentityAlreadyLinked(_))));
+    }
 }
@@ -3,35 +3,35 @@ use crate::account::model::AccountId;
 use crate::error::AuthError;
 
 use async_trait::async_trait;
-use num_derive::{FromPrimitive, ToPrimitive};
 
 /// Define a custom type for Identity IDs.
 pub type IdentityId = i32;
 
-/// Defines the supported identity sources.
-#[derive(Debug, PartialEq, FromPrimitive, ToPrimitive, sqlx::Type)]
-#[sqlx(rename = "identitysource")]
-#[sqlx(rename_all = "lowercase")]
-pub enum IdentitySource {
-    Password,
-    Google,
-}
+/// Define the provider name of password identities.
+///
+/// Every other provider is an OpenID Connect provider from the `oidc::provider` registry.
+pub const PASSWORD_PROVIDER: &str = "password";
+
 #[derive(Debug, PartialEq)]
 pub struct Identity {
     pub id: IdentityId,
     pub account_id: AccountId,
-    pub source: IdentitySource,
+    /// The name of the identity provider.
+    pub provider: String,
+    /// The identity provider's unique identifier for the user, unset for password identities.
+    pub subject: Option<String>,
 }
 
 #[derive(Debug, PartialEq)]
 pub struct IdentityCreate {
     pub account_id: AccountId,
-    pub source: IdentitySource,
+    pub provider: String,
+    pub subject: Option<String>,
 }
 
 /// Defines repository based data options for the Identity data type.
 #[async_trait]
-pub(super) trait IdentityRepository {
+pub(crate) trait IdentityRepository {
     /// Adds a new identity for an existing account.
     ///
     /// # Parameters
@@ -42,7 +42,8 @@ pub(super) trait IdentityRepository {
     /// The newly created Identity struct.
     ///
     /// ## Errors
-    /// If the account was not found, or a database failure occured.
+    /// If the account was not found, the account already has an identity from the provider, the
+    /// provider's subject is linked to another account, or a database failure occured.
     async fn add_identity(
         &mut self,
         identity_create: &IdentityCreate,
@@ -63,4 +64,38 @@ pub(super) trait IdentityRepository {
         &mut self,
         account_id: AccountId,
     ) -> Result<Vec<Identity>, AuthError>;
+
+    /// Finds the identity a provider's user has signed in with before.
+    ///
+    /// # Parameters
+    /// The name of the identity provider, and the provider's unique identifier for the user.
+    ///
+    /// # Return Values
+    /// ## Success
+    /// The matching identity, if the user has signed in with the provider before.
+    ///
+    /// ## Errors
+    /// If a database failure occured.
+    async fn find_identity(
+        &mut self,
+        provider: &str,
+        subject: &str,
+    ) -> Result<Option<Identity>, AuthError>;
+
+    /// Removes an identity from an account.
+    ///
+    /// # Parameters
+    /// The account ID, and the name of the identity provider to remove.
+    ///
+    /// # Return Values
+    /// ## Success
+    /// Nothing, once the identity has been removed.
+    ///
+    /// ## Errors
+    /// If the account has no identity for the provider, or a database failure occured.
+    async fn remove_identity(
+        &mut self,
+        account_id: AccountId,
+        provider: &str,
+    ) -> Result<(), AuthError>;
 }
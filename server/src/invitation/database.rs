@@ -0,0 +1,193 @@
+use super::model::{
+    Invitation, InvitationCreate, InvitationId, InvitationRepository, IssuedInvitation,
+};
+
+use crate::database::postgres::QueryResultExt;
+use crate::error::AuthError;
+use crate::hashing::TokenDigest;
+
+use async_trait::async_trait;
+use chrono::Utc;
+use sqlx::PgConnection;
+
+#[async_trait]
+impl InvitationRepository for PgConnection {
+    async fn create_invitation(
+        &mut self,
+        invitation_create: InvitationCreate,
+    ) -> Result<IssuedInvitation, AuthError> {
+        let invitation = sqlx::query_as!(
+            Invitation,
+            r#"
+            INSERT INTO invitations (code_hash, inviter_id, email, max_uses, expires, created_at)
+            VALUES($1, $2, $3, $4, $5, $6)
+            RETURNING *
+            "#,
+            invitation_create.code_hash,
+            invitation_create.inviter_id,
+            invitation_create.email,
+            invitation_create.max_uses,
+            invitation_create.expires,
+            invitation_create.created_at,
+        )
+        .fetch_one(self)
+        .await
+        .on_foreign_key_violation(|| {
+            AuthError::AccountNotFound(invitation_create.inviter_id.unwrap_or_default())
+        })?;
+
+        Ok(IssuedInvitation {
+            invitation,
+            code: invitation_create.code,
+        })
+    }
+
+    async fn list_invitations(&mut self) -> Result<Vec<Invitation>, AuthError> {
+        Ok(sqlx::query_as!(
+            Invitation,
+            r#"
+            SELECT * FROM invitations ORDER BY created_at DESC
+            "#
+        )
+        .fetch_all(self)
+        .await?)
+    }
+
+    async fn revoke_invitation(&mut self, invitation_id: InvitationId) -> Result<(), AuthError> {
+        let revoked = sqlx::query!(
+            r#"
+            UPDATE invitations SET revoked_at = $2
+            WHERE id = $1 AND revoked_at IS NULL
+            "#,
+            invitation_id,
+            Utc::now().naive_utc(),
+        )
+        .execute(self)
+        .await?;
+
+        if revoked == 0 {
+            return Err(AuthError::InvitationNotFound(invitation_id));
+        }
+
+        Ok(())
+    }
+
+    async fn use_invitation(&mut self, code: &str, email: &str) -> Result<Invitation, AuthError> {
+        // Check and count the use in one statement, so concurrent registrations cannot use the
+        // invitation more than max_uses times.
+        sqlx::query_as!(
+            Invitation,
+            r#"
+            UPDATE invitations SET uses = uses + 1
+            WHERE code_hash = $1
+                AND revoked_at IS NULL
+                AND uses < max_uses
+                AND (expires IS NULL OR expires > $2)
+                AND (email IS NULL OR lower(email) = lower($3))
+            RETURNING *
+            "#,
+            TokenDigest::digest(code),
+            Utc::now().naive_utc(),
+            email,
+        )
+        .fetch_optional(self)
+        .await?
+        .ok_or(AuthError::InvalidInvitation)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::database::postgres::{register_test_account, test_transaction};
+
+    use chrono::Duration;
+
+    #[tokio::test]
+    async fn test_invitation_is_limited_to_its_email_and_uses() {
+        let mut tx = test_transaction().await;
+        let inviter = register_test_account(&mut tx, None).await;
+
+        let issued = tx
+            .create_invitation(InvitationCreate::new(
+                Some(inviter.id),
+                Some("invitee@example.com".to_string()),
+                1,
+                None,
+            ))
+            .await
+            .unwrap();
+        assert!(tx
+            .list_invitations()
+            .await
+            .unwrap()
+            .iter()
+            .any(|invitation| invitation.id == issued.invitation.id));
+
+        assert!(matches!(
+            tx.use_invitation(&issued.code, "other@example.com").await,
+            Err(AuthError::InvalidInvitation)
+        ));
+        let used = tx
+            .use_invitation(&issued.code, "Invitee@Example.com")
+            .await
+            .unwrap();
+        assert_eq!(used.uses, 1);
+        assert!(matches!(
+            tx.use_invitation(&issued.code, "invitee@example.com").await,
+            Err(AuthError::InvalidInvitation)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_revoked_invitation_cannot_be_used() {
+        let mut tx = test_transaction().await;
+        let issued = tx
+            .create_invitation(InvitationCreate::new(None, None, 5, None))
+            .await
+            .unwrap();
+
+        tx.revoke_invitation(issued.invitation.id).await.unwrap();
+        assert!(matches!(
+            tx.use_invitation(&issued.code, "invitee@example.com").await,
+            Err(AuthError::InvalidInvitation)
+        ));
+        assert!(matches!(
+            tx.revoke_invitation(issued.invitation.id).await,
+            Err(AuthError::InvitationNotFound(id)) if id == issued.invitation.id
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_invitation_can_be_used_until_its_uses_run_out() {
+        let mut tx = test_transaction().await;
+        let issued = tx
+            .create_invitation(InvitationCreate::new(None, None, 2, None))
+            .await
+            .unwrap();
+
+        for email in &["first@example.com", "second@example.com"] {
+            tx.use_invitation(&issued.code, email).await.unwrap();
+        }
+        assert!(matches!(
+            tx.use_invitation(&issued.code, "third@example.com").await,
+            Err(AuthError::InvalidInvitation)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_expired_invitation_cannot_be_used() {
+        let mut tx = test_transaction().await;
+        let expired = Utc::now().naive_utc() - Duration::minutes(1);
+        let issued = tx
+            .create_invitation(InvitationCreate::new(None, None, 1, Some(expired)))
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            tx.use_invitation(&issued.code, "invitee@example.com").await,
+            Err(AuthError::InvalidInvitation)
+        ));
+    }
+}
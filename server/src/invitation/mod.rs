@@ -0,0 +1,8 @@
+/// Invitations allow new users to register while registration is invite-only.
+///
+/// Each invitation has a code that is given to the invited users, which can be restricted to one
+/// email address, limited to a number of uses, and set to expire. Only a keyed digest of the code
+/// is stored.
+///
+pub mod database;
+pub mod model;
@@ -0,0 +1,136 @@
+/// Data models for Invitations.
+use crate::account::model::AccountId;
+use crate::error::AuthError;
+use crate::hashing::TokenDigest;
+
+use async_trait::async_trait;
+use chrono::naive::NaiveDateTime;
+use chrono::Utc;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+
+/// Define a custom type for Invitation IDs.
+pub type InvitationId = i32;
+
+/// Define the number of characters an invitation code should contain.
+const CODE_LENGTH: usize = 32;
+
+#[derive(Debug)]
+pub struct Invitation {
+    pub id: InvitationId,
+    pub code_hash: Vec<u8>,
+    /// The account that sent the invitation, if any.
+    pub inviter_id: Option<AccountId>,
+    /// The only email address that can use the invitation, if any.
+    pub email: Option<String>,
+    pub max_uses: i32,
+    pub uses: i32,
+    pub expires: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+/// A newly created invitation, along with its plain-text code.
+///
+/// Only a digest of the code is stored, so this is the only time the code is available.
+#[derive(Debug)]
+pub struct IssuedInvitation {
+    pub invitation: Invitation,
+    pub code: String,
+}
+
+#[derive(Debug)]
+pub struct InvitationCreate {
+    pub inviter_id: Option<AccountId>,
+    pub email: Option<String>,
+    pub max_uses: i32,
+    pub expires: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+    pub code: String,
+    pub code_hash: Vec<u8>,
+}
+
+impl InvitationCreate {
+    /// Generates a new invitation code.
+    ///
+    /// # Parameters
+    /// The inviting account, the email address the invitation is restricted to, the number of
+    /// times it can be used, and when it expires.
+    pub fn new(
+        inviter_id: Option<AccountId>,
+        email: Option<String>,
+        max_uses: i32,
+        expires: Option<NaiveDateTime>,
+    ) -> Self {
+        let code: String = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(CODE_LENGTH)
+            .collect();
+
+        InvitationCreate {
+            inviter_id,
+            email,
+            max_uses,
+            expires,
+            created_at: Utc::now().naive_utc(),
+            code_hash: TokenDigest::digest(&code),
+            code,
+        }
+    }
+}
+
+#[async_trait]
+pub(crate) trait InvitationRepository {
+    /// Creates a new invitation.
+    ///
+    /// # Parameters
+    /// The fields required to create a new invitation.
+    ///
+    /// # Returns
+    /// ## Success
+    /// The newly created Invitation structure, along with its plain-text code.
+    ///
+    /// ## Errors
+    /// If the inviting account was not found, or a database failure occured.
+    async fn create_invitation(
+        &mut self,
+        invitation_create: InvitationCreate,
+    ) -> Result<IssuedInvitation, AuthError>;
+
+    /// Lists every invitation, newest first.
+    ///
+    /// # Returns
+    /// ## Success
+    /// A vector containing all invitations, including used, expired and revoked invitations.
+    ///
+    /// ## Errors
+    /// If a database failure occured.
+    async fn list_invitations(&mut self) -> Result<Vec<Invitation>, AuthError>;
+
+    /// Revokes an invitation, so that it can no longer be used.
+    ///
+    /// # Parameters
+    /// The ID of the invitation to revoke.
+    ///
+    /// # Returns
+    /// ## Success
+    /// Ok, but empty.
+    ///
+    /// ## Errors
+    /// If the invitation was not found, or a database failure occured.
+    async fn revoke_invitation(&mut self, invitation_id: InvitationId) -> Result<(), AuthError>;
+
+    /// Uses an invitation to register a new account.
+    ///
+    /// # Parameters
+    /// The invitation code presented by the user, and the email address they are registering.
+    ///
+    /// # Returns
+    /// ## Success
+    /// The Invitation structure, with its use counted.
+    ///
+    /// ## Errors
+    /// If the code was not found, or the invitation has been revoked, has expired, has been used
+    /// up, or is for another email address. Or if a database failure occured.
+    async fn use_invitation(&mut self, code: &str, email: &str) -> Result<Invitation, AuthError>;
+}
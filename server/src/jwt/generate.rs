@@ -1,44 +1,46 @@
 /// Generates Json Web Tokens.
-use super::model::Claims;
+use super::model::{Claims, JWT_ISSUER};
 
-use crate::account::model::AccountId;
+use crate::account::model::Account;
 use crate::error::AuthError;
+use crate::keys::store::KeyStore;
 
-use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::{decode, decode_header, encode, Header, Validation};
 
-/// Creates a new JWT for the provided username.
+/// Creates a new JWT for the provided account.
 ///
-/// This will expire in 1 day from now.
+/// This will expire in 1 hour from now.
 ///
-/// NOTE: This currently usees HS256 which needs a shared secret. It would be better to use
-/// RS256, publishing the public key in like Google does https://www.googleapis.com/oauth2/v3/certs.
-/// This will require a certificate authority for the microserivces, and will be done at a later
-/// stage.
+/// The token is signed with the key store's active key, and its key ID is set in the `kid` header
+/// so that verifiers can find the matching public key in the JWK Set.
 ///
-pub(crate) fn create_token(account_id: AccountId, email: &str) -> Result<String, AuthError> {
-    let secret = dotenv::var("JWT_SECRET").expect("JWT_SECRET must be set");
+pub(crate) fn create_token(keys: &KeyStore, account: &Account) -> Result<String, AuthError> {
+    let key = keys.active_key()?;
+    let claims: Claims = Claims::new(account);
 
-    let claims: Claims = Claims::new(account_id, &email);
+    let mut header = Header::new(key.algorithm.jwt_algorithm());
+    header.kid = Some(key.kid.clone());
 
-    encode(
-        &Header::new(Algorithm::RS256),
-        &claims,
-        &EncodingKey::from_secret(secret.as_ref()),
-    )
-    .map_err(AuthError::InvalidToken)
+    encode(&header, &claims, key.encoding_key()).map_err(AuthError::InvalidToken)
 }
 
-/// Validates a given JWT, ensuring it is valid and stll signed.
+/// Validates a given JWT, ensuring it is valid, stll signed, and was issued by this service.
 ///
 /// Returns the JWT's claims.
-pub(crate) fn validate_token(token: &str) -> Result<Claims, AuthError> {
-    let secret = dotenv::var("JWT_SECRET").expect("JWT_SECRET must be set");
-
-    decode::<Claims>(
-        token,
-        &DecodingKey::from_secret(secret.as_ref()),
-        &Validation::new(Algorithm::RS256),
-    )
-    .map(|data| data.claims)
-    .map_err(AuthError::InvalidToken)
+pub(crate) fn validate_token(keys: &KeyStore, token: &str) -> Result<Claims, AuthError> {
+    let header = decode_header(token).map_err(AuthError::InvalidToken)?;
+    let key = header
+        .kid
+        .as_ref()
+        .and_then(|kid| keys.find(kid))
+        // Tokens signed by an unknown key can never have a valid signature.
+        .ok_or_else(|| AuthError::InvalidToken(ErrorKind::InvalidSignature.into()))?;
+
+    let mut validation = Validation::new(key.algorithm.jwt_algorithm());
+    validation.iss = Some(JWT_ISSUER.to_string());
+
+    decode::<Claims>(token, key.decoding_key(), &validation)
+        .map(|data| data.claims)
+        .map_err(AuthError::InvalidToken)
 }
@@ -1,11 +1,18 @@
 /// Defines JWT models.
-use crate::account::model::AccountId;
+use crate::account::model::{Account, AccountId};
+use crate::error::AuthError;
 
 use chrono::{Duration, Utc};
+use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 
-const JWT_ISSUER: &str = "authentication";
-const JWT_EXPIRY_HOURS: i64 = 1;
+/// The issuer of every JWT, which is the service's `PUBLIC_URL`, so that verifiers can discover
+/// its keys from the issuer.
+pub(crate) static JWT_ISSUER: Lazy<String> = Lazy::new(|| {
+    let public_url = dotenv::var("PUBLIC_URL").expect("PUBLIC_URL must be set");
+    public_url.trim_end_matches('/').to_string()
+});
+pub(crate) const JWT_EXPIRY_HOURS: i64 = 1;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Claims {
@@ -19,19 +26,30 @@ pub struct Claims {
     pub exp: i64,
     // user email
     pub email: String,
+    // whether the user has proved they own the email address
+    #[serde(default)]
+    pub email_verified: bool,
 }
 
 impl Claims {
-    pub fn new(account_id: AccountId, email: &str) -> Self {
+    pub fn new(account: &Account) -> Self {
         let iat = Utc::now();
         let exp = iat + Duration::hours(JWT_EXPIRY_HOURS);
 
         Claims {
             iss: JWT_ISSUER.to_string(),
-            sub: account_id.to_string(),
+            sub: account.id.to_string(),
             iat: iat.timestamp(),
             exp: exp.timestamp(),
-            email: email.to_string(),
+            email: account.email.clone(),
+            email_verified: account.email_verified_at.is_some(),
         }
     }
+
+    /// Gets the ID of the account the claims were issued for.
+    pub fn account_id(&self) -> Result<AccountId, AuthError> {
+        self.sub
+            .parse()
+            .map_err(|_| AuthError::InvalidRequest(format!("invalid subject {}", self.sub)))
+    }
 }
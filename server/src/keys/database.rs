@@ -0,0 +1,160 @@
+use super::model::{SigningKeyCreate, SigningKeyMetadata, SigningKeyRepository};
+
+use crate::error::AuthError;
+
+use async_trait::async_trait;
+use chrono::naive::NaiveDateTime;
+use sqlx::PgConnection;
+
+#[async_trait]
+impl SigningKeyRepository for PgConnection {
+    async fn lock_signing_keys(&mut self) -> Result<(), AuthError> {
+        sqlx::query!(
+            r#"
+            LOCK TABLE signing_keys IN SHARE ROW EXCLUSIVE MODE
+            "#
+        )
+        .execute(self)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_published_signing_keys(&mut self) -> Result<Vec<SigningKeyMetadata>, AuthError> {
+        let now = chrono::Utc::now().naive_utc();
+        Ok(sqlx::query_as!(
+            SigningKeyMetadata,
+            r#"
+            SELECT * FROM signing_keys
+            WHERE retires_at IS NULL OR retires_at > $1
+            ORDER BY activates_at
+            "#,
+            now
+        )
+        .fetch_all(self)
+        .await?)
+    }
+
+    async fn add_signing_key(
+        &mut self,
+        key_create: &SigningKeyCreate,
+    ) -> Result<SigningKeyMetadata, AuthError> {
+        Ok(sqlx::query_as!(
+            SigningKeyMetadata,
+            r#"
+            INSERT INTO signing_keys (kid, algorithm, activates_at)
+            VALUES ($1, $2, $3)
+            RETURNING *
+            "#,
+            key_create.kid,
+            key_create.algorithm.to_string(),
+            key_create.activates_at,
+        )
+        .fetch_one(self)
+        .await?)
+    }
+
+    async fn retire_signing_keys_except(
+        &mut self,
+        kid: &str,
+        retires_at: NaiveDateTime,
+    ) -> Result<(), AuthError> {
+        sqlx::query!(
+            r#"
+            UPDATE signing_keys SET retires_at = $1
+            WHERE kid <> $2 AND (retires_at IS NULL OR retires_at > $1)
+            "#,
+            retires_at,
+            kid,
+        )
+        .execute(self)
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::database::postgres::test_transaction;
+    use crate::keys::model::KeyAlgorithm;
+
+    use chrono::{Duration, Utc};
+    use uuid::Uuid;
+
+    /// Gets the current time in whole seconds, which the database stores exactly.
+    fn now() -> NaiveDateTime {
+        NaiveDateTime::from_timestamp(Utc::now().timestamp(), 0)
+    }
+
+    /// Records a key with a new kid, activating at the given time.
+    async fn add_key(conn: &mut PgConnection, activates_at: NaiveDateTime) -> SigningKeyMetadata {
+        conn.add_signing_key(&SigningKeyCreate {
+            kid: Uuid::new_v4().to_simple().to_string(),
+            algorithm: KeyAlgorithm::ES256,
+            activates_at,
+        })
+        .await
+        .expect("add_signing_key returned an error")
+    }
+
+    #[tokio::test]
+    async fn test_published_keys_are_ordered_by_activation() {
+        let mut tx = test_transaction().await;
+        let now = now();
+        // Retire any keys already in the test database, so that only these keys are published.
+        tx.retire_signing_keys_except("", now).await.unwrap();
+
+        let retired = add_key(&mut tx, now - Duration::hours(2)).await;
+        let active = add_key(&mut tx, now - Duration::hours(1)).await;
+        tx.retire_signing_keys_except(&active.kid, now - Duration::minutes(1))
+            .await
+            .unwrap();
+        let next = add_key(&mut tx, now + Duration::hours(1)).await;
+        assert_eq!(next.algorithm, "ES256");
+        assert_eq!(next.retires_at, None);
+
+        let published = tx.get_published_signing_keys().await.unwrap();
+        let kids: Vec<_> = published.iter().map(|key| key.kid.as_str()).collect();
+        assert_eq!(kids, vec![active.kid.as_str(), next.kid.as_str()]);
+        assert!(!kids.contains(&retired.kid.as_str()));
+    }
+
+    #[tokio::test]
+    async fn test_retiring_never_postpones_a_retirement() {
+        let mut tx = test_transaction().await;
+        let now = now();
+        let previous = add_key(&mut tx, now - Duration::hours(1)).await;
+        let kept = add_key(&mut tx, now).await;
+
+        tx.retire_signing_keys_except(&kept.kid, now + Duration::hours(2))
+            .await
+            .unwrap();
+        tx.retire_signing_keys_except(&kept.kid, now + Duration::hours(3))
+            .await
+            .unwrap();
+        let retires_at = |keys: &[SigningKeyMetadata], kid: &str| {
+            keys.iter()
+                .find(|key| key.kid == kid)
+                .map(|key| key.retires_at)
+        };
+        let published = tx.get_published_signing_keys().await.unwrap();
+        assert_eq!(
+            retires_at(&published, &previous.kid),
+            Some(Some(now + Duration::hours(2)))
+        );
+        assert_eq!(retires_at(&published, &kept.kid), Some(None));
+
+        // An earlier retirement, e.g. after a compromise, still brings it forward.
+        tx.retire_signing_keys_except(&kept.kid, now + Duration::hours(1))
+            .await
+            .unwrap();
+        let published = tx.get_published_signing_keys().await.unwrap();
+        assert_eq!(
+            retires_at(&published, &previous.kid),
+            Some(Some(now + Duration::hours(1)))
+        );
+    }
+}
@@ -0,0 +1,348 @@
+/// Manages the lifecycle of signing keys, rotating them on a schedule.
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Instant;
+
+use super::model::{KeyAlgorithm, SigningKey, SigningKeyCreate, SigningKeyRepository};
+use super::store::{self, KeyStore};
+
+use crate::database::Db;
+use crate::error::AuthError;
+use crate::jwt::model::JWT_EXPIRY_HOURS;
+
+use chrono::naive::NaiveDateTime;
+use chrono::{Duration, Utc};
+use sqlx::{PgConnection, PgPool};
+use tokio::sync::{RwLock, RwLockReadGuard};
+use uuid::Uuid;
+
+/// Define how often to check whether the signing keys are due to be rotated, in seconds.
+const ROTATION_CHECK_INTERVAL_SECS: u64 = 5 * 60;
+
+/// Define the minimum time between reloads of the keys for an unknown key ID, in seconds.
+///
+/// This stops tokens with made up key IDs from being used to flood the database with queries.
+const UNKNOWN_KEY_MIN_RELOAD_SECS: u64 = 10;
+
+/// Rotates signing keys, and holds the currently published keys.
+///
+/// Each rotation generates a new key that is published `publish_ahead` before it starts signing
+/// tokens, giving verifiers time to fetch it. The previous keys stay published for
+/// `JWT_EXPIRY_HOURS` after the new key activates, so every token they signed can still be
+/// verified.
+///
+/// Private keys are saved to `dir`, which must be shared by every instance of the service.
+pub struct KeyManager {
+    pool: PgPool,
+    dir: PathBuf,
+    algorithm: KeyAlgorithm,
+    rotation_interval: Duration,
+    publish_ahead: Duration,
+    store: RwLock<KeyStore>,
+    reloaded_at: RwLock<Instant>,
+}
+
+impl KeyManager {
+    /// Creates a new KeyManager, loading the published keys and generating one if there are none.
+    pub async fn new(
+        pool: PgPool,
+        dir: PathBuf,
+        algorithm: KeyAlgorithm,
+        rotation_interval: Duration,
+        publish_ahead: Duration,
+    ) -> Result<Self, AuthError> {
+        let metadata = pool.acquire().await?.get_published_signing_keys().await?;
+        let manager = Self {
+            store: RwLock::new(KeyStore::load(&dir, metadata)?),
+            reloaded_at: RwLock::new(Instant::now()),
+            pool,
+            dir,
+            algorithm,
+            rotation_interval,
+            publish_ahead,
+        };
+
+        manager.rotate_if_due().await?;
+
+        Ok(manager)
+    }
+
+    /// Gets the currently published keys.
+    pub async fn store(&self) -> RwLockReadGuard<'_, KeyStore> {
+        self.store.read().await
+    }
+
+    /// Generates the next key if the newest key is due to be replaced, then reloads the keys.
+    ///
+    /// The next key is generated `publish_ahead` before the newest key has been active for
+    /// `rotation_interval`.
+    pub async fn rotate_if_due(&self) -> Result<(), AuthError> {
+        let mut tx = self.pool.transaction().await?;
+        self.add_key_if_due(&mut tx, Utc::now().naive_utc()).await?;
+        tx.commit().await?;
+
+        self.reload().await
+    }
+
+    /// Generates the next key if the newest key is due to be replaced at the given time, see
+    /// `rotate_if_due`.
+    async fn add_key_if_due(
+        &self,
+        conn: &mut PgConnection,
+        now: NaiveDateTime,
+    ) -> Result<(), AuthError> {
+        conn.lock_signing_keys().await?;
+
+        let newest = conn.get_published_signing_keys().await?.pop();
+        let activates_at = match newest {
+            None => Some(now),
+            Some(newest)
+                if newest.activates_at + self.rotation_interval - self.publish_ahead <= now =>
+            {
+                Some(std::cmp::max(
+                    newest.activates_at + self.rotation_interval,
+                    now + self.publish_ahead,
+                ))
+            }
+            Some(_) => None,
+        };
+
+        match activates_at {
+            Some(activates_at) => {
+                self.add_key(conn, activates_at, activates_at + jwt_lifetime())
+                    .await
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Immediately replaces the active key with a new key, e.g. if it may have been compromised.
+    ///
+    /// # Parameters
+    /// If `retire_previous` is set, the previous keys stop being published immediately, which
+    /// invalidates every token they signed. Otherwise they remain published until those tokens
+    /// expire.
+    pub async fn rotate_now(&self, retire_previous: bool) -> Result<(), AuthError> {
+        let mut tx = self.pool.transaction().await?;
+        self.replace_active_key(&mut tx, Utc::now().naive_utc(), retire_previous)
+            .await?;
+        tx.commit().await?;
+
+        self.reload().await
+    }
+
+    /// Generates a key that is active from the given time, see `rotate_now`.
+    async fn replace_active_key(
+        &self,
+        conn: &mut PgConnection,
+        now: NaiveDateTime,
+        retire_previous: bool,
+    ) -> Result<(), AuthError> {
+        let retires_at = if retire_previous {
+            now
+        } else {
+            now + jwt_lifetime()
+        };
+
+        conn.lock_signing_keys().await?;
+        self.add_key(conn, now, retires_at).await
+    }
+
+    /// Generates and records a new key, scheduling the other keys to retire.
+    async fn add_key(
+        &self,
+        conn: &mut PgConnection,
+        activates_at: NaiveDateTime,
+        previous_retire_at: NaiveDateTime,
+    ) -> Result<(), AuthError> {
+        let kid = Uuid::new_v4().to_simple().to_string();
+        let (_, pem) = SigningKey::generate(&kid, self.algorithm)?;
+        store::save_key(&self.dir, &kid, &pem)?;
+
+        let recorded = conn
+            .add_signing_key(&SigningKeyCreate {
+                kid: kid.clone(),
+                algorithm: self.algorithm,
+                activates_at,
+            })
+            .await;
+        if let Err(e) = recorded {
+            // The key was never published, so it is safe to discard.
+            store::remove_key(&self.dir, &kid).ok();
+            return Err(e);
+        }
+
+        conn.retire_signing_keys_except(&kid, previous_retire_at)
+            .await
+    }
+
+    /// Loads a key by its key ID, including keys that are no longer published, for verifying
+    /// what it signed long ago.
+    pub fn load_key(&self, kid: &str) -> Result<SigningKey, AuthError> {
+        store::load_key(&self.dir, kid)
+    }
+
+    /// Reloads the published keys if none has the given key ID, e.g. if another instance of the
+    /// service has just replaced the active key with `rotate_now`.
+    ///
+    /// The keys are reloaded at most once every `UNKNOWN_KEY_MIN_RELOAD_SECS`.
+    pub async fn reload_if_unknown(&self, kid: &str) -> Result<(), AuthError> {
+        let min_reload_interval = std::time::Duration::from_secs(UNKNOWN_KEY_MIN_RELOAD_SECS);
+        if self.store().await.find(kid).is_some()
+            || self.reloaded_at.read().await.elapsed() < min_reload_interval
+        {
+            return Ok(());
+        }
+
+        self.reload().await
+    }
+
+    /// Reloads the published keys, including any generated by other instances of the service.
+    pub async fn reload(&self) -> Result<(), AuthError> {
+        let metadata = self
+            .pool
+            .acquire()
+            .await?
+            .get_published_signing_keys()
+            .await?;
+        let key_store = KeyStore::load(&self.dir, metadata)?;
+
+        *self.store.write().await = key_store;
+        *self.reloaded_at.write().await = Instant::now();
+        Ok(())
+    }
+}
+
+/// Periodically rotates the signing keys when they are due.
+///
+/// This never returns, so it should be spawned as a separate task.
+pub async fn run_rotation(manager: Arc<KeyManager>) {
+    let mut interval =
+        tokio::time::interval(std::time::Duration::from_secs(ROTATION_CHECK_INTERVAL_SECS));
+
+    loop {
+        interval.tick().await;
+
+        if let Err(e) = manager.rotate_if_due().await {
+            println!("Failed to rotate signing keys: {:?}", e);
+        }
+    }
+}
+
+/// Gets how long a JWT remains valid for, which is how long a retired key must stay published.
+fn jwt_lifetime() -> Duration {
+    Duration::hours(JWT_EXPIRY_HOURS)
+}
+
+/// Creates a manager that saves keys to a new temporary directory, without loading any keys, for
+/// tests.
+#[cfg(test)]
+pub(crate) async fn test_key_manager() -> KeyManager {
+    let database_url = dotenv::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    let dir = std::env::temp_dir().join(Uuid::new_v4().to_simple().to_string());
+
+    KeyManager {
+        pool: crate::database::postgres::connect(&database_url)
+            .await
+            .expect("failed to connect to the test database"),
+        store: RwLock::new(KeyStore::load(&dir, Vec::new()).unwrap()),
+        reloaded_at: RwLock::new(Instant::now()),
+        dir,
+        algorithm: KeyAlgorithm::ES256,
+        rotation_interval: Duration::hours(30 * 24),
+        publish_ahead: Duration::hours(24),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::database::postgres::test_transaction;
+
+    /// Gets the current time in whole seconds, which the database stores exactly.
+    fn now() -> NaiveDateTime {
+        NaiveDateTime::from_timestamp(Utc::now().timestamp(), 0)
+    }
+
+    /// Retires any keys already in the test database, so that the test starts without keys.
+    async fn retire_existing_keys(conn: &mut PgConnection, now: NaiveDateTime) {
+        conn.retire_signing_keys_except("", now)
+            .await
+            .expect("retire_signing_keys_except returned an error");
+    }
+
+    #[tokio::test]
+    async fn test_next_key_is_published_ahead_of_rotation() {
+        let mut tx = test_transaction().await;
+        let manager = test_key_manager().await;
+        let now = now();
+        retire_existing_keys(&mut tx, now).await;
+
+        // The first key is due to be replaced an hour from now.
+        let first_activates_at =
+            now - manager.rotation_interval + manager.publish_ahead + Duration::hours(1);
+        manager
+            .add_key_if_due(&mut tx, first_activates_at)
+            .await
+            .unwrap();
+        let due_at = first_activates_at + manager.rotation_interval - manager.publish_ahead;
+
+        manager
+            .add_key_if_due(&mut tx, due_at - Duration::minutes(1))
+            .await
+            .unwrap();
+        assert_eq!(tx.get_published_signing_keys().await.unwrap().len(), 1);
+
+        manager.add_key_if_due(&mut tx, due_at).await.unwrap();
+        let keys = tx.get_published_signing_keys().await.unwrap();
+        assert_eq!(keys.len(), 2);
+        let (first, next) = (&keys[0], &keys[1]);
+        assert_eq!(next.activates_at, due_at + manager.publish_ahead);
+        assert!(next.is_published(due_at) && !next.is_active(due_at));
+        assert!(first.is_active(due_at));
+        assert_eq!(
+            first.retires_at,
+            Some(next.activates_at + Duration::hours(JWT_EXPIRY_HOURS))
+        );
+
+        std::fs::remove_dir_all(&manager.dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_rotating_now_replaces_the_active_key() {
+        let mut tx = test_transaction().await;
+        let manager = test_key_manager().await;
+        let now = now();
+        retire_existing_keys(&mut tx, now).await;
+        manager
+            .add_key_if_due(&mut tx, now - Duration::hours(1))
+            .await
+            .unwrap();
+
+        manager
+            .replace_active_key(&mut tx, now, false)
+            .await
+            .unwrap();
+        let keys = tx.get_published_signing_keys().await.unwrap();
+        assert_eq!(keys.len(), 2);
+        assert!(keys[1].is_active(now));
+        assert_eq!(
+            keys[0].retires_at,
+            Some(now + Duration::hours(JWT_EXPIRY_HOURS))
+        );
+        let replaced = keys[1].kid.clone();
+
+        // Retiring the previous keys unpublishes them immediately.
+        manager
+            .replace_active_key(&mut tx, now, true)
+            .await
+            .unwrap();
+        let keys = tx.get_published_signing_keys().await.unwrap();
+        assert_eq!(keys.len(), 1);
+        assert_ne!(keys[0].kid, replaced);
+        assert!(keys[0].is_active(now));
+
+        std::fs::remove_dir_all(&manager.dir).ok();
+    }
+}
@@ -0,0 +1,12 @@
+/// Signing keys are the asymmetric private keys used to sign Json Web Tokens.
+///
+/// Only the private keys need to be kept secret. Their public halves are published as a JSON Web
+/// Key Set (RFC 7517), so that other services can verify tokens without sharing a secret.
+///
+/// Keys are rotated on a schedule. A new key is published before it is used to sign tokens, and
+/// old keys remain published until every token they signed has expired.
+///
+pub mod database;
+pub mod manager;
+pub mod model;
+pub mod store;
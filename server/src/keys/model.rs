@@ -0,0 +1,373 @@
+/// Defines signing key models.
+use std::fmt;
+use std::str::FromStr;
+
+use crate::error::AuthError;
+
+use async_trait::async_trait;
+use chrono::naive::NaiveDateTime;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey};
+use openssl::bn::{BigNum, BigNumContext, BigNumRef};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::nid::Nid;
+use openssl::pkey::{Id, PKey, Private};
+use openssl::rsa::Rsa;
+use serde::{Deserialize, Serialize};
+
+/// Define the size of generated RSA keys, in bits.
+const RSA_KEY_BITS: u32 = 2048;
+
+/// Define the size of a P-256 curve coordinate, in bytes.
+const P256_COORDINATE_BYTES: usize = 32;
+
+/// Defines the supported signing algorithms.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyAlgorithm {
+    /// RSASSA-PKCS1-v1_5 using SHA-256.
+    RS256,
+    /// ECDSA using P-256 and SHA-256.
+    ES256,
+}
+
+impl KeyAlgorithm {
+    /// Gets the equivalent jsonwebtoken algorithm.
+    pub fn jwt_algorithm(self) -> Algorithm {
+        match self {
+            KeyAlgorithm::RS256 => Algorithm::RS256,
+            KeyAlgorithm::ES256 => Algorithm::ES256,
+        }
+    }
+}
+
+impl fmt::Display for KeyAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeyAlgorithm::RS256 => write!(f, "RS256"),
+            KeyAlgorithm::ES256 => write!(f, "ES256"),
+        }
+    }
+}
+
+impl FromStr for KeyAlgorithm {
+    type Err = AuthError;
+
+    fn from_str(algorithm: &str) -> Result<Self, Self::Err> {
+        match algorithm {
+            "RS256" => Ok(KeyAlgorithm::RS256),
+            "ES256" => Ok(KeyAlgorithm::ES256),
+            _ => Err(AuthError::KeyError(format!(
+                "unsupported algorithm {}",
+                algorithm
+            ))),
+        }
+    }
+}
+
+/// A JSON Web Key (RFC 7517), describing a public key.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Jwk {
+    pub kty: String,
+    // The following are optional in RFC 7517, but always set for this service's own keys.
+    #[serde(default)]
+    pub kid: String,
+    #[serde(default, rename = "use")]
+    pub key_use: String,
+    #[serde(default)]
+    pub alg: String,
+    // RSA modulus and exponent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub n: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub e: Option<String>,
+    // EC curve and coordinates
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crv: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub x: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub y: Option<String>,
+}
+
+impl Jwk {
+    /// Gets the key for verifying tokens signed with the private half of this key.
+    ///
+    /// Only RSA keys are supported, as these are what third-party identity providers publish.
+    pub fn decoding_key(&self) -> Result<DecodingKey, AuthError> {
+        match (self.kty.as_str(), &self.n, &self.e) {
+            ("RSA", Some(n), Some(e)) => Ok(DecodingKey::from_rsa_components(n, e)),
+            _ => Err(AuthError::KeyError(format!(
+                "unsupported {} key {}",
+                self.kty, self.kid
+            ))),
+        }
+    }
+}
+
+/// A JSON Web Key Set (RFC 7517).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JwkSet {
+    pub keys: Vec<Jwk>,
+}
+
+/// Defines the persisted details of a signing key.
+///
+/// The private key itself is not stored in the database.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SigningKeyMetadata {
+    pub kid: String,
+    pub algorithm: String,
+    pub created_at: NaiveDateTime,
+    pub activates_at: NaiveDateTime,
+    pub retires_at: Option<NaiveDateTime>,
+}
+
+impl SigningKeyMetadata {
+    /// Checks whether the key can still be used to verify tokens at the given time.
+    pub fn is_published(&self, now: NaiveDateTime) -> bool {
+        self.retires_at.map_or(true, |retires_at| retires_at > now)
+    }
+
+    /// Checks whether the key can be used to sign tokens at the given time.
+    pub fn is_active(&self, now: NaiveDateTime) -> bool {
+        self.activates_at <= now && self.is_published(now)
+    }
+}
+
+/// Defines the data required to record a new signing key.
+#[derive(Debug, PartialEq)]
+pub struct SigningKeyCreate {
+    pub kid: String,
+    pub algorithm: KeyAlgorithm,
+    pub activates_at: NaiveDateTime,
+}
+
+/// A private key used to sign JWTs, along with its public key.
+pub struct SigningKey {
+    pub kid: String,
+    pub algorithm: KeyAlgorithm,
+    pub jwk: Jwk,
+    encoding_key: EncodingKey,
+    decoding_key: DecodingKey<'static>,
+}
+
+impl SigningKey {
+    /// Generates a new private key.
+    ///
+    /// # Parameters
+    /// The key ID to use, and the algorithm the key will sign with.
+    ///
+    /// # Return Values
+    /// The new signing key, and its private key as PKCS #8 PEM so that it can be saved.
+    pub fn generate(kid: &str, algorithm: KeyAlgorithm) -> Result<(Self, Vec<u8>), AuthError> {
+        let private_key = match algorithm {
+            KeyAlgorithm::RS256 => PKey::from_rsa(Rsa::generate(RSA_KEY_BITS)?)?,
+            KeyAlgorithm::ES256 => {
+                let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+                PKey::from_ec_key(EcKey::generate(&group)?)?
+            }
+        };
+        let pem = private_key.private_key_to_pem_pkcs8()?;
+
+        Ok((Self::from_pem(kid, &pem)?, pem))
+    }
+
+    /// Loads a private key from a PEM encoded RSA or P-256 ECDSA key.
+    pub fn from_pem(kid: &str, pem: &[u8]) -> Result<Self, AuthError> {
+        let private_key = PKey::private_key_from_pem(pem)?;
+
+        match private_key.id() {
+            Id::RSA => Self::from_rsa(kid, &private_key),
+            Id::EC => Self::from_ec(kid, &private_key),
+            _ => Err(AuthError::KeyError(format!(
+                "key {} is not an RSA or EC key",
+                kid
+            ))),
+        }
+    }
+
+    /// Gets the key used to sign new JWTs.
+    pub fn encoding_key(&self) -> &EncodingKey {
+        &self.encoding_key
+    }
+
+    /// Gets the key used to verify JWTs signed by this key.
+    pub fn decoding_key(&self) -> &DecodingKey<'static> {
+        &self.decoding_key
+    }
+
+    fn from_rsa(kid: &str, private_key: &PKey<Private>) -> Result<Self, AuthError> {
+        let rsa = private_key.rsa()?;
+        let n = base64_url(&rsa.n().to_vec());
+        let e = base64_url(&rsa.e().to_vec());
+
+        Ok(Self {
+            kid: kid.to_string(),
+            algorithm: KeyAlgorithm::RS256,
+            encoding_key: EncodingKey::from_rsa_der(&rsa.private_key_to_der()?),
+            decoding_key: DecodingKey::from_rsa_components(&n, &e).into_static(),
+            jwk: Jwk {
+                kty: "RSA".to_string(),
+                kid: kid.to_string(),
+                key_use: "sig".to_string(),
+                alg: KeyAlgorithm::RS256.to_string(),
+                n: Some(n),
+                e: Some(e),
+                crv: None,
+                x: None,
+                y: None,
+            },
+        })
+    }
+
+    fn from_ec(kid: &str, private_key: &PKey<Private>) -> Result<Self, AuthError> {
+        let ec_key = private_key.ec_key()?;
+        let group = ec_key.group();
+        if group.curve_name() != Some(Nid::X9_62_PRIME256V1) {
+            return Err(AuthError::KeyError(format!(
+                "key {} does not use the P-256 curve",
+                kid
+            )));
+        }
+
+        let mut ctx = BigNumContext::new()?;
+        let mut x = BigNum::new()?;
+        let mut y = BigNum::new()?;
+        ec_key
+            .public_key()
+            .affine_coordinates_gfp(group, &mut x, &mut y, &mut ctx)?;
+
+        Ok(Self {
+            kid: kid.to_string(),
+            algorithm: KeyAlgorithm::ES256,
+            encoding_key: EncodingKey::from_ec_pem(&private_key.private_key_to_pem_pkcs8()?)
+                .map_err(|e| AuthError::KeyError(e.to_string()))?,
+            decoding_key: DecodingKey::from_ec_pem(&private_key.public_key_to_pem()?)
+                .map_err(|e| AuthError::KeyError(e.to_string()))?
+                .into_static(),
+            jwk: Jwk {
+                kty: "EC".to_string(),
+                kid: kid.to_string(),
+                key_use: "sig".to_string(),
+                alg: KeyAlgorithm::ES256.to_string(),
+                n: None,
+                e: None,
+                crv: Some("P-256".to_string()),
+                x: Some(base64_url(&coordinate_bytes(&x))),
+                y: Some(base64_url(&coordinate_bytes(&y))),
+            },
+        })
+    }
+}
+
+/// Defines repository based data options for signing key metadata.
+#[async_trait]
+pub(crate) trait SigningKeyRepository {
+    /// Locks the signing keys until the end of the current transaction, so that only one instance
+    /// of the service rotates keys at a time.
+    ///
+    /// # Returns
+    /// ## Success
+    /// Ok, but empty.
+    ///
+    /// ## Errors
+    /// If a database failure occured.
+    async fn lock_signing_keys(&mut self) -> Result<(), AuthError>;
+
+    /// Gets every signing key that has not yet been retired.
+    ///
+    /// # Returns
+    /// ## Success
+    /// The keys, ordered by the time they activate.
+    ///
+    /// ## Errors
+    /// If a database failure occured.
+    async fn get_published_signing_keys(&mut self) -> Result<Vec<SigningKeyMetadata>, AuthError>;
+
+    /// Records a newly generated signing key.
+    ///
+    /// # Parameters
+    /// The details of the new key.
+    ///
+    /// # Returns
+    /// ## Success
+    /// The newly created SigningKeyMetadata structure.
+    ///
+    /// ## Errors
+    /// If a key with the same ID exists, or a database failure occured.
+    async fn add_signing_key(
+        &mut self,
+        key_create: &SigningKeyCreate,
+    ) -> Result<SigningKeyMetadata, AuthError>;
+
+    /// Schedules every other published key to retire, if it is not already retiring sooner.
+    ///
+    /// # Parameters
+    /// The ID of the key to keep, and the time the other keys retire at.
+    ///
+    /// # Returns
+    /// ## Success
+    /// Ok, but empty.
+    ///
+    /// ## Errors
+    /// If a database failure occured.
+    async fn retire_signing_keys_except(
+        &mut self,
+        kid: &str,
+        retires_at: NaiveDateTime,
+    ) -> Result<(), AuthError>;
+}
+
+/// Encodes bytes as unpadded base64url, as used throughout JWKs.
+fn base64_url(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+/// Gets the fixed length big-endian bytes of a P-256 coordinate, including any leading zeros.
+fn coordinate_bytes(coordinate: &BigNumRef) -> Vec<u8> {
+    let bytes = coordinate.to_vec();
+    let mut padded = vec![0; P256_COORDINATE_BYTES.saturating_sub(bytes.len())];
+    padded.extend(bytes);
+    padded
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use chrono::{Duration, Utc};
+
+    #[test]
+    fn test_generated_keys_can_be_reloaded_from_pem() {
+        for algorithm in &[KeyAlgorithm::RS256, KeyAlgorithm::ES256] {
+            let (key, pem) =
+                SigningKey::generate("kid", *algorithm).expect("generate returned an error");
+
+            let loaded = SigningKey::from_pem("kid", &pem).expect("from_pem returned an error");
+
+            assert_eq!(loaded.algorithm, *algorithm);
+            assert_eq!(loaded.jwk, key.jwk);
+        }
+    }
+
+    #[test]
+    fn test_retired_and_pending_keys_are_not_active() {
+        let now = Utc::now().naive_utc();
+        let metadata = |activates_at, retires_at| SigningKeyMetadata {
+            kid: "kid".to_string(),
+            algorithm: KeyAlgorithm::ES256.to_string(),
+            created_at: now,
+            activates_at,
+            retires_at,
+        };
+
+        let active = metadata(now - Duration::hours(1), None);
+        let pending = metadata(now + Duration::hours(1), None);
+        let retiring = metadata(now - Duration::hours(2), Some(now + Duration::hours(1)));
+        let retired = metadata(now - Duration::hours(2), Some(now - Duration::hours(1)));
+
+        assert!(active.is_active(now) && active.is_published(now));
+        assert!(!pending.is_active(now) && pending.is_published(now));
+        assert!(retiring.is_active(now) && retiring.is_published(now));
+        assert!(!retired.is_active(now) && !retired.is_published(now));
+    }
+}
@@ -0,0 +1,138 @@
+/// Loads and saves signing keys, and selects which key to use.
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use super::model::{JwkSet, SigningKey, SigningKeyMetadata};
+
+use crate::error::AuthError;
+
+use chrono::Utc;
+
+/// Define the file extension of PEM encoded private keys.
+const KEY_FILE_EXTENSION: &str = "pem";
+
+/// Holds the keys used to sign and verify JWTs.
+///
+/// Each private key is stored as a PEM file in the key directory, named by its key ID.
+pub struct KeyStore {
+    keys: Vec<(SigningKeyMetadata, SigningKey)>,
+}
+
+impl KeyStore {
+    /// Loads the private keys for the given signing keys from a directory.
+    ///
+    /// # Parameters
+    /// The directory containing the PEM files, and the published signing keys.
+    ///
+    /// # Return Values
+    /// A key store containing every published key.
+    pub fn load(dir: &Path, metadata: Vec<SigningKeyMetadata>) -> Result<Self, AuthError> {
+        let mut keys = Vec::with_capacity(metadata.len());
+        for key_metadata in metadata {
+            let key = load_key(dir, &key_metadata.kid)?;
+            keys.push((key_metadata, key));
+        }
+
+        Ok(Self { keys })
+    }
+
+    /// Gets the key currently used to sign new tokens.
+    ///
+    /// This is the most recently activated key, keys that have been published but are not yet
+    /// active are never used.
+    pub fn active_key(&self) -> Result<&SigningKey, AuthError> {
+        let now = Utc::now().naive_utc();
+        self.keys
+            .iter()
+            .filter(|(metadata, _)| metadata.is_active(now))
+            .max_by_key(|(metadata, _)| metadata.activates_at)
+            .map(|(_, key)| key)
+            .ok_or_else(|| AuthError::KeyError("no active signing key".to_string()))
+    }
+
+    /// Finds the published key with the given key ID, for verifying a token.
+    pub fn find(&self, kid: &str) -> Option<&SigningKey> {
+        let now = Utc::now().naive_utc();
+        self.keys
+            .iter()
+            .find(|(metadata, _)| metadata.kid == kid && metadata.is_published(now))
+            .map(|(_, key)| key)
+    }
+
+    /// Gets the public keys of every published key, as a JWK Set.
+    pub fn jwks(&self) -> JwkSet {
+        let now = Utc::now().naive_utc();
+        JwkSet {
+            keys: self
+                .keys
+                .iter()
+                .filter(|(metadata, _)| metadata.is_published(now))
+                .map(|(_, key)| key.jwk.clone())
+                .collect(),
+        }
+    }
+}
+
+/// Loads a private key from the key directory, whether or not it is still published.
+///
+/// Retired keys are never removed from the directory, so anything they signed can still be
+/// verified, e.g. audit log checkpoints.
+pub fn load_key(dir: &Path, kid: &str) -> Result<SigningKey, AuthError> {
+    // Key IDs are generated as simple UUIDs, so anything else cannot name a key file.
+    if kid.is_empty() || !kid.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(AuthError::KeyError(format!("invalid key ID {}", kid)));
+    }
+
+    let pem = fs::read(key_path(dir, kid)).map_err(io_error)?;
+    SigningKey::from_pem(kid, &pem)
+}
+
+/// Saves a new private key to the key directory, readable only by the service.
+pub fn save_key(dir: &Path, kid: &str, pem: &[u8]) -> Result<(), AuthError> {
+    fs::create_dir_all(dir).map_err(io_error)?;
+
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(key_path(dir, kid))
+        .map_err(io_error)?;
+
+    file.write_all(pem).map_err(io_error)
+}
+
+/// Removes a private key from the key directory.
+pub fn remove_key(dir: &Path, kid: &str) -> Result<(), AuthError> {
+    fs::remove_file(key_path(dir, kid)).map_err(io_error)
+}
+
+fn key_path(dir: &Path, kid: &str) -> PathBuf {
+    dir.join(kid).with_extension(KEY_FILE_EXTENSION)
+}
+
+fn io_error(error: std::io::Error) -> AuthError {
+    AuthError::KeyError(error.to_string())
+}
+
+/// Creates a key store holding a single new active key, for a test.
+#[cfg(test)]
+pub(crate) fn test_key_store(algorithm: super::model::KeyAlgorithm) -> KeyStore {
+    let kid = uuid::Uuid::new_v4().to_simple().to_string();
+    let (key, _) = SigningKey::generate(&kid, algorithm).expect("generate returned an error");
+    let now = Utc::now().naive_utc();
+
+    KeyStore {
+        keys: vec![(
+            SigningKeyMetadata {
+                kid,
+                algorithm: algorithm.to_string(),
+                created_at: now,
+                activates_at: now,
+                retires_at: None,
+            },
+            key,
+        )],
+    }
+}
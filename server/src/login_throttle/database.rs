@@ -0,0 +1,251 @@
+use super::model::{LoginThrottle, LoginThrottlePolicy, LoginThrottleRepository, ThrottleScope};
+
+use crate::database::Db;
+use crate::error::AuthError;
+
+use async_trait::async_trait;
+use chrono::naive::NaiveDateTime;
+use chrono::Utc;
+use sqlx::{PgConnection, PgPool};
+
+/// Define how often counts that no longer have an effect are deleted.
+const PRUNE_INTERVAL_SECS: u64 = 60 * 60;
+
+#[async_trait]
+impl LoginThrottleRepository for PgConnection {
+    async fn get_lockout(
+        &mut self,
+        scope: ThrottleScope,
+        key: &str,
+    ) -> Result<Option<NaiveDateTime>, AuthError> {
+        let throttle = sqlx::query!(
+            r#"
+            SELECT locked_until FROM login_throttles
+            WHERE scope = $1 AND key = $2 AND locked_until > $3
+            "#,
+            scope.as_str(),
+            key,
+            Utc::now().naive_utc(),
+        )
+        .fetch_optional(self)
+        .await?;
+
+        Ok(throttle.and_then(|throttle| throttle.locked_until))
+    }
+
+    async fn record_login_failure(
+        &mut self,
+        scope: ThrottleScope,
+        key: &str,
+        policy: &LoginThrottlePolicy,
+    ) -> Result<LoginThrottle, AuthError> {
+        let now = Utc::now().naive_utc();
+
+        // Count the failure in one statement, so concurrent failures are all counted.
+        let throttle = sqlx::query_as!(
+            LoginThrottle,
+            r#"
+            INSERT INTO login_throttles (scope, key, failures, last_failure_at)
+            VALUES ($1, $2, 1, $3)
+            ON CONFLICT (scope, key) DO UPDATE SET
+                failures = CASE
+                    WHEN login_throttles.last_failure_at < $4 THEN 1
+                    ELSE login_throttles.failures + 1
+                END,
+                last_failure_at = $3
+            RETURNING *
+            "#,
+            scope.as_str(),
+            key,
+            now,
+            now - policy.reset_after(),
+        )
+        .fetch_one(&mut *self)
+        .await?;
+
+        let lockout = match policy.lockout(scope, throttle.failures) {
+            Some(lockout) => lockout,
+            None => return Ok(throttle),
+        };
+
+        // A concurrent failure may have already set a later lockout, which is kept.
+        Ok(sqlx::query_as!(
+            LoginThrottle,
+            r#"
+            UPDATE login_throttles SET locked_until = GREATEST(locked_until, $3)
+            WHERE scope = $1 AND key = $2
+            RETURNING *
+            "#,
+            scope.as_str(),
+            key,
+            now + lockout,
+        )
+        .fetch_one(self)
+        .await?)
+    }
+
+    async fn clear_login_failures(
+        &mut self,
+        scope: ThrottleScope,
+        key: &str,
+    ) -> Result<(), AuthError> {
+        sqlx::query!(
+            r#"
+            DELETE FROM login_throttles WHERE scope = $1 AND key = $2
+            "#,
+            scope.as_str(),
+            key,
+        )
+        .execute(self)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn prune_login_throttles(
+        &mut self,
+        policy: &LoginThrottlePolicy,
+    ) -> Result<u64, AuthError> {
+        let now = Utc::now().naive_utc();
+
+        Ok(sqlx::query!(
+            r#"
+            DELETE FROM login_throttles
+            WHERE last_failure_at < $1 AND (locked_until IS NULL OR locked_until < $2)
+            "#,
+            now - policy.reset_after(),
+            now,
+        )
+        .execute(self)
+        .await?)
+    }
+}
+
+/// Periodically deletes the counts of failed sign in attempts that no longer have an effect.
+///
+/// This never returns, so it should be spawned as a separate task.
+pub async fn run_pruning(pool: PgPool, policy: LoginThrottlePolicy) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(PRUNE_INTERVAL_SECS));
+
+    loop {
+        interval.tick().await;
+
+        let pruned = match pool.conn().await {
+            Ok(mut conn) => conn.prune_login_throttles(&policy).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = pruned {
+            println!("Failed to prune login throttles: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::database::postgres::test_transaction;
+
+    use chrono::Duration;
+    use uuid::Uuid;
+
+    fn policy() -> LoginThrottlePolicy {
+        LoginThrottlePolicy::new(
+            2,
+            10,
+            Duration::minutes(1),
+            Duration::hours(1),
+            Duration::days(1),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_failures_lock_out_after_the_free_failures() {
+        let mut tx = test_transaction().await;
+        let key = Uuid::new_v4().to_string();
+
+        for failures in 1..=2 {
+            let throttle = tx
+                .record_login_failure(ThrottleScope::Email, &key, &policy())
+                .await
+                .unwrap();
+            assert_eq!(throttle.failures, failures);
+            assert_eq!(throttle.locked_until, None);
+        }
+        assert_eq!(
+            tx.get_lockout(ThrottleScope::Email, &key).await.unwrap(),
+            None
+        );
+
+        let throttle = tx
+            .record_login_failure(ThrottleScope::Email, &key, &policy())
+            .await
+            .unwrap();
+        let locked_until = tx
+            .get_lockout(ThrottleScope::Email, &key)
+            .await
+            .unwrap()
+            .expect("the email address was not locked out");
+        assert_eq!(Some(locked_until), throttle.locked_until);
+        assert!(locked_until > Utc::now().naive_utc() + Duration::seconds(50));
+
+        // The same key in another scope is counted separately.
+        assert_eq!(tx.get_lockout(ThrottleScope::Ip, &key).await.unwrap(), None);
+
+        tx.clear_login_failures(ThrottleScope::Email, &key)
+            .await
+            .unwrap();
+        assert_eq!(
+            tx.get_lockout(ThrottleScope::Email, &key).await.unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_old_failures_are_reset_and_pruned() {
+        let mut tx = test_transaction().await;
+        let conn: &mut PgConnection = &mut tx;
+        let key = Uuid::new_v4().to_string();
+        let old = Utc::now().naive_utc() - Duration::days(2);
+
+        sqlx::query!(
+            r#"
+            INSERT INTO login_throttles (scope, key, failures, last_failure_at, locked_until)
+            VALUES ('ip', $1, 50, $2, $2)
+            "#,
+            key,
+            old,
+        )
+        .execute(&mut *conn)
+        .await
+        .unwrap();
+
+        let throttle = conn
+            .record_login_failure(ThrottleScope::Ip, &key, &policy())
+            .await
+            .unwrap();
+        assert_eq!(throttle.failures, 1);
+
+        sqlx::query!(
+            r#"
+            UPDATE login_throttles SET last_failure_at = $2 WHERE scope = 'ip' AND key = $1
+            "#,
+            key,
+            old,
+        )
+        .execute(&mut *conn)
+        .await
+        .unwrap();
+        assert!(conn.prune_login_throttles(&policy()).await.unwrap() >= 1);
+        let remaining = sqlx::query!(
+            r#"
+            SELECT failures FROM login_throttles WHERE scope = 'ip' AND key = $1
+            "#,
+            key,
+        )
+        .fetch_optional(&mut *conn)
+        .await
+        .unwrap();
+        assert!(remaining.is_none());
+    }
+}
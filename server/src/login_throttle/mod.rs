@@ -0,0 +1,9 @@
+/// Login throttles slow down password guessing.
+///
+/// Failed sign in attempts are counted per email address and per client IP address. After a
+/// number of failures each further failure locks out its email or IP address, for a time that
+/// doubles with each failure. The counts are stored in the database, so every replica of the
+/// service shares them.
+///
+pub mod database;
+pub mod model;
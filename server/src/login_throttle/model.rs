@@ -0,0 +1,221 @@
+/// Data models for Login Throttles.
+use crate::error::AuthError;
+
+use async_trait::async_trait;
+use chrono::naive::NaiveDateTime;
+use chrono::Duration;
+use std::cmp;
+
+/// Define the most times a lockout is doubled, so the multiplier cannot overflow.
+const MAX_LOCKOUT_DOUBLINGS: i32 = 20;
+
+/// Defines what failed sign in attempts are counted by.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ThrottleScope {
+    /// The canonical email address the attempt was for, whether or not it is registered.
+    Email,
+    /// The IP address the attempt was made from.
+    Ip,
+}
+
+impl ThrottleScope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ThrottleScope::Email => "email",
+            ThrottleScope::Ip => "ip",
+        }
+    }
+}
+
+/// Defines the failed sign in attempts counted for one email address or IP address.
+#[derive(Debug)]
+pub struct LoginThrottle {
+    pub scope: String,
+    pub key: String,
+    /// The number of failures since the count was last reset.
+    pub failures: i32,
+    pub last_failure_at: NaiveDateTime,
+    /// When the current lockout ends, unset if there has not been one.
+    pub locked_until: Option<NaiveDateTime>,
+}
+
+/// The rules for locking out email and IP addresses after failed sign in attempts.
+#[derive(Debug, Clone)]
+pub struct LoginThrottlePolicy {
+    /// The number of failures allowed for an email address before it is locked out.
+    email_free_failures: i32,
+    /// The number of failures allowed from an IP address before it is locked out. This should be
+    /// higher than for an email address, as many users can share an IP address.
+    ip_free_failures: i32,
+    base_lockout: Duration,
+    max_lockout: Duration,
+    /// How long failures are counted for. A failure this long after the previous one starts a new
+    /// count.
+    reset_after: Duration,
+}
+
+impl LoginThrottlePolicy {
+    /// Creates a new LoginThrottlePolicy instance.
+    ///
+    /// # Parameters
+    /// The number of failures allowed for an email address and from an IP address before they are
+    /// locked out, the length of the first lockout, which doubles with each further failure up to
+    /// the maximum length, and how long after the last failure the count is reset.
+    pub fn new(
+        email_free_failures: i32,
+        ip_free_failures: i32,
+        base_lockout: Duration,
+        max_lockout: Duration,
+        reset_after: Duration,
+    ) -> Self {
+        Self {
+            email_free_failures,
+            ip_free_failures,
+            base_lockout,
+            max_lockout,
+            reset_after,
+        }
+    }
+
+    /// Gets the lockout that follows a number of failures.
+    ///
+    /// # Parameters
+    /// What the failures were counted by, and the number of failures.
+    ///
+    /// # Return Values
+    /// How long the email or IP address is locked out for, or `None` if it is not.
+    pub fn lockout(&self, scope: ThrottleScope, failures: i32) -> Option<Duration> {
+        let free_failures = match scope {
+            ThrottleScope::Email => self.email_free_failures,
+            ThrottleScope::Ip => self.ip_free_failures,
+        };
+
+        let doublings = failures - free_failures - 1;
+        if doublings < 0 {
+            return None;
+        }
+
+        let lockout = self.base_lockout * (1 << cmp::min(doublings, MAX_LOCKOUT_DOUBLINGS));
+        Some(cmp::min(lockout, self.max_lockout))
+    }
+
+    pub fn reset_after(&self) -> Duration {
+        self.reset_after
+    }
+}
+
+#[async_trait]
+pub(crate) trait LoginThrottleRepository: Send + Sync + 'static {
+    /// Gets when the lockout of an email or IP address ends.
+    ///
+    /// # Parameters
+    /// What the failures are counted by, and the email or IP address.
+    ///
+    /// # Return Values
+    /// ## Success
+    /// When the lockout ends, or `None` if the address is not locked out.
+    ///
+    /// ## Errors
+    /// If a failure occured with the database.
+    async fn get_lockout(
+        &mut self,
+        scope: ThrottleScope,
+        key: &str,
+    ) -> Result<Option<NaiveDateTime>, AuthError>;
+
+    /// Counts a failed sign in attempt, locking the email or IP address out if the policy says so.
+    ///
+    /// # Parameters
+    /// What the failure is counted by, the email or IP address, and the lockout rules.
+    ///
+    /// # Return Values
+    /// ## Success
+    /// The updated LoginThrottle structure.
+    ///
+    /// ## Errors
+    /// If a failure occured with the database.
+    async fn record_login_failure(
+        &mut self,
+        scope: ThrottleScope,
+        key: &str,
+        policy: &LoginThrottlePolicy,
+    ) -> Result<LoginThrottle, AuthError>;
+
+    /// Clears the failed sign in attempts of an email or IP address, ending any lockout.
+    ///
+    /// # Parameters
+    /// What the failures were counted by, and the email or IP address.
+    ///
+    /// # Return Values
+    /// ## Success
+    /// Ok, but empty.
+    ///
+    /// ## Errors
+    /// If a failure occured with the database.
+    async fn clear_login_failures(
+        &mut self,
+        scope: ThrottleScope,
+        key: &str,
+    ) -> Result<(), AuthError>;
+
+    /// Deletes the counts that no longer have an effect, as their last failure was before the
+    /// count is reset and any lockout has ended.
+    ///
+    /// # Parameters
+    /// The lockout rules.
+    ///
+    /// # Return Values
+    /// ## Success
+    /// The number of counts deleted.
+    ///
+    /// ## Errors
+    /// If a failure occured with the database.
+    async fn prune_login_throttles(
+        &mut self,
+        policy: &LoginThrottlePolicy,
+    ) -> Result<u64, AuthError>;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_lockout_doubles_up_to_the_maximum() {
+        let policy = LoginThrottlePolicy::new(
+            3,
+            10,
+            Duration::seconds(30),
+            Duration::minutes(5),
+            Duration::days(1),
+        );
+
+        let lockouts: Vec<_> = (1..=9)
+            .map(|failures| policy.lockout(ThrottleScope::Email, failures))
+            .collect();
+        assert_eq!(
+            lockouts,
+            vec![
+                None,
+                None,
+                None,
+                Some(Duration::seconds(30)),
+                Some(Duration::seconds(60)),
+                Some(Duration::seconds(120)),
+                Some(Duration::seconds(240)),
+                Some(Duration::minutes(5)),
+                Some(Duration::minutes(5)),
+            ]
+        );
+
+        assert_eq!(policy.lockout(ThrottleScope::Ip, 10), None);
+        assert_eq!(
+            policy.lockout(ThrottleScope::Ip, 11),
+            Some(Duration::seconds(30))
+        );
+        assert_eq!(
+            policy.lockout(ThrottleScope::Email, i32::max_value()),
+            Some(Duration::minutes(5))
+        );
+    }
+}
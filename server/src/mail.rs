@@ -0,0 +1,61 @@
+/// Sends emails to users.
+///
+/// Emails are handed to the mail service's HTTP API, which takes care of templating and delivery.
+/// Without one configured, e.g. in development, emails are printed instead.
+use crate::error::AuthError;
+
+use serde::Serialize;
+
+/// Defines an email to send to a user.
+#[derive(Debug, Serialize)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+pub struct Mailer {
+    api_url: Option<String>,
+    client: reqwest::Client,
+}
+
+impl Mailer {
+    /// Creates a new Mailer instance.
+    ///
+    /// # Parameters
+    /// The URL emails are posted to as JSON, if unset emails are printed instead.
+    pub fn new(api_url: Option<String>) -> Self {
+        Self {
+            api_url,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Sends an email.
+    ///
+    /// # Return Values
+    /// ## Success
+    /// Ok, but empty, once the mail service has accepted the email.
+    ///
+    /// ## Errors
+    /// If the mail service could not be reached, or rejected the email.
+    pub async fn send(&self, email: &Email) -> Result<(), AuthError> {
+        let api_url = match &self.api_url {
+            Some(api_url) => api_url,
+            None => {
+                println!("Email to {}: {}\n{}", email.to, email.subject, email.body);
+                return Ok(());
+            }
+        };
+
+        self.client
+            .post(api_url)
+            .json(email)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| AuthError::MailError(e.to_string()))?;
+
+        Ok(())
+    }
+}
@@ -1,25 +1,194 @@
 mod account;
+mod audit;
+mod breached_passwords;
 mod database;
+mod discovery;
+mod email;
+mod email_verification;
 mod error;
 mod hashing;
 mod identity;
+mod invitation;
 mod jwt;
+mod keys;
+mod login_throttle;
+mod mail;
+mod oidc;
+mod password_policy;
+mod password_reset;
+mod rate_limit;
 mod refresh_token;
 mod server;
 
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use chrono::Duration;
 use dotenv::dotenv;
 use tokio;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     dotenv().ok();
+
+    let mut args = std::env::args().skip(1);
+    if args.next().as_deref() == Some("build-breached-passwords-index") {
+        return build_breached_passwords_index(args.collect());
+    }
+
     let database_url = dotenv::var("DATABASE_URL").expect("DATABASE_URL must be set");
     let server_addr = dotenv::var("SERVER_ADDR").expect("SERVER_URL must be set");
+    let http_addr = dotenv::var("HTTP_ADDR").expect("HTTP_ADDR must be set");
+    let sign_in_url = dotenv::var("SIGN_IN_URL").expect("SIGN_IN_URL must be set");
+    let keys_dir = dotenv::var("JWT_KEYS_DIR").expect("JWT_KEYS_DIR must be set");
+    let key_algorithm = dotenv::var("JWT_KEY_ALGORITHM").expect("JWT_KEY_ALGORITHM must be set");
+    let key_rotation_hours: i64 = dotenv::var("JWT_KEY_ROTATION_HOURS")
+        .expect("JWT_KEY_ROTATION_HOURS must be set")
+        .parse()?;
+    let key_publish_hours: i64 = dotenv::var("JWT_KEY_PUBLISH_HOURS")
+        .expect("JWT_KEY_PUBLISH_HOURS must be set")
+        .parse()?;
+    let providers_file =
+        dotenv::var("OIDC_PROVIDERS_FILE").expect("OIDC_PROVIDERS_FILE must be set");
+    let mail_api_url = dotenv::var("MAIL_API_URL").ok();
+    let password_min_length: usize = dotenv::var("PASSWORD_MIN_LENGTH")
+        .expect("PASSWORD_MIN_LENGTH must be set")
+        .parse()?;
+    let password_max_length: usize = dotenv::var("PASSWORD_MAX_LENGTH")
+        .expect("PASSWORD_MAX_LENGTH must be set")
+        .parse()?;
+    let password_required_classes =
+        dotenv::var("PASSWORD_REQUIRED_CLASSES").expect("PASSWORD_REQUIRED_CLASSES must be set");
+    let password_deny_list_file = dotenv::var("PASSWORD_DENY_LIST_FILE").ok();
+    let breached_passwords_index = dotenv::var("BREACHED_PASSWORDS_INDEX").ok();
+    let login_email_free_failures: i32 = dotenv::var("LOGIN_EMAIL_FREE_FAILURES")
+        .expect("LOGIN_EMAIL_FREE_FAILURES must be set")
+        .parse()?;
+    let login_ip_free_failures: i32 = dotenv::var("LOGIN_IP_FREE_FAILURES")
+        .expect("LOGIN_IP_FREE_FAILURES must be set")
+        .parse()?;
+    let login_lockout_base_seconds: i64 = dotenv::var("LOGIN_LOCKOUT_BASE_SECONDS")
+        .expect("LOGIN_LOCKOUT_BASE_SECONDS must be set")
+        .parse()?;
+    let login_lockout_max_seconds: i64 = dotenv::var("LOGIN_LOCKOUT_MAX_SECONDS")
+        .expect("LOGIN_LOCKOUT_MAX_SECONDS must be set")
+        .parse()?;
+    let login_failure_reset_seconds: i64 = dotenv::var("LOGIN_FAILURE_RESET_SECONDS")
+        .expect("LOGIN_FAILURE_RESET_SECONDS must be set")
+        .parse()?;
+    let rate_limits_file = dotenv::var("RATE_LIMITS_FILE").expect("RATE_LIMITS_FILE must be set");
+    let admin_api_key = dotenv::var("ADMIN_API_KEY")
+        .ok()
+        .filter(|key| !key.is_empty())
+        .expect("ADMIN_API_KEY must be set");
+    let audit_checkpoint_seconds: i64 = dotenv::var("AUDIT_CHECKPOINT_SECONDS")
+        .expect("AUDIT_CHECKPOINT_SECONDS must be set")
+        .parse()?;
+
+    discovery::check_issuer(&jwt::model::JWT_ISSUER)?;
+
+    // Failed sign ins verify against the dummy hash, which should not slow down the first one.
+    hashing::Argon2id::init_dummy_hash();
 
     let pool = database::postgres::connect(&database_url).await?;
-    let auth_service = server::AuthService::new(pool);
+    let keys = Arc::new(
+        keys::manager::KeyManager::new(
+            pool.clone(),
+            PathBuf::from(keys_dir),
+            key_algorithm.parse()?,
+            Duration::hours(key_rotation_hours),
+            Duration::hours(key_publish_hours),
+        )
+        .await?,
+    );
+    tokio::spawn(keys::manager::run_rotation(keys.clone()));
+    tokio::spawn(audit::chain::run_checkpoints(
+        pool.clone(),
+        keys.clone(),
+        Duration::seconds(audit_checkpoint_seconds),
+    ));
+
+    let providers = oidc::provider::ProviderRegistry::load(Path::new(&providers_file))?;
+    let mailer = Arc::new(mail::Mailer::new(mail_api_url));
+    let password_policy = password_policy::PasswordPolicy::new(
+        password_min_length,
+        password_max_length,
+        password_required_classes
+            .split(',')
+            .map(str::trim)
+            .filter(|class| !class.is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()?,
+        match password_deny_list_file {
+            Some(file) => password_policy::PasswordPolicy::load_deny_list(Path::new(&file))?,
+            None => HashSet::new(),
+        },
+        match breached_passwords_index {
+            Some(file) => Some(breached_passwords::BreachedPasswords::open(Path::new(
+                &file,
+            ))?),
+            None => None,
+        },
+    );
+    let login_throttle_policy = login_throttle::model::LoginThrottlePolicy::new(
+        login_email_free_failures,
+        login_ip_free_failures,
+        Duration::seconds(login_lockout_base_seconds),
+        Duration::seconds(login_lockout_max_seconds),
+        Duration::seconds(login_failure_reset_seconds),
+    );
+    tokio::spawn(login_throttle::database::run_pruning(
+        pool.clone(),
+        login_throttle_policy.clone(),
+    ));
+
+    let rate_limiter = Arc::new(rate_limit::RateLimiter::new(
+        rate_limit::RateLimitConfig::load(Path::new(&rate_limits_file))?,
+    ));
+    tokio::spawn(rate_limit::run_pruning(rate_limiter.clone()));
+
+    let auth_service = server::AuthService::new(
+        pool,
+        keys.clone(),
+        providers,
+        mailer,
+        password_policy,
+        login_throttle_policy,
+        rate_limiter.clone(),
+        &admin_api_key,
+    );
+
+    tokio::try_join!(
+        auth_service.run_server(server_addr.parse()?),
+        discovery::run_http_server(http_addr.parse()?, keys, sign_in_url, rate_limiter),
+    )?;
+
+    Ok(())
+}
+
+const BUILD_BREACHED_PASSWORDS_INDEX_USAGE: &str =
+    "usage: build-breached-passwords-index <range directory> <index file> [minimum count]";
+
+/// Builds the breached passwords index from a directory of Have I Been Pwned range files.
+///
+/// Passwords that appeared in fewer than the minimum count of breaches, 1 by default, are left out.
+fn build_breached_passwords_index(args: Vec<String>) -> Result<(), Box<dyn std::error::Error>> {
+    let (range_dir, index_file) = match args.as_slice() {
+        [range_dir, index_file, ..] => (range_dir, index_file),
+        _ => return Err(BUILD_BREACHED_PASSWORDS_INDEX_USAGE.into()),
+    };
+    let min_count = match args.get(2) {
+        Some(min_count) => min_count.parse()?,
+        None => 1,
+    };
 
-    auth_service.run_server(server_addr.parse()?).await?;
+    let count = breached_passwords::BreachedPasswords::build(
+        Path::new(range_dir),
+        Path::new(index_file),
+        min_count,
+    )?;
+    println!("Indexed {} breached passwords", count);
 
     Ok(())
 }
@@ -0,0 +1,81 @@
+/// Fetches and caches the JWK Sets published by identity providers.
+use std::time::{Duration, Instant};
+
+use crate::error::AuthError;
+use crate::keys::model::{Jwk, JwkSet};
+
+use jsonwebtoken::errors::ErrorKind;
+use tokio::sync::RwLock;
+
+/// Define how long a fetched JWK Set is used for before it is fetched again, in seconds.
+const JWKS_CACHE_SECS: u64 = 60 * 60;
+
+/// Define the minimum time between fetches, in seconds.
+///
+/// Tokens signed with an unknown key cause the keys to be fetched again in case the provider has
+/// rotated its keys, this stops such tokens from being used to flood the provider with requests.
+const JWKS_MIN_REFRESH_SECS: u64 = 60;
+
+/// A JWK Set published at a URL, cached between requests.
+pub struct RemoteJwks {
+    url: String,
+    client: reqwest::Client,
+    cache: RwLock<Option<(Instant, JwkSet)>>,
+}
+
+impl RemoteJwks {
+    /// Creates a new RemoteJwks instance, the keys are not fetched until they are first needed.
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            client: reqwest::Client::new(),
+            cache: RwLock::new(None),
+        }
+    }
+
+    /// Finds the key with the given key ID, fetching the keys if they are not cached.
+    ///
+    /// # Return Values
+    /// ## Success
+    /// The matching key.
+    ///
+    /// ## Errors
+    /// If the keys could not be fetched, or no key matched.
+    pub async fn find(&self, kid: &str) -> Result<Jwk, AuthError> {
+        if let Some((fetched_at, jwks)) = &*self.cache.read().await {
+            let age = fetched_at.elapsed();
+            let jwk = jwks.keys.iter().find(|jwk| jwk.kid == kid);
+
+            match jwk {
+                Some(jwk) if age < Duration::from_secs(JWKS_CACHE_SECS) => return Ok(jwk.clone()),
+                None if age < Duration::from_secs(JWKS_MIN_REFRESH_SECS) => {
+                    return Err(unknown_key())
+                }
+                _ => {}
+            }
+        }
+
+        let jwks = self.fetch().await?;
+        let jwk = jwks.keys.iter().find(|jwk| jwk.kid == kid).cloned();
+        *self.cache.write().await = Some((Instant::now(), jwks));
+
+        jwk.ok_or_else(unknown_key)
+    }
+
+    async fn fetch(&self) -> Result<JwkSet, AuthError> {
+        self.client
+            .get(&self.url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| AuthError::IdentityProviderError(e.to_string()))?
+            .json()
+            .await
+            .map_err(|e| AuthError::IdentityProviderError(e.to_string()))
+    }
+}
+
+/// Tokens signed by an unknown key can never have a valid signature.
+fn unknown_key() -> AuthError {
+    AuthError::InvalidToken(ErrorKind::InvalidSignature.into())
+}
@@ -0,0 +1,12 @@
+/// OpenID Connect (OIDC) allows users to sign in with an account from a third-party identity
+/// provider, such as Google, Azure AD, Okta or Keycloak.
+///
+/// Providers are configured in a registry. Each provider issues signed ID tokens, which are
+/// verified against the provider's published JWK Set before the user is signed in to the matching
+/// account.
+///
+pub mod jwks;
+pub mod provider;
+
+#[cfg(test)]
+mod test_server;
@@ -0,0 +1,493 @@
+/// Defines configurable OpenID Connect identity providers.
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use super::jwks::RemoteJwks;
+
+use crate::error::AuthError;
+use crate::identity::model::PASSWORD_PROVIDER;
+
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::{decode, decode_header, Algorithm, Validation};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use tokio::sync::RwLock;
+
+/// Define the path of the OpenID Connect discovery metadata, relative to the issuer.
+const DISCOVERY_PATH: &str = "/.well-known/openid-configuration";
+
+/// Defines which ID token claims hold each of the account details.
+///
+/// Most providers use the standard OpenID Connect claims, but some, such as Azure AD, put the
+/// email address in another claim.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ClaimMappings {
+    pub subject: String,
+    pub email: String,
+    pub email_verified: String,
+    pub given_name: String,
+    pub name: String,
+}
+
+impl Default for ClaimMappings {
+    fn default() -> Self {
+        Self {
+            subject: "sub".to_string(),
+            email: "email".to_string(),
+            email_verified: "email_verified".to_string(),
+            given_name: "given_name".to_string(),
+            name: "name".to_string(),
+        }
+    }
+}
+
+/// Defines the configuration of an identity provider.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProviderConfig {
+    /// The name clients use to select the provider, and which is stored on each identity.
+    pub name: String,
+    /// The issuer URL, the discovery metadata is fetched relative to this.
+    pub issuer: String,
+    /// Any other issuers that appear in the provider's ID tokens.
+    #[serde(default)]
+    pub additional_issuers: Vec<String>,
+    pub client_id: String,
+    #[serde(default)]
+    pub client_secret: String,
+    #[serde(default = "default_scopes")]
+    pub scopes: Vec<String>,
+    #[serde(default)]
+    pub claims: ClaimMappings,
+}
+
+fn default_scopes() -> Vec<String> {
+    vec![
+        "openid".to_string(),
+        "email".to_string(),
+        "profile".to_string(),
+    ]
+}
+
+/// Defines the file the provider registry is loaded from.
+#[derive(Debug, Deserialize)]
+struct RegistryConfig {
+    providers: Vec<ProviderConfig>,
+}
+
+/// Defines the parts of a provider's discovery metadata that are used by this service.
+#[derive(Debug, Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+/// Defines the parts of a token endpoint response that are used by this service.
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// A user's identity, as asserted by a provider's verified ID token.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExternalIdentity {
+    pub provider: String,
+    pub subject: String,
+    pub email: String,
+    pub given_name: String,
+}
+
+struct Discovered {
+    metadata: ProviderMetadata,
+    jwks: RemoteJwks,
+}
+
+/// An OpenID Connect identity provider.
+///
+/// The provider's endpoints are discovered from its issuer the first time they are needed.
+pub struct Provider {
+    config: ProviderConfig,
+    client: reqwest::Client,
+    discovered: RwLock<Option<Arc<Discovered>>>,
+}
+
+impl Provider {
+    /// Creates a new Provider instance.
+    pub fn new(config: ProviderConfig) -> Self {
+        Self {
+            config,
+            client: reqwest::Client::new(),
+            discovered: RwLock::new(None),
+        }
+    }
+
+    /// Builds the URL to send a user to, to sign in with the provider.
+    ///
+    /// # Parameters
+    /// The URL the provider should redirect the user back to with an authorization code, and the
+    /// client's `state` and `nonce` values.
+    pub async fn authorization_url(
+        &self,
+        redirect_uri: &str,
+        state: &str,
+        nonce: &str,
+    ) -> Result<String, AuthError> {
+        let discovered = self.discover().await?;
+        let scope = self.config.scopes.join(" ");
+
+        let url = reqwest::Url::parse_with_params(
+            &discovered.metadata.authorization_endpoint,
+            &[
+                ("response_type", "code"),
+                ("client_id", &self.config.client_id),
+                ("redirect_uri", redirect_uri),
+                ("scope", &scope),
+                ("state", state),
+                ("nonce", nonce),
+            ],
+        )
+        .map_err(|e| AuthError::IdentityProviderError(e.to_string()))?;
+
+        Ok(url.to_string())
+    }
+
+    /// Exchanges an authorization code at the provider's token endpoint, and verifies the
+    /// returned ID token.
+    ///
+    /// # Parameters
+    /// The authorization code, the redirect URI it was issued for, and the nonce sent in the
+    /// authorization request.
+    pub async fn exchange_code(
+        &self,
+        code: &str,
+        redirect_uri: &str,
+        nonce: &str,
+    ) -> Result<ExternalIdentity, AuthError> {
+        let discovered = self.discover().await?;
+
+        let response = self
+            .client
+            .post(&discovered.metadata.token_endpoint)
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", redirect_uri),
+                ("client_id", &self.config.client_id),
+                ("client_secret", &self.config.client_secret),
+            ])
+            .send()
+            .await
+            .map_err(|e| AuthError::IdentityProviderError(e.to_string()))?;
+
+        if response.status().is_client_error() {
+            return Err(AuthError::InvalidRequest(format!(
+                "{} rejected the authorization code",
+                self.config.name
+            )));
+        }
+
+        let token_response: TokenResponse = response
+            .error_for_status()
+            .map_err(|e| AuthError::IdentityProviderError(e.to_string()))?
+            .json()
+            .await
+            .map_err(|e| AuthError::IdentityProviderError(e.to_string()))?;
+
+        self.verify_id_token(&token_response.id_token, Some(nonce))
+            .await
+    }
+
+    /// Verifies an ID token issued by the provider.
+    ///
+    /// The token must be signed by the provider, unexpired, issued to this service's client ID,
+    /// and for a user whose email address has been verified. If a nonce is given, the token must
+    /// contain it.
+    ///
+    /// Returns the identity the token asserts.
+    pub async fn verify_id_token(
+        &self,
+        id_token: &str,
+        nonce: Option<&str>,
+    ) -> Result<ExternalIdentity, AuthError> {
+        let discovered = self.discover().await?;
+
+        let header = decode_header(id_token).map_err(AuthError::InvalidToken)?;
+        let kid = header
+            .kid
+            .ok_or_else(|| AuthError::InvalidToken(ErrorKind::InvalidSignature.into()))?;
+        let jwk = discovered.jwks.find(&kid).await?;
+
+        let mut validation = Validation::new(Algorithm::RS256);
+        validation.algorithms = vec![Algorithm::RS256, Algorithm::RS384, Algorithm::RS512];
+        validation.set_audience(&[&self.config.client_id]);
+
+        let claims = decode::<Map<String, Value>>(id_token, &jwk.decoding_key()?, &validation)
+            .map_err(AuthError::InvalidToken)?
+            .claims;
+
+        // Some providers use more than one issuer, so this cannot be checked by the validation.
+        let issuer = claim(&claims, "iss").unwrap_or_default();
+        let known_issuer = issuer == discovered.metadata.issuer
+            || issuer == self.config.issuer
+            || self.config.additional_issuers.contains(&issuer);
+        if !known_issuer {
+            return Err(AuthError::InvalidToken(ErrorKind::InvalidIssuer.into()));
+        }
+
+        if let Some(nonce) = nonce {
+            if claim(&claims, "nonce").as_deref() != Some(nonce) {
+                return Err(AuthError::InvalidRequest("nonce mismatch".to_string()));
+            }
+        }
+
+        self.map_claims(&claims)
+    }
+
+    /// Maps a verified ID token's claims to an identity, using the configured claim mappings.
+    fn map_claims(&self, claims: &Map<String, Value>) -> Result<ExternalIdentity, AuthError> {
+        let mappings = &self.config.claims;
+        let required = |name: &str| {
+            claim(claims, name)
+                .ok_or_else(|| AuthError::InvalidRequest(format!("missing {} claim", name)))
+        };
+
+        let subject = required(&mappings.subject)?;
+        let email = required(&mappings.email)?;
+
+        // Some providers encode booleans as strings.
+        let email_verified = match claims.get(&mappings.email_verified) {
+            Some(Value::Bool(email_verified)) => *email_verified,
+            Some(Value::String(email_verified)) => email_verified == "true",
+            _ => false,
+        };
+        if !email_verified {
+            return Err(AuthError::UnverifiedEmail(email));
+        }
+
+        Ok(ExternalIdentity {
+            provider: self.config.name.clone(),
+            subject,
+            email,
+            given_name: claim(claims, &mappings.given_name)
+                .or_else(|| claim(claims, &mappings.name))
+                .unwrap_or_default(),
+        })
+    }
+
+    /// Gets the provider's discovery metadata and JWK Set, fetching the metadata if needed.
+    async fn discover(&self) -> Result<Arc<Discovered>, AuthError> {
+        if let Some(discovered) = &*self.discovered.read().await {
+            return Ok(discovered.clone());
+        }
+
+        let url = format!(
+            "{}{}",
+            self.config.issuer.trim_end_matches('/'),
+            DISCOVERY_PATH
+        );
+        let metadata: ProviderMetadata = get_json(&self.client, &url).await?;
+        let discovered = Arc::new(Discovered {
+            jwks: RemoteJwks::new(&metadata.jwks_uri),
+            metadata,
+        });
+
+        *self.discovered.write().await = Some(discovered.clone());
+        Ok(discovered)
+    }
+}
+
+/// Holds every configured identity provider.
+pub struct ProviderRegistry {
+    providers: HashMap<String, Provider>,
+}
+
+impl ProviderRegistry {
+    /// Creates a registry of the given providers.
+    pub fn new(configs: Vec<ProviderConfig>) -> Result<Self, AuthError> {
+        let mut providers = HashMap::new();
+        for config in configs {
+            if config.name == PASSWORD_PROVIDER || providers.contains_key(&config.name) {
+                return Err(AuthError::InvalidRequest(format!(
+                    "invalid provider name {}",
+                    config.name
+                )));
+            }
+            providers.insert(config.name.clone(), Provider::new(config));
+        }
+
+        Ok(Self { providers })
+    }
+
+    /// Loads the registry from a JSON file, in the form `{ "providers": [ ... ] }`.
+    pub fn load(path: &Path) -> Result<Self, AuthError> {
+        let file = fs::read(path).map_err(|e| AuthError::Unknown(Box::new(e)))?;
+        let registry_config: RegistryConfig =
+            serde_json::from_slice(&file).map_err(|e| AuthError::Unknown(Box::new(e)))?;
+
+        Self::new(registry_config.providers)
+    }
+
+    /// Gets a provider by name.
+    pub fn get(&self, name: &str) -> Result<&Provider, AuthError> {
+        self.providers
+            .get(name)
+            .ok_or_else(|| AuthError::InvalidRequest(format!("unknown provider {}", name)))
+    }
+}
+
+/// Gets a string claim.
+fn claim(claims: &Map<String, Value>, name: &str) -> Option<String> {
+    claims
+        .get(name)
+        .and_then(Value::as_str)
+        .map(|value| value.to_string())
+}
+
+async fn get_json<T: DeserializeOwned>(
+    client: &reqwest::Client,
+    url: &str,
+) -> Result<T, AuthError> {
+    client
+        .get(url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| AuthError::IdentityProviderError(e.to_string()))?
+        .json()
+        .await
+        .map_err(|e| AuthError::IdentityProviderError(e.to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::keys::model::{JwkSet, KeyAlgorithm, SigningKey};
+    use crate::oidc::test_server;
+
+    use chrono::Utc;
+    use jsonwebtoken::{encode, Header};
+    use serde_json::json;
+
+    const CLIENT_ID: &str = "client-id";
+    const NONCE: &str = "nonce";
+
+    fn sign(key: &SigningKey, issuer: &str, aud: &str, email_verified: bool) -> String {
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some(key.kid.clone());
+        let claims = json!({
+            "iss": issuer,
+            "sub": "1234",
+            "aud": aud,
+            "iat": Utc::now().timestamp(),
+            "exp": Utc::now().timestamp() + 60,
+            "nonce": NONCE,
+            "email": "user@example.com",
+            "email_verified": email_verified,
+            "name": "User",
+        });
+
+        encode(&header, &claims, key.encoding_key()).expect("encode returned an error")
+    }
+
+    /// Starts a mock OIDC provider, whose token endpoint returns the given ID token.
+    fn mock_provider(key: &SigningKey, id_token: impl FnOnce(&str) -> String) -> Provider {
+        let jwks = JwkSet {
+            keys: vec![key.jwk.clone()],
+        };
+        let issuer = test_server::serve(|url| {
+            vec![
+                (
+                    DISCOVERY_PATH,
+                    json!({
+                        "issuer": url,
+                        "authorization_endpoint": format!("{}/authorize", url),
+                        "token_endpoint": format!("{}/token", url),
+                        "jwks_uri": format!("{}/certs", url),
+                    })
+                    .to_string(),
+                ),
+                ("/token", json!({ "id_token": id_token(url) }).to_string()),
+                ("/certs", serde_json::to_string(&jwks).unwrap()),
+            ]
+        });
+
+        Provider::new(ProviderConfig {
+            name: "mock".to_string(),
+            issuer,
+            additional_issuers: vec![],
+            client_id: CLIENT_ID.to_string(),
+            client_secret: "secret".to_string(),
+            scopes: default_scopes(),
+            claims: ClaimMappings::default(),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_exchange_code_verifies_the_returned_id_token() {
+        let (key, _) = SigningKey::generate("kid", KeyAlgorithm::RS256).expect("generate failed");
+        let provider = mock_provider(&key, |url| sign(&key, url, CLIENT_ID, true));
+
+        let identity = provider
+            .exchange_code("code", "https://example.com/callback", NONCE)
+            .await
+            .expect("exchange_code returned an error");
+
+        assert_eq!(
+            identity,
+            ExternalIdentity {
+                provider: "mock".to_string(),
+                subject: "1234".to_string(),
+                email: "user@example.com".to_string(),
+                given_name: "User".to_string(),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_verify_id_token_rejects_invalid_tokens() {
+        let (key, _) = SigningKey::generate("kid", KeyAlgorithm::RS256).expect("generate failed");
+        let (other_key, _) =
+            SigningKey::generate("kid", KeyAlgorithm::RS256).expect("generate failed");
+        let mut issuer = String::new();
+        let provider = mock_provider(&key, |url| {
+            issuer = url.to_string();
+            String::new()
+        });
+
+        let wrong_audience = sign(&key, &issuer, "other-client", true);
+        let wrong_issuer = sign(&key, "https://attacker.example.com", CLIENT_ID, true);
+        let wrong_key = sign(&other_key, &issuer, CLIENT_ID, true);
+        let unverified = sign(&key, &issuer, CLIENT_ID, false);
+
+        let result = provider.verify_id_token(&wrong_audience, None).await;
+        assert!(matches!(result, Err(AuthError::InvalidToken(_))));
+        let result = provider.verify_id_token(&wrong_issuer, None).await;
+        assert!(matches!(result, Err(AuthError::InvalidToken(_))));
+        let result = provider.verify_id_token(&wrong_key, None).await;
+        assert!(matches!(result, Err(AuthError::InvalidToken(_))));
+        let result = provider.verify_id_token(&unverified, None).await;
+        assert!(matches!(result, Err(AuthError::UnverifiedEmail(_))));
+        let result = provider.verify_id_token(&unverified, Some("other")).await;
+        assert!(matches!(result, Err(AuthError::InvalidRequest(_))));
+    }
+
+    #[tokio::test]
+    async fn test_authorization_url_includes_the_request_parameters() {
+        let (key, _) = SigningKey::generate("kid", KeyAlgorithm::RS256).expect("generate failed");
+        let provider = mock_provider(&key, |_| String::new());
+
+        let url = provider
+            .authorization_url("https://example.com/callback", "state", NONCE)
+            .await
+            .expect("authorization_url returned an error");
+
+        assert!(url.contains("/authorize?response_type=code&client_id=client-id"));
+        assert!(url.contains("&state=state&nonce=nonce"));
+    }
+}
@@ -0,0 +1,57 @@
+/// A stub identity provider, serving fixed JSON responses from localhost for tests.
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::TcpListener;
+use std::sync::Arc;
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Response, Server, StatusCode};
+
+/// Starts serving each JSON body at its path, for any request method.
+///
+/// # Parameters
+/// A function building the routes from the base URL of the server, e.g. `http://127.0.0.1:12345`,
+/// so that responses can link to other routes.
+///
+/// # Return Values
+/// The base URL of the server.
+pub(crate) fn serve<F>(routes: F) -> String
+where
+    F: FnOnce(&str) -> Vec<(&'static str, String)>,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").expect("failed to bind stub server");
+    listener
+        .set_nonblocking(true)
+        .expect("failed to configure stub server");
+    let url = format!(
+        "http://{}",
+        listener.local_addr().expect("stub server address")
+    );
+
+    let routes: Arc<HashMap<_, _>> = Arc::new(routes(&url).into_iter().collect());
+    let make_service = make_service_fn(move |_| {
+        let routes = routes.clone();
+
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request: hyper::Request<Body>| {
+                let response = match routes.get(request.uri().path()) {
+                    Some(body) => Response::new(Body::from(body.clone())),
+                    None => {
+                        let mut response = Response::new(Body::empty());
+                        *response.status_mut() = StatusCode::NOT_FOUND;
+                        response
+                    }
+                };
+
+                async move { Ok::<_, Infallible>(response) }
+            }))
+        }
+    });
+
+    let server = Server::from_tcp(listener)
+        .expect("failed to start stub server")
+        .serve(make_service);
+    tokio::spawn(server);
+
+    url
+}
@@ -0,0 +1,247 @@
+/// Defines the rules passwords must follow.
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::breached_passwords::BreachedPasswords;
+use crate::error::{AuthError, FieldViolation};
+
+/// Defines a class of characters a password can be required to contain.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CharacterClass {
+    Lowercase,
+    Uppercase,
+    Digit,
+    Symbol,
+}
+
+impl CharacterClass {
+    /// Checks whether a character belongs to the class.
+    fn contains(self, c: char) -> bool {
+        match self {
+            CharacterClass::Lowercase => c.is_lowercase(),
+            CharacterClass::Uppercase => c.is_uppercase(),
+            CharacterClass::Digit => c.is_numeric(),
+            CharacterClass::Symbol => !c.is_alphanumeric(),
+        }
+    }
+}
+
+impl fmt::Display for CharacterClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CharacterClass::Lowercase => write!(f, "lowercase"),
+            CharacterClass::Uppercase => write!(f, "uppercase"),
+            CharacterClass::Digit => write!(f, "digit"),
+            CharacterClass::Symbol => write!(f, "symbol"),
+        }
+    }
+}
+
+impl FromStr for CharacterClass {
+    type Err = AuthError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lowercase" => Ok(CharacterClass::Lowercase),
+            "uppercase" => Ok(CharacterClass::Uppercase),
+            "digit" => Ok(CharacterClass::Digit),
+            "symbol" => Ok(CharacterClass::Symbol),
+            _ => Err(AuthError::InvalidRequest(format!(
+                "unknown character class {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// The rules passwords must follow, checked whenever a password is set.
+pub struct PasswordPolicy {
+    min_length: usize,
+    /// Long passwords are rejected, as Argon2id hashing time grows with the password length.
+    max_length: usize,
+    required_classes: Vec<CharacterClass>,
+    /// Common passwords, in lowercase.
+    deny_list: HashSet<String>,
+    breached_passwords: Option<BreachedPasswords>,
+}
+
+impl PasswordPolicy {
+    /// Creates a new PasswordPolicy instance.
+    ///
+    /// # Parameters
+    /// The minimum and maximum number of characters, the classes of characters every password
+    /// must contain, a list of passwords that are too common to be used, and optionally an index
+    /// of passwords from known data breaches, which cannot be used either.
+    pub fn new(
+        min_length: usize,
+        max_length: usize,
+        required_classes: Vec<CharacterClass>,
+        deny_list: HashSet<String>,
+        breached_passwords: Option<BreachedPasswords>,
+    ) -> Self {
+        Self {
+            min_length,
+            max_length,
+            required_classes,
+            deny_list: deny_list
+                .into_iter()
+                .map(|password| password.to_lowercase())
+                .collect(),
+            breached_passwords,
+        }
+    }
+
+    /// Loads a deny-list of common passwords, one per line.
+    ///
+    /// Blank lines, and lines starting with `#`, are skipped.
+    pub fn load_deny_list(path: &Path) -> Result<HashSet<String>, AuthError> {
+        let file = fs::read_to_string(path).map_err(|e| AuthError::Unknown(Box::new(e)))?;
+
+        Ok(file
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| line.to_lowercase())
+            .collect())
+    }
+
+    /// Checks a password against the policy.
+    ///
+    /// # Parameters
+    /// The name of the request field the password was sent in, the password, and the email
+    /// address and given name of the account, which cannot be used as the password.
+    ///
+    /// # Return Values
+    /// ## Success
+    /// Ok, but empty, if the password follows every rule.
+    ///
+    /// ## Errors
+    /// `AuthError::InvalidFields`, with a violation for each broken rule. Or if the breached
+    /// passwords index could not be read.
+    pub async fn check(
+        &self,
+        field: &str,
+        password: &str,
+        email: &str,
+        given_name: &str,
+    ) -> Result<(), AuthError> {
+        let mut violations = Vec::new();
+        let length = password.chars().count();
+
+        if length < self.min_length {
+            violations.push(FieldViolation::new(
+                field,
+                &format!("must be at least {} characters", self.min_length),
+            ));
+        }
+        if length > self.max_length {
+            violations.push(FieldViolation::new(
+                field,
+                &format!("must be at most {} characters", self.max_length),
+            ));
+        }
+
+        for class in &self.required_classes {
+            if !password.chars().any(|c| class.contains(c)) {
+                violations.push(FieldViolation::new(
+                    field,
+                    &format!("must contain at least one {} character", class),
+                ));
+            }
+        }
+
+        let lowercase = password.to_lowercase();
+        let email = email.to_lowercase();
+        let email_name = email.split('@').next().unwrap_or_default();
+        if lowercase == email || lowercase == email_name {
+            violations.push(FieldViolation::new(field, "must not be the email address"));
+        }
+        if !given_name.is_empty() && lowercase == given_name.to_lowercase() {
+            violations.push(FieldViolation::new(field, "must not be the given name"));
+        }
+
+        if self.deny_list.contains(&lowercase) {
+            violations.push(FieldViolation::new(field, "is too common"));
+        } else if self.is_breached(password).await? {
+            violations.push(FieldViolation::new(field, "has appeared in a data breach"));
+        }
+
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(AuthError::InvalidFields(violations))
+        }
+    }
+
+    /// Checks whether a password appears in the breached passwords index, if there is one.
+    pub async fn is_breached(&self, password: &str) -> Result<bool, AuthError> {
+        match &self.breached_passwords {
+            Some(breached_passwords) => breached_passwords.contains(password).await,
+            None => Ok(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn policy() -> PasswordPolicy {
+        PasswordPolicy::new(
+            8,
+            16,
+            vec![CharacterClass::Uppercase, CharacterClass::Digit],
+            ["Password1"].iter().map(|p| p.to_string()).collect(),
+            None,
+        )
+    }
+
+    async fn violations(password: &str) -> Vec<String> {
+        match policy()
+            .check("password", password, "jane.doe@example.com", "Jane")
+            .await
+        {
+            Ok(()) => Vec::new(),
+            Err(AuthError::InvalidFields(violations)) => violations
+                .into_iter()
+                .map(|violation| violation.description)
+                .collect(),
+            Err(e) => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_valid_password_is_accepted() {
+        assert!(violations("Correct7Horse").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_every_broken_rule_is_reported() {
+        assert_eq!(
+            violations("short").await,
+            vec![
+                "must be at least 8 characters",
+                "must contain at least one uppercase character",
+                "must contain at least one digit character",
+            ]
+        );
+        assert_eq!(
+            violations("Much2LongForThePolicy").await,
+            vec!["must be at most 16 characters"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_account_details_and_common_passwords_are_rejected() {
+        assert_eq!(violations("PASSWORD1").await, vec!["is too common"]);
+        assert!(violations("Jane.Doe@Example.com")
+            .await
+            .contains(&"must not be the email address".to_string()));
+        assert!(violations("jane")
+            .await
+            .contains(&"must not be the given name".to_string()));
+    }
+}
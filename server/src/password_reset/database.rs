@@ -0,0 +1,91 @@
+use super::model::{PasswordReset, PasswordResetCreate, PasswordResetRepository};
+
+use crate::account::model::AccountId;
+use crate::database::postgres::QueryResultExt;
+use crate::error::AuthError;
+use crate::hashing::TokenDigest;
+
+use async_trait::async_trait;
+use chrono::Utc;
+use sqlx::PgConnection;
+
+#[async_trait]
+impl PasswordResetRepository for PgConnection {
+    async fn issue_password_reset(&mut self, account_id: AccountId) -> Result<String, AuthError> {
+        let password_reset = PasswordResetCreate::new(account_id);
+
+        sqlx::query!(
+            r#"
+            INSERT INTO password_resets (account_id, token_hash, issued_at, expires)
+            VALUES($1, $2, $3, $4)
+            "#,
+            password_reset.account_id,
+            password_reset.token_hash,
+            password_reset.issued_at,
+            password_reset.expires,
+        )
+        .execute(self)
+        .await
+        .on_foreign_key_violation(|| AuthError::AccountNotFound(password_reset.account_id))?;
+
+        Ok(password_reset.token)
+    }
+
+    async fn use_password_reset(&mut self, token: &str) -> Result<PasswordReset, AuthError> {
+        let now = Utc::now().naive_utc();
+
+        // Only use the token if it is still unused, so concurrent requests cannot both use it.
+        let password_reset = sqlx::query_as!(
+            PasswordReset,
+            r#"
+            UPDATE password_resets SET used_at = $2
+            WHERE token_hash = $1 AND used_at IS NULL AND expires > $2
+            RETURNING *
+            "#,
+            TokenDigest::digest(token),
+            now,
+        )
+        .fetch_optional(&mut *self)
+        .await?
+        .ok_or(AuthError::InvalidPasswordResetToken)?;
+
+        // Any other links that were sent must not be usable once the password has been reset.
+        sqlx::query!(
+            r#"
+            UPDATE password_resets SET used_at = $2
+            WHERE account_id = $1 AND used_at IS NULL
+            "#,
+            password_reset.account_id,
+            now,
+        )
+        .execute(self)
+        .await?;
+
+        Ok(password_reset)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::database::postgres::{register_test_account, test_transaction};
+
+    #[tokio::test]
+    async fn test_using_a_reset_token_uses_every_token() {
+        let mut tx = test_transaction().await;
+        let account = register_test_account(&mut tx, None).await;
+
+        let first = tx.issue_password_reset(account.id).await.unwrap();
+        let second = tx.issue_password_reset(account.id).await.unwrap();
+        let password_reset = tx.use_password_reset(&second).await.unwrap();
+        assert_eq!(password_reset.account_id, account.id);
+
+        for token in &[first, second] {
+            assert!(matches!(
+                tx.use_password_reset(token).await,
+                Err(AuthError::InvalidPasswordResetToken)
+            ));
+        }
+    }
+}
@@ -0,0 +1,7 @@
+/// Password resets allow users who have forgotten their password to set a new one.
+///
+/// A short-lived, single-use token is emailed to the account's address, and presenting it back
+/// allows a new password to be set. Only a keyed digest of each token is stored.
+///
+pub mod database;
+pub mod model;
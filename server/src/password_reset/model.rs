@@ -0,0 +1,88 @@
+/// Data models for password reset tokens.
+use crate::account::model::AccountId;
+use crate::error::AuthError;
+use crate::hashing::TokenDigest;
+
+use async_trait::async_trait;
+use chrono::naive::NaiveDateTime;
+use chrono::{Duration, Utc};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+
+/// Define a custom type for Password Reset IDs.
+pub type PasswordResetId = i32;
+
+/// Define the number of characters a reset token should contain.
+const TOKEN_LENGTH: usize = 64;
+
+/// Define how long a reset token can be used for, in minutes.
+const TOKEN_EXPIRY_MINUTES: i64 = 30;
+
+#[derive(Debug)]
+pub struct PasswordReset {
+    pub id: PasswordResetId,
+    pub account_id: AccountId,
+    pub token_hash: Vec<u8>,
+    pub issued_at: NaiveDateTime,
+    pub expires: NaiveDateTime,
+    pub used_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug)]
+pub struct PasswordResetCreate {
+    pub account_id: AccountId,
+    pub token: String,
+    pub token_hash: Vec<u8>,
+    pub issued_at: NaiveDateTime,
+    pub expires: NaiveDateTime,
+}
+
+impl PasswordResetCreate {
+    /// Generates a new reset token for an account, with the default expiry time.
+    pub fn new(account_id: AccountId) -> Self {
+        let issued_at = Utc::now();
+        let expires = issued_at + Duration::minutes(TOKEN_EXPIRY_MINUTES);
+        let token: String = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(TOKEN_LENGTH)
+            .collect();
+
+        PasswordResetCreate {
+            account_id,
+            token_hash: TokenDigest::digest(&token),
+            token,
+            issued_at: issued_at.naive_utc(),
+            expires: expires.naive_utc(),
+        }
+    }
+}
+
+#[async_trait]
+pub(crate) trait PasswordResetRepository {
+    /// Issues a new reset token for an account.
+    ///
+    /// # Parameters
+    /// The ID of the account whose password will be reset.
+    ///
+    /// # Returns
+    /// ## Success
+    /// The plain-text token, which is not stored and must be sent to the user.
+    ///
+    /// ## Errors
+    /// If the account was not found, or a database failure occured.
+    async fn issue_password_reset(&mut self, account_id: AccountId) -> Result<String, AuthError>;
+
+    /// Uses a reset token, along with every other unused token for the same account.
+    ///
+    /// # Parameters
+    /// The reset token presented by the user.
+    ///
+    /// # Returns
+    /// ## Success
+    /// The PasswordReset structure the token was issued for.
+    ///
+    /// ## Errors
+    /// If the token was not found, has expired or has already been used, or a database failure
+    /// occured.
+    async fn use_password_reset(&mut self, token: &str) -> Result<PasswordReset, AuthError>;
+}
@@ -0,0 +1,398 @@
+/// Limits how often each client can call each gRPC method.
+///
+/// Every method has a token bucket per client IP address: each request takes a token, and tokens
+/// are refilled at a steady rate up to the bucket's size, which allows short bursts. Requests
+/// that find the bucket empty are refused with `RESOURCE_EXHAUSTED` before any other work is done.
+///
+/// Each RPC handler checks its own request, as only the handlers are given the peer's address by
+/// tonic. Methods are named by the handlers rather than by the request path, so a client cannot
+/// create buckets or metrics for methods that do not exist.
+///
+/// The buckets are kept in memory, so each replica of the service limits requests separately.
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::error::AuthError;
+
+use serde::Deserialize;
+use tonic::Request;
+
+/// Define how often buckets that have refilled are forgotten.
+const PRUNE_INTERVAL_SECS: u64 = 60;
+
+/// Define the metadata header proxies send the client's IP address in.
+const FORWARDED_FOR_HEADER: &str = "x-forwarded-for";
+
+/// Defines the limit of one method.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct BucketConfig {
+    /// The most requests that can be made at once, after a pause.
+    pub burst: u32,
+    /// The number of requests that can be made each minute, on average.
+    pub per_minute: u32,
+}
+
+impl BucketConfig {
+    fn tokens_per_second(&self) -> f64 {
+        f64::from(self.per_minute) / 60.0
+    }
+}
+
+/// Defines the rate limits of every method.
+#[derive(Debug, Deserialize)]
+pub struct RateLimitConfig {
+    /// The proxies trusted to send the client's IP address in the `x-forwarded-for` header.
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpAddr>,
+    /// The limit of methods without their own limit, which are unlimited if this is unset.
+    #[serde(default)]
+    pub default: Option<BucketConfig>,
+    /// The limits of methods, by their name, e.g. `RegisterUser`.
+    #[serde(default)]
+    pub methods: HashMap<String, BucketConfig>,
+}
+
+impl RateLimitConfig {
+    /// Loads the rate limits from a JSON file.
+    pub fn load(path: &Path) -> Result<Self, AuthError> {
+        let file = fs::read(path).map_err(|e| AuthError::Unknown(Box::new(e)))?;
+        serde_json::from_slice(&file).map_err(|e| AuthError::Unknown(Box::new(e)))
+    }
+
+    fn bucket_config(&self, method: &str) -> Option<&BucketConfig> {
+        self.methods.get(method).or_else(|| self.default.as_ref())
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl Bucket {
+    /// Adds the tokens refilled since the bucket was last updated.
+    fn refill(&mut self, config: &BucketConfig, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated_at).as_secs_f64();
+        self.tokens =
+            (self.tokens + elapsed * config.tokens_per_second()).min(f64::from(config.burst));
+        self.updated_at = now;
+    }
+}
+
+/// Counts the requests to a method that were allowed and limited.
+#[derive(Debug, Default)]
+struct MethodMetrics {
+    allowed: u64,
+    limited: u64,
+}
+
+/// The token buckets of every client, shared by every connection.
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    /// The buckets by method and client IP address. Requests without a known IP address share
+    /// a bucket.
+    buckets: Mutex<HashMap<(&'static str, Option<IpAddr>), Bucket>>,
+    metrics: Mutex<HashMap<&'static str, MethodMetrics>>,
+}
+
+impl RateLimiter {
+    /// Creates a new RateLimiter instance, with every bucket full.
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            buckets: Mutex::new(HashMap::new()),
+            metrics: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token for a request from its client's bucket for the method.
+    ///
+    /// # Parameters
+    /// The name of the method, e.g. `RegisterUser`, the client's IP address, and the current time.
+    ///
+    /// # Return Values
+    /// ## Success
+    /// Ok, but empty, if the request is allowed.
+    ///
+    /// ## Errors
+    /// `AuthError::TooManyAttempts` with the time until a token is refilled, if the bucket is
+    /// empty.
+    pub fn check(
+        &self,
+        method: &'static str,
+        client_ip: Option<IpAddr>,
+        now: Instant,
+    ) -> Result<(), AuthError> {
+        let config = match self.config.bucket_config(method) {
+            Some(config) => config,
+            None => return Ok(()),
+        };
+
+        let retry_delay = {
+            let mut buckets = self
+                .buckets
+                .lock()
+                .expect("rate limit buckets were poisoned");
+            let bucket = buckets
+                .entry((method, client_ip))
+                .or_insert_with(|| Bucket {
+                    tokens: f64::from(config.burst),
+                    updated_at: now,
+                });
+            bucket.refill(config, now);
+
+            if bucket.tokens >= 1.0 {
+                bucket.tokens -= 1.0;
+                None
+            } else {
+                Some((1.0 - bucket.tokens) / config.tokens_per_second())
+            }
+        };
+
+        let mut metrics = self
+            .metrics
+            .lock()
+            .expect("rate limit metrics were poisoned");
+        let method_metrics = metrics.entry(method).or_default();
+        match retry_delay {
+            None => {
+                method_metrics.allowed += 1;
+                Ok(())
+            }
+            Some(retry_delay) => {
+                method_metrics.limited += 1;
+                Err(AuthError::TooManyAttempts(chrono::Duration::seconds(
+                    retry_delay.ceil() as i64,
+                )))
+            }
+        }
+    }
+
+    /// Gets the IP address of the client that sent a request.
+    ///
+    /// This is the peer's address, unless the peer is a trusted proxy, in which case it is the
+    /// last address in the `x-forwarded-for` header that was not added by a trusted proxy.
+    pub fn client_ip(
+        &self,
+        peer_ip: Option<IpAddr>,
+        forwarded_for: Option<&str>,
+    ) -> Option<IpAddr> {
+        let mut client_ip = peer_ip?;
+        if !self.is_trusted_proxy(&client_ip) {
+            return Some(client_ip);
+        }
+
+        // Each proxy appends the address it received the request from, so the addresses are read
+        // from the right, until one is not a trusted proxy.
+        for forwarded_ip in forwarded_for.unwrap_or_default().rsplit(',') {
+            match forwarded_ip.trim().parse() {
+                Ok(forwarded_ip) => client_ip = forwarded_ip,
+                Err(_) => break,
+            }
+            if !self.is_trusted_proxy(&client_ip) {
+                break;
+            }
+        }
+
+        Some(client_ip)
+    }
+
+    /// Checks whether an IP address is one of the trusted proxies.
+    pub fn is_trusted_proxy(&self, ip: &IpAddr) -> bool {
+        self.config.trusted_proxies.contains(ip)
+    }
+
+    /// Gets the IP address of the client that sent a gRPC request, see `client_ip`.
+    pub fn request_client_ip<T>(&self, request: &Request<T>) -> Option<IpAddr> {
+        let forwarded_for = request
+            .metadata()
+            .get(FORWARDED_FOR_HEADER)
+            .and_then(|value| value.to_str().ok());
+
+        self.client_ip(request.remote_addr().map(|addr| addr.ip()), forwarded_for)
+    }
+
+    /// Forgets the buckets that have refilled, which are the same as new buckets.
+    pub fn prune(&self, now: Instant) {
+        let mut buckets = self
+            .buckets
+            .lock()
+            .expect("rate limit buckets were poisoned");
+        let config = &self.config;
+        buckets.retain(|(method, _), bucket| match config.bucket_config(method) {
+            Some(bucket_config) => {
+                bucket.refill(bucket_config, now);
+                bucket.tokens < f64::from(bucket_config.burst)
+            }
+            None => false,
+        });
+    }
+
+    /// Renders the metrics in the Prometheus text format.
+    pub fn render_metrics(&self) -> String {
+        let mut rendered = String::new();
+
+        rendered.push_str(
+            "# HELP auth_rate_limit_requests_total Requests checked by the rate limiter.\n\
+             # TYPE auth_rate_limit_requests_total counter\n",
+        );
+        let metrics = self
+            .metrics
+            .lock()
+            .expect("rate limit metrics were poisoned");
+        let mut methods: Vec<_> = metrics.iter().collect();
+        methods.sort_by_key(|(method, _)| **method);
+        for (method, method_metrics) in methods {
+            for (result, count) in &[
+                ("allowed", method_metrics.allowed),
+                ("limited", method_metrics.limited),
+            ] {
+                // Writing to a String cannot fail.
+                let _ = writeln!(
+                    rendered,
+                    "auth_rate_limit_requests_total{{method=\"{}\",result=\"{}\"}} {}",
+                    escape_label_value(method),
+                    result,
+                    count
+                );
+            }
+        }
+
+        let buckets = self
+            .buckets
+            .lock()
+            .expect("rate limit buckets were poisoned");
+        let _ = write!(
+            rendered,
+            "# HELP auth_rate_limit_buckets Token buckets of clients that have made requests.\n\
+             # TYPE auth_rate_limit_buckets gauge\n\
+             auth_rate_limit_buckets {}\n",
+            buckets.len()
+        );
+
+        rendered
+    }
+}
+
+/// Periodically forgets the buckets that have refilled.
+///
+/// This never returns, so it should be spawned as a separate task.
+pub async fn run_pruning(limiter: Arc<RateLimiter>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(PRUNE_INTERVAL_SECS));
+
+    loop {
+        interval.tick().await;
+        limiter.prune(Instant::now());
+    }
+}
+
+/// Escapes a Prometheus label value, so that it cannot end the label or the line.
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CLIENT: &str = "203.0.113.7";
+    const PROXY: &str = "10.0.0.1";
+
+    fn limiter() -> RateLimiter {
+        let mut methods = HashMap::new();
+        methods.insert(
+            "RegisterUser".to_string(),
+            BucketConfig {
+                burst: 2,
+                per_minute: 6,
+            },
+        );
+
+        RateLimiter::new(RateLimitConfig {
+            trusted_proxies: vec![PROXY.parse().unwrap()],
+            default: None,
+            methods,
+        })
+    }
+
+    fn ip(ip: &str) -> Option<IpAddr> {
+        Some(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn test_bucket_allows_a_burst_then_refills() {
+        let limiter = limiter();
+        let now = Instant::now();
+
+        assert!(limiter.check("RegisterUser", ip(CLIENT), now).is_ok());
+        assert!(limiter.check("RegisterUser", ip(CLIENT), now).is_ok());
+        match limiter.check("RegisterUser", ip(CLIENT), now) {
+            Err(AuthError::TooManyAttempts(retry_delay)) => {
+                assert_eq!(retry_delay, chrono::Duration::seconds(10))
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        // Other clients and methods without a limit are not affected.
+        assert!(limiter.check("RegisterUser", ip(PROXY), now).is_ok());
+        assert!(limiter.check("GetJwks", ip(CLIENT), now).is_ok());
+
+        let later = now + Duration::from_secs(10);
+        assert!(limiter.check("RegisterUser", ip(CLIENT), later).is_ok());
+        assert!(limiter.check("RegisterUser", ip(CLIENT), later).is_err());
+    }
+
+    #[test]
+    fn test_forwarded_for_is_only_trusted_from_proxies() {
+        let limiter = limiter();
+
+        assert_eq!(limiter.client_ip(ip(CLIENT), Some("192.0.2.1")), ip(CLIENT));
+        assert_eq!(
+            limiter.client_ip(
+                ip(PROXY),
+                Some(&format!("192.0.2.1, {}, {}", CLIENT, PROXY))
+            ),
+            ip(CLIENT)
+        );
+        assert_eq!(limiter.client_ip(ip(PROXY), Some("not an ip")), ip(PROXY));
+        assert_eq!(limiter.client_ip(ip(PROXY), None), ip(PROXY));
+        assert_eq!(limiter.client_ip(None, Some(CLIENT)), None);
+    }
+
+    #[test]
+    fn test_metrics_count_allowed_and_limited_requests() {
+        let limiter = limiter();
+        let now = Instant::now();
+        for _ in 0..3 {
+            let _ = limiter.check("RegisterUser", ip(CLIENT), now);
+        }
+
+        let metrics = limiter.render_metrics();
+        assert!(metrics.contains(
+            "auth_rate_limit_requests_total{method=\"RegisterUser\",result=\"allowed\"} 2"
+        ));
+        assert!(metrics.contains(
+            "auth_rate_limit_requests_total{method=\"RegisterUser\",result=\"limited\"} 1"
+        ));
+        assert!(metrics.contains("auth_rate_limit_buckets 1"));
+
+        limiter.prune(now + Duration::from_secs(60));
+        assert!(limiter
+            .render_metrics()
+            .contains("auth_rate_limit_buckets 0"));
+    }
+
+    #[test]
+    fn test_label_values_cannot_end_the_label() {
+        assert_eq!(escape_label_value("a\"} 1\nb\\"), "a\\\"} 1\\nb\\\\");
+    }
+}
@@ -1,35 +1,107 @@
-use super::model::{RefreshToken, RefreshTokenCreate, RefreshTokenRepository};
+use super::model::{
+    IssuedRefreshToken, RefreshToken, RefreshTokenCreate, RefreshTokenId, RefreshTokenRepository,
+    TOKEN_PREFIX_LENGTH,
+};
 
 use crate::account::model::AccountId;
+use crate::database::postgres::QueryResultExt;
 use crate::error::AuthError;
+use crate::hashing::TokenDigest;
 
 use async_trait::async_trait;
 use sqlx::PgConnection;
+use uuid::Uuid;
 
 #[async_trait]
 impl RefreshTokenRepository for PgConnection {
     async fn issue_refresh_token(
         &mut self,
         account_id: AccountId,
-    ) -> Result<RefreshToken, AuthError> {
-        let token_create = RefreshTokenCreate::new(account_id);
+    ) -> Result<IssuedRefreshToken, AuthError> {
+        insert_refresh_token(self, RefreshTokenCreate::new(account_id)).await
+    }
+
+    async fn get_refresh_token(&mut self, token: &str) -> Result<RefreshToken, AuthError> {
+        let token_prefix = token
+            .get(..TOKEN_PREFIX_LENGTH)
+            .ok_or(AuthError::InvalidRefreshToken)?;
 
-        let refresh_token = sqlx::query_as!(
+        let candidates = sqlx::query_as!(
             RefreshToken,
             r#"
-            INSERT INTO refresh_tokens (account_id, issued_at, expires, token)
-            VALUES ($1, $2, $3, $4)
-            RETURNING *
+            SELECT * FROM refresh_tokens WHERE token_prefix = $1
             "#,
-            account_id,
-            token_create.issued_at,
-            token_create.expires,
-            token_create.token,
+            token_prefix
         )
-        .fetch_one(self)
+        .fetch_all(self)
         .await?;
 
-        Ok(refresh_token)
+        // Check every candidate rather than stopping at the first match, so the time taken does
+        // not reveal which one matched.
+        let mut refresh_token = None;
+        for candidate in candidates {
+            if TokenDigest::verify(token, &candidate.token_hash) && refresh_token.is_none() {
+                refresh_token = Some(candidate);
+            }
+        }
+
+        refresh_token.ok_or(AuthError::InvalidRefreshToken)
+    }
+
+    async fn rotate_refresh_token(
+        &mut self,
+        refresh_token: &RefreshToken,
+    ) -> Result<IssuedRefreshToken, AuthError> {
+        let revocation_time = chrono::Utc::now().naive_utc();
+        // Only revoke the token if it is still active, so concurrent rotations cannot both succeed.
+        let revoked = sqlx::query!(
+            r#"
+            UPDATE refresh_tokens SET revoked = true, revocation_time = $1
+            WHERE id = $2 AND revoked = false
+            "#,
+            revocation_time,
+            refresh_token.id,
+        )
+        .execute(&mut *self)
+        .await?;
+
+        if revoked == 0 {
+            return Err(AuthError::InvalidRefreshToken);
+        }
+
+        insert_refresh_token(self, RefreshTokenCreate::rotate(refresh_token)).await
+    }
+
+    async fn is_refresh_token_rotated(
+        &mut self,
+        refresh_token_id: RefreshTokenId,
+    ) -> Result<bool, AuthError> {
+        let child = sqlx::query!(
+            r#"
+            SELECT id FROM refresh_tokens WHERE parent_id = $1
+            "#,
+            refresh_token_id,
+        )
+        .fetch_optional(self)
+        .await?;
+
+        Ok(child.is_some())
+    }
+
+    async fn revoke_token_family(&mut self, family: Uuid) -> Result<(), AuthError> {
+        let revocation_time = chrono::Utc::now().naive_utc();
+        sqlx::query!(
+            r#"
+            UPDATE refresh_tokens SET revoked = true, revocation_time = $1
+            WHERE family = $2 AND revoked = false
+            "#,
+            revocation_time,
+            family,
+        )
+        .execute(self)
+        .await?;
+
+        Ok(())
     }
 
     async fn revoke_all_tokens_for_account(
@@ -50,4 +122,139 @@ impl RefreshTokenRepository for PgConnection {
 
         Ok(())
     }
+
+    async fn revoke_other_tokens_for_account(
+        &mut self,
+        account_id: AccountId,
+        family: Uuid,
+    ) -> Result<(), AuthError> {
+        let revocation_time = chrono::Utc::now().naive_utc();
+        sqlx::query!(
+            r#"
+            UPDATE refresh_tokens SET revoked = true, revocation_time = $1
+            WHERE account_id = $2 AND family <> $3 AND revoked = false
+            "#,
+            revocation_time,
+            account_id,
+            family,
+        )
+        .execute(self)
+        .await?;
+
+        Ok(())
+    }
+}
+
+/// Inserts a new refresh token into the database, storing only the digest of its token.
+async fn insert_refresh_token(
+    conn: &mut PgConnection,
+    token_create: RefreshTokenCreate,
+) -> Result<IssuedRefreshToken, AuthError> {
+    let refresh_token = sqlx::query_as!(
+        RefreshToken,
+        r#"
+        INSERT INTO refresh_tokens
+            (account_id, issued_at, expires, family, parent_id, token_prefix, token_hash)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        RETURNING *
+        "#,
+        token_create.account_id,
+        token_create.issued_at,
+        token_create.expires,
+        token_create.family,
+        token_create.parent_id,
+        token_create.token_prefix,
+        token_create.token_hash,
+    )
+    .fetch_one(conn)
+    .await
+    .on_foreign_key_violation(|| AuthError::AccountNotFound(token_create.account_id))?;
+
+    Ok(IssuedRefreshToken {
+        refresh_token,
+        token: token_create.token,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::database::postgres::{register_test_account, test_transaction};
+
+    #[tokio::test]
+    async fn test_rotated_token_is_revoked() {
+        let mut tx = test_transaction().await;
+        let account = register_test_account(&mut tx, None).await;
+
+        let issued = tx.issue_refresh_token(account.id).await.unwrap();
+        let refresh_token = tx.get_refresh_token(&issued.token).await.unwrap();
+        assert_eq!(refresh_token.id, issued.refresh_token.id);
+        assert!(!tx.is_refresh_token_rotated(refresh_token.id).await.unwrap());
+
+        let rotated = tx.rotate_refresh_token(&refresh_token).await.unwrap();
+        assert_eq!(rotated.refresh_token.family, refresh_token.family);
+        assert_eq!(rotated.refresh_token.parent_id, Some(refresh_token.id));
+        assert!(tx.is_refresh_token_rotated(refresh_token.id).await.unwrap());
+        assert!(tx.get_refresh_token(&issued.token).await.unwrap().revoked);
+
+        assert!(matches!(
+            tx.rotate_refresh_token(&refresh_token).await,
+            Err(AuthError::InvalidRefreshToken)
+        ));
+        assert!(matches!(
+            tx.get_refresh_token("unknown").await,
+            Err(AuthError::InvalidRefreshToken)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_reused_token_revokes_its_family() {
+        let mut tx = test_transaction().await;
+        let account = register_test_account(&mut tx, None).await;
+        let reused = tx.issue_refresh_token(account.id).await.unwrap();
+        let other_session = tx.issue_refresh_token(account.id).await.unwrap();
+        let rotated = tx
+            .rotate_refresh_token(&reused.refresh_token)
+            .await
+            .unwrap();
+
+        // Presenting a rotated token again is reuse, so its whole family is revoked.
+        let presented = tx.get_refresh_token(&reused.token).await.unwrap();
+        assert!(presented.revoked);
+        assert!(tx.is_refresh_token_rotated(presented.id).await.unwrap());
+        tx.revoke_token_family(presented.family).await.unwrap();
+
+        assert!(tx.get_refresh_token(&rotated.token).await.unwrap().revoked);
+        assert!(
+            !tx.get_refresh_token(&other_session.token)
+                .await
+                .unwrap()
+                .revoked
+        );
+    }
+
+    #[tokio::test]
+    async fn test_revoking_tokens() {
+        let mut tx = test_transaction().await;
+        let account = register_test_account(&mut tx, None).await;
+        let first = tx.issue_refresh_token(account.id).await.unwrap();
+        let second = tx.issue_refresh_token(account.id).await.unwrap();
+        let third = tx.issue_refresh_token(account.id).await.unwrap();
+
+        tx.revoke_token_family(first.refresh_token.family)
+            .await
+            .unwrap();
+        assert!(tx.get_refresh_token(&first.token).await.unwrap().revoked);
+        assert!(!tx.get_refresh_token(&second.token).await.unwrap().revoked);
+
+        tx.revoke_other_tokens_for_account(account.id, third.refresh_token.family)
+            .await
+            .unwrap();
+        assert!(tx.get_refresh_token(&second.token).await.unwrap().revoked);
+        assert!(!tx.get_refresh_token(&third.token).await.unwrap().revoked);
+
+        tx.revoke_all_tokens_for_account(account.id).await.unwrap();
+        assert!(tx.get_refresh_token(&third.token).await.unwrap().revoked);
+    }
 }
@@ -1,12 +1,14 @@
 /// Data models for Refresh Tokens.
 use crate::account::model::AccountId;
 use crate::error::AuthError;
+use crate::hashing::TokenDigest;
 
 use async_trait::async_trait;
 use chrono::naive::NaiveDateTime;
 use chrono::{Duration, Utc};
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
+use uuid::Uuid;
 
 /// Define a custom type for Refresh Token IDs.
 pub type RefreshTokenId = i32;
@@ -14,6 +16,9 @@ pub type RefreshTokenId = i32;
 /// Defne the number of characters a refresh token should contain.
 const TOKEN_LENGTH: usize = 256;
 
+/// Define the number of leading characters of a refresh token stored in plain-text for lookups.
+pub(super) const TOKEN_PREFIX_LENGTH: usize = 8;
+
 /// Define the default expiry length of a refresh token, in hours.
 const TOKEN_EXPIRY_HOURS: i64 = 7 * 24; // 168 hours, or 7 days.
 
@@ -25,6 +30,26 @@ pub struct RefreshToken {
     pub expires: NaiveDateTime,
     pub revoked: bool,
     pub revocation_time: Option<NaiveDateTime>,
+    pub family: Uuid,
+    pub parent_id: Option<RefreshTokenId>,
+    pub token_prefix: String,
+    pub token_hash: Vec<u8>,
+}
+
+impl RefreshToken {
+    /// Checks whether the refresh token can still be used, i.e. it has not expired or been revoked.
+    pub fn is_active(&self) -> bool {
+        !self.revoked && self.expires > Utc::now().naive_utc()
+    }
+}
+
+/// A newly issued refresh token, along with its plain-text token.
+///
+/// Only a digest of the token is stored, so this is the only time the token is available and it
+/// must be returned to the client.
+#[derive(Debug)]
+pub struct IssuedRefreshToken {
+    pub refresh_token: RefreshToken,
     pub token: String,
 }
 
@@ -34,22 +59,40 @@ pub struct RefreshTokenCreate {
     pub issued_at: NaiveDateTime,
     pub expires: NaiveDateTime,
     pub token: String,
+    pub family: Uuid,
+    pub parent_id: Option<RefreshTokenId>,
+    pub token_prefix: String,
+    pub token_hash: Vec<u8>,
 }
 
 impl RefreshTokenCreate {
-    /// Generates a new refresh token, with the default expiry time.
+    /// Generates a new refresh token in a new token family, with the default expiry time.
     pub fn new(account_id: AccountId) -> Self {
+        Self::with_family(account_id, Uuid::new_v4(), None)
+    }
+
+    /// Generates the replacement for a rotated refresh token, in the same token family.
+    pub fn rotate(parent: &RefreshToken) -> Self {
+        Self::with_family(parent.account_id, parent.family, Some(parent.id))
+    }
+
+    fn with_family(account_id: AccountId, family: Uuid, parent_id: Option<RefreshTokenId>) -> Self {
         let issued_at = Utc::now();
         let expires = issued_at + Duration::hours(TOKEN_EXPIRY_HOURS);
+        let token: String = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(TOKEN_LENGTH)
+            .collect();
 
         RefreshTokenCreate {
             account_id,
             issued_at: issued_at.naive_utc(),
             expires: expires.naive_utc(),
-            token: thread_rng()
-                .sample_iter(&Alphanumeric)
-                .take(TOKEN_LENGTH)
-                .collect(),
+            token_prefix: token[..TOKEN_PREFIX_LENGTH].to_string(),
+            token_hash: TokenDigest::digest(&token),
+            token,
+            family,
+            parent_id,
         }
     }
 }
@@ -63,14 +106,79 @@ pub(crate) trait RefreshTokenRepository {
     ///
     /// # Returns
     /// ## Success
-    /// The newly created RefreshToken structure.
+    /// The newly created RefreshToken structure, along with its plain-text token.
     ///
     /// ## Errors
     /// If the account was not found, or a database failure occured.
     async fn issue_refresh_token(
         &mut self,
         account_id: AccountId,
-    ) -> Result<RefreshToken, AuthError>;
+    ) -> Result<IssuedRefreshToken, AuthError>;
+
+    /// Gets an issued refresh token by its token string.
+    ///
+    /// # Parameters
+    /// The refresh token string presented by the client.
+    ///
+    /// The token is found by its plain-text prefix, and then compared against the stored digests
+    /// in constant time.
+    ///
+    /// # Returns
+    /// ## Success
+    /// The matching RefreshToken structure. This may have expired or been revoked.
+    ///
+    /// ## Errors
+    /// If the token was not found, or a database failure occured.
+    async fn get_refresh_token(&mut self, token: &str) -> Result<RefreshToken, AuthError>;
+
+    /// Rotates a refresh token, revoking it and issuing its replacement in the same token family.
+    ///
+    /// This must be called in a transaction, so the token is not left revoked if issuing its
+    /// replacement fails.
+    ///
+    /// # Parameters
+    /// The refresh token to rotate.
+    ///
+    /// # Returns
+    /// ## Success
+    /// The newly created RefreshToken structure, along with its plain-text token.
+    ///
+    /// ## Errors
+    /// If the token had already been revoked (e.g. rotated by a concurrent request), or a database
+    /// failure occured.
+    async fn rotate_refresh_token(
+        &mut self,
+        refresh_token: &RefreshToken,
+    ) -> Result<IssuedRefreshToken, AuthError>;
+
+    /// Checks whether a refresh token has been rotated, i.e. a replacement has been issued for it.
+    ///
+    /// # Parameters
+    /// The ID of the refresh token to check.
+    ///
+    /// # Returns
+    /// ## Success
+    /// `true` if the token has been rotated, `false` otherwise.
+    ///
+    /// ## Errors
+    /// If a database failure occured.
+    async fn is_refresh_token_rotated(
+        &mut self,
+        refresh_token_id: RefreshTokenId,
+    ) -> Result<bool, AuthError>;
+
+    /// Revokes every refresh token in a token family.
+    ///
+    /// # Parameters
+    /// The token family to revoke.
+    ///
+    /// # Returns
+    /// ## Success
+    /// Ok, but empty.
+    ///
+    /// ## Errors
+    /// If a database failure occured.
+    async fn revoke_token_family(&mut self, family: Uuid) -> Result<(), AuthError>;
 
     /// Revokes all refresh tokens issued for an account.
     ///
@@ -87,4 +195,21 @@ pub(crate) trait RefreshTokenRepository {
         &mut self,
         account_id: AccountId,
     ) -> Result<(), AuthError>;
+
+    /// Revokes all refresh tokens issued for an account, except those in one token family.
+    ///
+    /// # Parameters
+    /// The account ID to revoke tokens for, and the token family of the session to keep.
+    ///
+    /// # Returns
+    /// ## Success
+    /// Ok, but empty.
+    ///
+    /// ## Errors
+    /// If a database failure occured.
+    async fn revoke_other_tokens_for_account(
+        &mut self,
+        account_id: AccountId,
+        family: Uuid,
+    ) -> Result<(), AuthError>;
 }
@@ -2,33 +2,107 @@ pub(crate) mod auth {
     tonic::include_proto!("com.service.auth");
 }
 
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Instant;
 
 use auth::auth_server::{Auth, AuthServer};
 use auth::{
-    authenticated_user_response::RefreshToken as ProtoRefreshToken, AuthenticatedUserResponse,
-    AuthenticationRequest, RegisterUserRequest,
+    authenticated_user_response::RefreshToken as ProtoRefreshToken,
+    introspect_token_response::InactiveReason, AuditEvent as ProtoAuditEvent,
+    AuthenticatedUserResponse, AuthenticationRequest, AuthorizationUrlRequest,
+    AuthorizationUrlResponse, ChangePasswordRequest, ChangePasswordResponse,
+    CreateInvitationRequest, CreateInvitationResponse, GetJwksRequest, GoogleAuthenticationRequest,
+    Identity as ProtoIdentity, IntrospectTokenRequest, IntrospectTokenResponse,
+    Invitation as ProtoInvitation, Jwk as ProtoJwk, JwkSet as ProtoJwkSet, LinkIdentityRequest,
+    ListAuditEventsRequest, ListAuditEventsResponse, ListIdentitiesRequest, ListIdentitiesResponse,
+    ListInvitationsRequest, ListInvitationsResponse, LogoutEverywhereRequest, LogoutRequest,
+    LogoutResponse, ProviderAuthenticationRequest, RefreshSessionRequest, RegisterUserRequest,
+    RequestPasswordResetRequest, RequestPasswordResetResponse, ResetPasswordRequest,
+    ResetPasswordResponse, RevokeInvitationRequest, RevokeInvitationResponse,
+    RotateSigningKeyRequest, RotateSigningKeyResponse, SendVerificationEmailRequest,
+    SendVerificationEmailResponse, UnlinkIdentityRequest, UnlinkIdentityResponse,
+    UnlockAccountRequest, UnlockAccountResponse, VerifyAuditLogRequest, VerifyAuditLogResponse,
+    VerifyEmailRequest, VerifyEmailResponse,
 };
 
-use crate::account::model::{AccountAuthenticate, AccountRegister, AccountRepository};
+use crate::account::model::{
+    Account, AccountAuthenticate, AccountId, AccountRegister, AccountRepository,
+};
+use crate::audit::chain::{self, ChainVerification};
+use crate::audit::model::{
+    AuditEvent, AuditEventCreate, AuditEventFilter, AuditEventKind, AuditOutcome, AuditRepository,
+    DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE,
+};
 use crate::database::Db;
+use crate::email;
+use crate::email_verification::model::EmailVerificationRepository;
+use crate::error::{AuthError, FieldViolation};
+use crate::hashing::Argon2id;
+use crate::identity::model::{Identity, IdentityCreate, IdentityRepository, PASSWORD_PROVIDER};
+use crate::invitation::model::{Invitation, InvitationCreate, InvitationRepository};
 use crate::jwt;
-use crate::refresh_token::model::RefreshTokenRepository;
+use crate::jwt::model::{Claims, JWT_ISSUER};
+use crate::keys::manager::KeyManager;
+use crate::keys::model::Jwk;
+use crate::login_throttle::model::{LoginThrottlePolicy, LoginThrottleRepository, ThrottleScope};
+use crate::mail::{Email, Mailer};
+use crate::oidc::provider::{ExternalIdentity, ProviderRegistry};
+use crate::password_policy::PasswordPolicy;
+use crate::password_reset::model::PasswordResetRepository;
+use crate::rate_limit::RateLimiter;
+use crate::refresh_token::model::{IssuedRefreshToken, RefreshToken, RefreshTokenRepository};
 
-use sqlx::PgPool;
+use chrono::naive::NaiveDateTime;
+use chrono::{Duration, Utc};
+use jsonwebtoken::decode_header;
+use jsonwebtoken::errors::ErrorKind;
+use sha2::{Digest, Sha256};
+use sqlx::{PgConnection, PgPool};
+use subtle::ConstantTimeEq;
 use tonic::{transport::Server, Request, Response, Status};
 
 /// The AuthService struct is used for handling incoming gRPC requests to this microservice.
 pub struct AuthService {
     pool: PgPool,
+    keys: Arc<KeyManager>,
+    providers: ProviderRegistry,
+    mailer: Arc<Mailer>,
+    password_policy: PasswordPolicy,
+    login_throttle_policy: LoginThrottlePolicy,
+    rate_limiter: Arc<RateLimiter>,
+    /// The SHA-256 digest of the `ADMIN_API_KEY`, so every comparison is of the same length.
+    admin_api_key_digest: Vec<u8>,
 }
 
 impl AuthService {
     /// Creates a new AuthService instance.
-    pub fn new(pool: PgPool) -> AuthService {
-        Self { pool }
+    pub fn new(
+        pool: PgPool,
+        keys: Arc<KeyManager>,
+        providers: ProviderRegistry,
+        mailer: Arc<Mailer>,
+        password_policy: PasswordPolicy,
+        login_throttle_policy: LoginThrottlePolicy,
+        rate_limiter: Arc<RateLimiter>,
+        admin_api_key: &str,
+    ) -> AuthService {
+        Self {
+            pool,
+            keys,
+            providers,
+            mailer,
+            password_policy,
+            login_throttle_policy,
+            rate_limiter,
+            admin_api_key_digest: Sha256::digest(admin_api_key.as_bytes()).to_vec(),
+        }
     }
 
+    /// Runs the gRPC server until it fails.
+    ///
+    /// # Parameters
+    /// The address to listen on.
     pub async fn run_server(self, addr: SocketAddr) -> Result<(), Box<dyn std::error::Error>> {
         Server::builder()
             .add_service(AuthServer::new(self))
@@ -37,6 +111,998 @@ impl AuthService {
 
         Ok(())
     }
+
+    /// Takes a token for a request from its client's bucket for the method, see
+    /// `RateLimiter::check`.
+    fn check_rate_limit<T>(
+        &self,
+        method: &'static str,
+        request: &Request<T>,
+    ) -> Result<(), AuthError> {
+        let client_ip = self.rate_limiter.request_client_ip(request);
+        self.rate_limiter.check(method, client_ip, Instant::now())
+    }
+
+    /// Validates the bearer JWT sent in the `authorization` metadata of a request.
+    ///
+    /// Returns the JWT's claims.
+    async fn authorize<T>(&self, request: &Request<T>) -> Result<Claims, AuthError> {
+        const BEARER_PREFIX: &str = "Bearer ";
+
+        let token = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .filter(|value| value.starts_with(BEARER_PREFIX))
+            .map(|value| &value[BEARER_PREFIX.len()..])
+            .ok_or(AuthError::MissingToken)?;
+
+        self.validate_token(token).await
+    }
+
+    /// Validates a JWT, see `jwt::generate::validate_token`.
+    ///
+    /// A token signed by an unknown key first reloads the published keys, as another instance of
+    /// the service may have just generated the key.
+    async fn validate_token(&self, token: &str) -> Result<Claims, AuthError> {
+        if let Some(kid) = decode_header(token).ok().and_then(|header| header.kid) {
+            self.keys.reload_if_unknown(&kid).await?;
+        }
+
+        jwt::generate::validate_token(&*self.keys.store().await, token)
+    }
+
+    /// Checks that a request was sent by an administrator, i.e. it has the `ADMIN_API_KEY` in its
+    /// `x-api-key` metadata.
+    fn authorize_admin<T>(&self, request: &Request<T>) -> Result<(), AuthError> {
+        let api_key = request
+            .metadata()
+            .get("x-api-key")
+            .ok_or(AuthError::MissingToken)?;
+
+        // Compare digests rather than the keys, which would reveal the key's length.
+        let api_key_digest = Sha256::digest(api_key.as_bytes());
+        if bool::from(api_key_digest.as_slice().ct_eq(&self.admin_api_key_digest)) {
+            Ok(())
+        } else {
+            Err(AuthError::PermissionDenied)
+        }
+    }
+
+    /// Emails a new verification token to an account's email address.
+    async fn deliver_verification_email(&self, account: &Account) -> Result<(), AuthError> {
+        let verification_url =
+            dotenv::var("EMAIL_VERIFICATION_URL").expect("EMAIL_VERIFICATION_URL must be set");
+
+        let mut conn = self.pool.conn().await?;
+        let token = conn
+            .issue_email_verification(account.id, &account.email)
+            .await?;
+
+        self.mailer
+            .send(&Email {
+                to: account.email.clone(),
+                subject: "Verify your email address".to_string(),
+                body: format!(
+                    "Hi {},\n\nPlease verify your email address by visiting {}?token={}",
+                    account.given_name, verification_url, token
+                ),
+            })
+            .await
+    }
+
+    /// Starts the audit event of a request, with the client's IP address, resolved as for rate
+    /// limiting, and user agent.
+    fn audit_event<T>(&self, kind: AuditEventKind, request: &Request<T>) -> AuditEventCreate {
+        let user_agent = request
+            .metadata()
+            .get("user-agent")
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+
+        AuditEventCreate::new(
+            kind,
+            self.rate_limiter
+                .request_client_ip(request)
+                .map(|client_ip| client_ip.to_string()),
+            user_agent,
+        )
+    }
+
+    /// Records an audit event, in its own transaction as it locks the head of the audit chain.
+    ///
+    /// The outcome of a request matters more than its record, so a failure to record the event is
+    /// logged rather than returned.
+    async fn record_audit_event(&self, event: AuditEventCreate) {
+        let recorded = match self.pool.transaction().await {
+            Ok(mut tx) => match tx.record_audit_event(&event).await {
+                Ok(_) => tx.commit().await.map(|_| ()).map_err(AuthError::from),
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        };
+        if let Err(e) = recorded {
+            println!("Failed to record audit event {:?}: {:?}", event, e);
+        }
+    }
+
+    /// Records the audit events of a registration or sign in: the attempt itself, and the session
+    /// it started if it succeeded.
+    async fn record_sign_in(
+        &self,
+        event: AuditEventCreate,
+        result: &Result<AuthenticatedUserResponse, AuthError>,
+    ) {
+        let session = event.with_kind(AuditEventKind::TokenIssue);
+        self.record_audit_event(event.with_result(result)).await;
+        if result.is_ok() {
+            self.record_audit_event(session).await;
+        }
+    }
+
+    /// Registers a new account with a password, for the `RegisterUser` RPC.
+    ///
+    /// The audit event's account is set once the account is registered.
+    async fn register_with_password(
+        &self,
+        inner_request: RegisterUserRequest,
+        event: &mut AuditEventCreate,
+    ) -> Result<AuthenticatedUserResponse, AuthError> {
+        let email = email::canonicalize("email", &inner_request.email)?;
+        self.password_policy
+            .check(
+                "password",
+                &inner_request.password,
+                &email,
+                &inner_request.given_name,
+            )
+            .await?;
+
+        let account_register = AccountRegister {
+            given_name: inner_request.given_name,
+            email,
+            password: Some(inner_request.password),
+        };
+        let mut tx = self.pool.transaction().await?;
+        let (account, refresh_token) =
+            register_password_account(&mut tx, &account_register, &inner_request.token).await?;
+        event.account_id = Some(account.id);
+
+        let jwt = jwt::generate::create_token(&*self.keys.store().await, &account)?;
+        tx.commit().await?;
+
+        // The user can ask for another verification email, so this does not fail registration.
+        if let Err(e) = self.deliver_verification_email(&account).await {
+            println!("Failed to send verification email: {:?}", e);
+        }
+
+        Ok(AuthenticatedUserResponse {
+            jwt,
+            refresh_token: Some(refresh_token.into()),
+        })
+    }
+
+    /// Signs in a user with their email address and password, for the `AuthenticateUser` RPC.
+    ///
+    /// The audit event's account is set once it is known, which for a wrong password is the
+    /// account with the email address, if there is one.
+    async fn sign_in_with_password(
+        &self,
+        inner_request: AuthenticationRequest,
+        client_ip: Option<IpAddr>,
+        event: &mut AuditEventCreate,
+    ) -> Result<AuthenticatedUserResponse, AuthError> {
+        let mut conn = self.pool.conn().await?;
+        let throttle_ip = login_throttle_ip(&self.rate_limiter, client_ip);
+        let throttle_keys = login_throttle_keys(&inner_request.email, throttle_ip);
+        check_login_throttles(&mut conn, &throttle_keys).await?;
+
+        let account_auth = AccountAuthenticate {
+            email: inner_request.email,
+            password: inner_request.password,
+        };
+        let account = match conn.authenticate_account(&account_auth).await {
+            Ok(account) => account,
+            Err(AuthError::InvalidUsernameOrPassword) => {
+                for (scope, key) in &throttle_keys {
+                    conn.record_login_failure(*scope, key, &self.login_throttle_policy)
+                        .await?;
+                }
+                event.account_id = conn
+                    .find_account_by_email(&account_auth.email)
+                    .await
+                    .ok()
+                    .flatten()
+                    .map(|account| account.id);
+                return Err(AuthError::InvalidUsernameOrPassword);
+            }
+            Err(e) => return Err(e),
+        };
+        event.account_id = Some(account.id);
+
+        // Only the email address's count is cleared. Clearing the IP address's count would let an
+        // attacker reset it by signing in to their own account between guesses.
+        conn.clear_login_failures(ThrottleScope::Email, &account.email)
+            .await?;
+
+        // Passwords that were set before they appeared in a breach must be replaced, so the user
+        // is sent a reset link the first time one is found.
+        if account.password_breached_at.is_some() {
+            return Err(AuthError::PasswordResetRequired);
+        }
+        if breached_password_check_at_login()
+            && self
+                .password_policy
+                .is_breached(&account_auth.password)
+                .await?
+        {
+            conn.flag_breached_password(account.id).await?;
+
+            let pool = self.pool.clone();
+            let mailer = self.mailer.clone();
+            tokio::spawn(async move {
+                if let Err(e) = send_password_reset_email(pool, mailer, account).await {
+                    println!("Failed to send password reset email: {:?}", e);
+                }
+            });
+
+            return Err(AuthError::PasswordResetRequired);
+        }
+
+        let jwt = jwt::generate::create_token(&*self.keys.store().await, &account)?;
+        let refresh_token = conn.issue_refresh_token(account.id).await?;
+
+        Ok(AuthenticatedUserResponse {
+            jwt,
+            refresh_token: Some(refresh_token.into()),
+        })
+    }
+
+    /// Signs in a user verified by an identity provider, registering them if they are new.
+    ///
+    /// New users must present an invitation code if registration is invite-only. The audit
+    /// event's account is set once it is known, and a registration is recorded as its own event.
+    async fn sign_in_external(
+        &self,
+        identity: ExternalIdentity,
+        invitation_code: &str,
+        event: &mut AuditEventCreate,
+    ) -> Result<AuthenticatedUserResponse, AuthError> {
+        let mut tx = self.pool.transaction().await?;
+        let (account, registered) =
+            find_or_register_external(&mut tx, identity, invitation_code).await?;
+        event.account_id = Some(account.id);
+
+        let jwt = jwt::generate::create_token(&*self.keys.store().await, &account)?;
+        let refresh_token = tx.issue_refresh_token(account.id).await?;
+        tx.commit().await?;
+
+        if registered {
+            self.record_audit_event(event.with_kind(AuditEventKind::Registration))
+                .await;
+        }
+
+        Ok(AuthenticatedUserResponse {
+            jwt,
+            refresh_token: Some(refresh_token.into()),
+        })
+    }
+
+    /// Resets an account's password with a token emailed to it, for the `ResetPassword` RPC.
+    ///
+    /// The audit event's account is set once the token is used.
+    async fn reset_forgotten_password(
+        &self,
+        inner_request: ResetPasswordRequest,
+        event: &mut AuditEventCreate,
+    ) -> Result<(), AuthError> {
+        let mut tx = self.pool.transaction().await?;
+        reset_password_with_token(&mut tx, &inner_request, &self.password_policy, event).await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Changes an account's password, for the `ChangePassword` RPC.
+    ///
+    /// # Return Values
+    /// ## Success
+    /// Whether the account's other sessions were revoked.
+    async fn change_known_password(
+        &self,
+        account_id: AccountId,
+        inner_request: ChangePasswordRequest,
+    ) -> Result<bool, AuthError> {
+        let mut tx = self.pool.transaction().await?;
+        let result = change_account_password(
+            &mut tx,
+            account_id,
+            &inner_request,
+            &self.password_policy,
+            &self.login_throttle_policy,
+        )
+        .await;
+        // A wrong current password changes nothing else, but its failure must still be counted.
+        if matches!(result, Ok(_) | Err(AuthError::InvalidUsernameOrPassword)) {
+            tx.commit().await?;
+        }
+
+        result
+    }
+
+    /// Rotates a refresh token for a new one, for the `RefreshSession` RPC.
+    ///
+    /// The audit event's account is set once the token is found. If the token has been used
+    /// before, its whole session is revoked, which is recorded as its own event.
+    async fn rotate_session(
+        &self,
+        token: &str,
+        event: &mut AuditEventCreate,
+    ) -> Result<AuthenticatedUserResponse, AuthError> {
+        // Rotate in a transaction, so a token is never revoked without its replacement.
+        let mut tx = self.pool.transaction().await?;
+        let refresh_token = tx.get_refresh_token(token).await?;
+        event.account_id = Some(refresh_token.account_id);
+        let reuse = event
+            .with_kind(AuditEventKind::TokenRevocation)
+            .because("refresh token reuse");
+
+        // Presenting a token that has already been rotated means it has been used twice, so assume
+        // it was stolen and end the whole session.
+        if refresh_token.revoked && tx.is_refresh_token_rotated(refresh_token.id).await? {
+            tx.revoke_token_family(refresh_token.family).await?;
+            tx.commit().await?;
+            self.record_audit_event(reuse).await;
+            return Err(AuthError::InvalidRefreshToken);
+        }
+
+        if !refresh_token.is_active() {
+            return Err(AuthError::InvalidRefreshToken);
+        }
+
+        let issued = match tx.rotate_refresh_token(&refresh_token).await {
+            Ok(rotated) => rotated,
+            Err(AuthError::InvalidRefreshToken) => {
+                // Lost a race with another rotation of the same token, which is also a reuse.
+                tx.revoke_token_family(refresh_token.family).await?;
+                tx.commit().await?;
+                self.record_audit_event(reuse).await;
+                return Err(AuthError::InvalidRefreshToken);
+            }
+            Err(e) => return Err(e),
+        };
+
+        let account = tx.get_account(issued.refresh_token.account_id).await?;
+        let jwt = jwt::generate::create_token(&*self.keys.store().await, &account)?;
+        tx.commit().await?;
+
+        Ok(AuthenticatedUserResponse {
+            jwt,
+            refresh_token: Some(issued.into()),
+        })
+    }
+}
+
+/// Converts the filters of a `ListAuditEvents` request.
+///
+/// # Return Values
+/// ## Success
+/// The AuditEventFilter structure, which lists one more event than the page size, so that
+/// whether there is another page is known.
+///
+/// ## Errors
+/// `AuthError::InvalidFields`, listing every filter that is not valid.
+fn audit_event_filter(
+    inner_request: &ListAuditEventsRequest,
+) -> Result<AuditEventFilter, AuthError> {
+    let mut violations = Vec::new();
+
+    let kind = match inner_request.kind.as_str() {
+        "" => None,
+        kind => AuditEventKind::parse(kind).or_else(|| {
+            violations.push(FieldViolation::new("kind", "must be a kind of audit event"));
+            None
+        }),
+    };
+    let outcome = match inner_request.outcome.as_str() {
+        "" => None,
+        outcome => AuditOutcome::parse(outcome).or_else(|| {
+            violations.push(FieldViolation::new(
+                "outcome",
+                "must be either success or failure",
+            ));
+            None
+        }),
+    };
+    let before_id = match inner_request.page_token.as_str() {
+        "" => None,
+        page_token => page_token.parse().ok().or_else(|| {
+            violations.push(FieldViolation::new(
+                "page_token",
+                "must be the next_page_token of a previous page",
+            ));
+            None
+        }),
+    };
+    if inner_request.page_size < 0 || i64::from(inner_request.page_size) > MAX_PAGE_SIZE {
+        violations.push(FieldViolation::new(
+            "page_size",
+            &format!("must be between 0 and {}", MAX_PAGE_SIZE),
+        ));
+    }
+    if !violations.is_empty() {
+        return Err(AuthError::InvalidFields(violations));
+    }
+
+    let page_size = match inner_request.page_size {
+        0 => DEFAULT_PAGE_SIZE,
+        page_size => i64::from(page_size),
+    };
+
+    Ok(AuditEventFilter {
+        account_id: Some(inner_request.account_id).filter(|&account_id| account_id != 0),
+        kind,
+        outcome,
+        ip_address: Some(inner_request.ip_address.clone())
+            .filter(|ip_address| !ip_address.is_empty()),
+        since: Some(inner_request.since)
+            .filter(|&since| since != 0)
+            .map(|since| NaiveDateTime::from_timestamp(since, 0)),
+        until: Some(inner_request.until)
+            .filter(|&until| until != 0)
+            .map(|until| NaiveDateTime::from_timestamp(until, 0)),
+        before_id,
+        limit: page_size + 1,
+    })
+}
+
+/// Gets what a sign in attempt is counted by: its email address, whether or not it is registered,
+/// and the client's IP address, if it is known.
+fn login_throttle_keys(email: &str, client_ip: Option<IpAddr>) -> Vec<(ThrottleScope, String)> {
+    let email = email::canonicalize("email", email).unwrap_or_else(|_| email.trim().to_lowercase());
+    let mut throttle_keys = vec![(ThrottleScope::Email, email)];
+    if let Some(client_ip) = client_ip {
+        throttle_keys.push((ThrottleScope::Ip, client_ip.to_string()));
+    }
+
+    throttle_keys
+}
+
+/// Gets the IP address a sign in attempt is counted by, from the client's address as resolved for
+/// rate limiting.
+///
+/// A trusted proxy's own address is not counted by, e.g. when it did not send `x-forwarded-for`,
+/// as every user behind it would then be locked out together.
+fn login_throttle_ip(rate_limiter: &RateLimiter, client_ip: Option<IpAddr>) -> Option<IpAddr> {
+    client_ip.filter(|ip| !rate_limiter.is_trusted_proxy(ip))
+}
+
+/// Checks that none of what a sign in attempt is counted by is locked out.
+///
+/// If any is, the error holds the time until the last lockout ends, rounded up to a second.
+async fn check_login_throttles(
+    conn: &mut PgConnection,
+    throttle_keys: &[(ThrottleScope, String)],
+) -> Result<(), AuthError> {
+    let mut locked_until = None;
+    for (scope, key) in throttle_keys {
+        locked_until = locked_until.max(conn.get_lockout(*scope, key).await?);
+    }
+
+    match locked_until {
+        Some(locked_until) => {
+            let retry_delay = locked_until - Utc::now().naive_utc();
+            Err(AuthError::TooManyAttempts(Duration::seconds(
+                (retry_delay.num_milliseconds() + 999) / 1000,
+            )))
+        }
+        None => Ok(()),
+    }
+}
+
+/// Registers an account with a password, along with its password identity and first session.
+///
+/// This must be called in a transaction, so that nothing is left behind if any of it fails.
+///
+/// # Parameters
+/// The account to register, and the invitation code sent with the request, which is only used
+/// while invitations are required.
+///
+/// # Return Values
+/// ## Success
+/// The registered Account structure, and its first refresh token.
+///
+/// ## Errors
+/// If the email address is already registered, no valid invitation was sent while they are
+/// required, or a database failure occured.
+async fn register_password_account(
+    conn: &mut PgConnection,
+    account_register: &AccountRegister,
+    invitation_code: &str,
+) -> Result<(Account, IssuedRefreshToken), AuthError> {
+    if invitations_required() {
+        conn.use_invitation(invitation_code, &account_register.email)
+            .await?;
+    }
+
+    let account = conn.register_new_account(account_register).await?;
+    conn.add_identity(&IdentityCreate {
+        account_id: account.id,
+        provider: PASSWORD_PROVIDER.to_string(),
+        subject: None,
+    })
+    .await?;
+    let refresh_token = conn.issue_refresh_token(account.id).await?;
+
+    Ok((account, refresh_token))
+}
+
+/// Resets an account's password with a token sent by `RequestPasswordReset`, revoking every session
+/// of the account.
+///
+/// The audit event's account is set once the token is found.
+///
+/// # Return Values
+/// ## Success
+/// Ok, but empty.
+///
+/// ## Errors
+/// If the token is unknown, used or expired, the password does not meet the policy, or a database
+/// failure occured.
+async fn reset_password_with_token(
+    conn: &mut PgConnection,
+    reset_request: &ResetPasswordRequest,
+    password_policy: &PasswordPolicy,
+    event: &mut AuditEventCreate,
+) -> Result<(), AuthError> {
+    let password_reset = conn.use_password_reset(&reset_request.token).await?;
+    let account_id = password_reset.account_id;
+    event.account_id = Some(account_id);
+    conn.lock_account(account_id).await?;
+
+    let account = conn.get_account(account_id).await?;
+    password_policy
+        .check(
+            "password",
+            &reset_request.password,
+            &account.email,
+            &account.given_name,
+        )
+        .await?;
+
+    conn.set_password(account_id, Some(&reset_request.password))
+        .await?;
+    // The password may have been unlinked since the reset was requested.
+    let has_password_identity = conn
+        .get_identities_for_account(account_id)
+        .await?
+        .iter()
+        .any(|identity| identity.provider == PASSWORD_PROVIDER);
+    if !has_password_identity {
+        conn.add_identity(&IdentityCreate {
+            account_id,
+            provider: PASSWORD_PROVIDER.to_string(),
+            subject: None,
+        })
+        .await?;
+    }
+
+    // Whoever knew the old password may still be signed in.
+    conn.revoke_all_tokens_for_account(account_id).await?;
+
+    Ok(())
+}
+
+/// Changes an account's password, checking its current password first.
+///
+/// A wrong current password is counted against the account's email address, as for a failed sign
+/// in, so a stolen session cannot be used to guess the password. The failure must be committed
+/// even though this returns an error.
+///
+/// # Parameters
+/// The account, the request, the policy the new password must meet, and the lockout rules.
+///
+/// # Return Values
+/// ## Success
+/// Whether the account's other sessions were revoked.
+///
+/// ## Errors
+/// If the email address is locked out, the account has no password, the current password is
+/// wrong, the new password does not meet the policy, the refresh token to keep is not an active
+/// session of the account, or a database failure occured.
+async fn change_account_password(
+    conn: &mut PgConnection,
+    account_id: AccountId,
+    change_request: &ChangePasswordRequest,
+    password_policy: &PasswordPolicy,
+    throttle_policy: &LoginThrottlePolicy,
+) -> Result<bool, AuthError> {
+    conn.lock_account(account_id).await?;
+
+    let account = conn.get_account(account_id).await?;
+    let throttle_keys = login_throttle_keys(&account.email, None);
+    check_login_throttles(conn, &throttle_keys).await?;
+
+    let hash = account.hash.as_ref().ok_or_else(|| {
+        AuthError::InvalidRequest("the account does not have a password".to_string())
+    })?;
+    if !Argon2id::verify_password(&change_request.current_password, hash)? {
+        for (scope, key) in &throttle_keys {
+            conn.record_login_failure(*scope, key, throttle_policy)
+                .await?;
+        }
+        return Err(AuthError::InvalidUsernameOrPassword);
+    }
+    conn.clear_login_failures(ThrottleScope::Email, &account.email)
+        .await?;
+
+    password_policy
+        .check(
+            "new_password",
+            &change_request.new_password,
+            &account.email,
+            &account.given_name,
+        )
+        .await?;
+
+    // The session to keep is checked first, so an invalid request changes nothing.
+    let keep_family = if change_request.revoke_other_sessions {
+        let refresh_token = conn
+            .get_refresh_token(&change_request.refresh_token)
+            .await?;
+        if refresh_token.account_id != account_id || !refresh_token.is_active() {
+            return Err(AuthError::InvalidRefreshToken);
+        }
+        Some(refresh_token.family)
+    } else {
+        None
+    };
+
+    conn.set_password(account_id, Some(&change_request.new_password))
+        .await?;
+    if let Some(family) = keep_family {
+        conn.revoke_other_tokens_for_account(account_id, family)
+            .await?;
+    }
+
+    Ok(change_request.revoke_other_sessions)
+}
+
+/// Finds the account an external identity is linked to, or registers a new account for it.
+///
+/// An identity is never linked to an existing account by its email address, as anyone who could
+/// sign in to any provider with that address would then own the account. The user must sign in to
+/// the account and link the identity with the `LinkIdentity` RPC instead.
+///
+/// # Return Values
+/// ## Success
+/// The account, and whether it was registered.
+///
+/// ## Errors
+/// If another account has the identity's email address, no valid invitation was sent while they
+/// are required, or a database failure occured.
+async fn find_or_register_external(
+    conn: &mut PgConnection,
+    identity: ExternalIdentity,
+    invitation_code: &str,
+) -> Result<(Account, bool), AuthError> {
+    if let Some(existing) = conn
+        .find_identity(&identity.provider, &identity.subject)
+        .await?
+    {
+        return Ok((conn.get_account(existing.account_id).await?, false));
+    }
+
+    let email = email::canonicalize("email", &identity.email)?;
+    if conn.find_account_by_email(&email).await?.is_some() {
+        return Err(AuthError::EmailNotLinked(email));
+    }
+    if invitations_required() {
+        conn.use_invitation(invitation_code, &email).await?;
+    }
+
+    let account = conn
+        .register_new_account(&AccountRegister {
+            given_name: identity.given_name,
+            email,
+            password: None,
+        })
+        .await?;
+    conn.add_identity(&IdentityCreate {
+        account_id: account.id,
+        provider: identity.provider,
+        subject: Some(identity.subject),
+    })
+    .await?;
+
+    // The provider has verified the email address.
+    let account = conn
+        .mark_email_verified(account.id, &account.email)
+        .await?
+        .unwrap_or(account);
+
+    Ok((account, true))
+}
+
+/// Links an identity to an account, setting the account's password for a password identity.
+///
+/// # Parameters
+/// The identity to link, and for a password identity the password and the policy it must meet.
+///
+/// # Return Values
+/// ## Success
+/// The linked Identity structure.
+///
+/// ## Errors
+/// If the account already has an identity from the provider, the identity is linked to another
+/// account, the password does not meet the policy, or a database failure occured.
+async fn link_account_identity(
+    conn: &mut PgConnection,
+    identity_create: &IdentityCreate,
+    password: &str,
+    password_policy: &PasswordPolicy,
+) -> Result<Identity, AuthError> {
+    let account_id = identity_create.account_id;
+    conn.lock_account(account_id).await?;
+
+    let already_linked = conn
+        .get_identities_for_account(account_id)
+        .await?
+        .iter()
+        .any(|identity| identity.provider == identity_create.provider);
+    let linked_elsewhere = match &identity_create.subject {
+        Some(subject) => conn
+            .find_identity(&identity_create.provider, subject)
+            .await?
+            .is_some(),
+        None => false,
+    };
+    if already_linked || linked_elsewhere {
+        return Err(AuthError::IdentityAlreadyLinked(
+            identity_create.provider.clone(),
+        ));
+    }
+
+    if identity_create.provider == PASSWORD_PROVIDER {
+        let account = conn.get_account(account_id).await?;
+        password_policy
+            .check("password", password, &account.email, &account.given_name)
+            .await?;
+
+        conn.set_password(account_id, Some(password)).await?;
+    }
+
+    conn.add_identity(identity_create).await
+}
+
+/// Unlinks an identity from an account, clearing the account's password for a password identity.
+///
+/// # Return Values
+/// ## Success
+/// Ok, but empty.
+///
+/// ## Errors
+/// If the account has no identity from the provider, it is the account's only identity, or a
+/// database failure occured.
+async fn unlink_account_identity(
+    conn: &mut PgConnection,
+    account_id: AccountId,
+    provider: &str,
+) -> Result<(), AuthError> {
+    // Lock the account, so concurrent requests cannot each unlink one of its last two identities.
+    conn.lock_account(account_id).await?;
+
+    let identities = conn.get_identities_for_account(account_id).await?;
+    if !identities
+        .iter()
+        .any(|identity| identity.provider == provider)
+    {
+        return Err(AuthError::IdentityNotFound(provider.to_string()));
+    }
+    if identities.len() == 1 {
+        return Err(AuthError::LastIdentity);
+    }
+
+    conn.remove_identity(account_id, provider).await?;
+    if provider == PASSWORD_PROVIDER {
+        conn.set_password(account_id, None).await?;
+    }
+
+    Ok(())
+}
+
+/// Emails a new password reset token to an account's email address.
+async fn send_password_reset_email(
+    pool: PgPool,
+    mailer: Arc<Mailer>,
+    account: Account,
+) -> Result<(), AuthError> {
+    let reset_url = dotenv::var("PASSWORD_RESET_URL").expect("PASSWORD_RESET_URL must be set");
+
+    let mut conn = pool.conn().await?;
+    let token = conn.issue_password_reset(account.id).await?;
+
+    mailer
+        .send(&Email {
+            to: account.email,
+            subject: "Reset your password".to_string(),
+            body: format!(
+                "Hi {},\n\nYou can choose a new password by visiting {}?token={}\n\n\
+                 If you did not ask to reset your password, you can ignore this email.",
+                account.given_name, reset_url, token
+            ),
+        })
+        .await
+}
+
+/// Checks whether registration is invite-only, i.e. `REQUIRE_INVITATION` is set to `true`.
+fn invitations_required() -> bool {
+    dotenv::var("REQUIRE_INVITATION")
+        .map(|value| value == "true")
+        .unwrap_or(false)
+}
+
+/// Checks whether passwords are checked for breaches at sign in, i.e.
+/// `BREACHED_PASSWORDS_CHECK_AT_LOGIN` is set to `true`.
+fn breached_password_check_at_login() -> bool {
+    dotenv::var("BREACHED_PASSWORDS_CHECK_AT_LOGIN")
+        .map(|value| value == "true")
+        .unwrap_or(false)
+}
+
+/// Define the name of the Google identity provider, used by the `AuthenticateWithGoogle` RPC.
+const GOOGLE_PROVIDER: &str = "google";
+
+/// Define the RFC 7662 token type of JWTs.
+const ACCESS_TOKEN_TYPE: &str = "access_token";
+
+/// Define the RFC 7662 token type of refresh tokens.
+const REFRESH_TOKEN_TYPE: &str = "refresh_token";
+
+/// Gets the reason a JWT was rejected by `jwt::generate::validate_token`.
+fn inactive_reason(auth_error: &AuthError) -> InactiveReason {
+    match auth_error {
+        AuthError::InvalidToken(e) => match e.kind() {
+            ErrorKind::ExpiredSignature => InactiveReason::Expired,
+            ErrorKind::InvalidSignature => InactiveReason::BadSignature,
+            ErrorKind::InvalidIssuer => InactiveReason::WrongIssuer,
+            _ => InactiveReason::Malformed,
+        },
+        _ => InactiveReason::Malformed,
+    }
+}
+
+/// Describes a JWT for the `IntrospectToken` RPC, from the result of validating it.
+fn introspect_jwt(validated: Result<Claims, AuthError>) -> IntrospectTokenResponse {
+    match validated {
+        Ok(claims) => IntrospectTokenResponse {
+            active: true,
+            token_type: ACCESS_TOKEN_TYPE.to_string(),
+            sub: claims.sub,
+            email: claims.email,
+            iat: claims.iat,
+            exp: claims.exp,
+            iss: claims.iss,
+            ..Default::default()
+        },
+        Err(e) => IntrospectTokenResponse {
+            active: false,
+            token_type: ACCESS_TOKEN_TYPE.to_string(),
+            inactive_reason: inactive_reason(&e) as i32,
+            ..Default::default()
+        },
+    }
+}
+
+/// Describes a refresh token for the `IntrospectToken` RPC.
+fn introspect_refresh_token(refresh_token: &RefreshToken) -> IntrospectTokenResponse {
+    let inactive_reason = if refresh_token.revoked {
+        InactiveReason::Revoked
+    } else if !refresh_token.is_active() {
+        InactiveReason::Expired
+    } else {
+        InactiveReason::None
+    };
+
+    IntrospectTokenResponse {
+        active: refresh_token.is_active(),
+        token_type: REFRESH_TOKEN_TYPE.to_string(),
+        sub: refresh_token.account_id.to_string(),
+        iat: refresh_token.issued_at.timestamp(),
+        exp: refresh_token.expires.timestamp(),
+        iss: JWT_ISSUER.to_string(),
+        inactive_reason: inactive_reason as i32,
+        revoked: refresh_token.revoked,
+        ..Default::default()
+    }
+}
+
+impl From<Jwk> for ProtoJwk {
+    fn from(jwk: Jwk) -> Self {
+        Self {
+            kty: jwk.kty,
+            kid: jwk.kid,
+            key_use: jwk.key_use,
+            alg: jwk.alg,
+            n: jwk.n.unwrap_or_default(),
+            e: jwk.e.unwrap_or_default(),
+            crv: jwk.crv.unwrap_or_default(),
+            x: jwk.x.unwrap_or_default(),
+            y: jwk.y.unwrap_or_default(),
+        }
+    }
+}
+
+impl From<Identity> for ProtoIdentity {
+    fn from(identity: Identity) -> Self {
+        Self {
+            provider: identity.provider,
+            subject: identity.subject.unwrap_or_default(),
+        }
+    }
+}
+
+impl From<Invitation> for ProtoInvitation {
+    fn from(invitation: Invitation) -> Self {
+        Self {
+            id: invitation.id,
+            inviter_id: invitation.inviter_id.unwrap_or_default(),
+            email: invitation.email.unwrap_or_default(),
+            max_uses: invitation.max_uses,
+            uses: invitation.uses,
+            expires: invitation
+                .expires
+                .map(|expires| expires.timestamp())
+                .unwrap_or_default(),
+            created_at: invitation.created_at.timestamp(),
+            revoked: invitation.revoked_at.is_some(),
+        }
+    }
+}
+
+impl From<AuditEvent> for ProtoAuditEvent {
+    fn from(event: AuditEvent) -> Self {
+        Self {
+            id: event.id,
+            kind: event.kind,
+            account_id: event.account_id.unwrap_or_default(),
+            ip_address: event.ip_address.unwrap_or_default(),
+            user_agent: event.user_agent.unwrap_or_default(),
+            outcome: event.outcome,
+            reason: event.reason.unwrap_or_default(),
+            created_at: event.created_at.timestamp(),
+            hash: event.hash.unwrap_or_default(),
+        }
+    }
+}
+
+impl From<ChainVerification> for VerifyAuditLogResponse {
+    fn from(verification: ChainVerification) -> Self {
+        let first_break = verification.first_break;
+        Self {
+            intact: first_break.is_none(),
+            events_verified: verification.events_verified as i64,
+            unchained_events: verification.unchained_events as i64,
+            checkpoints_verified: verification.checkpoints_verified as i64,
+            broken_event_id: first_break
+                .as_ref()
+                .map(|first_break| first_break.event_id)
+                .unwrap_or_default(),
+            break_reason: first_break
+                .map(|first_break| first_break.reason)
+                .unwrap_or_default(),
+        }
+    }
+}
+
+impl From<IssuedRefreshToken> for ProtoRefreshToken {
+    fn from(issued: IssuedRefreshToken) -> Self {
+        Self {
+            issued_at: issued.refresh_token.issued_at.timestamp(),
+            expires: issued.refresh_token.expires.timestamp(),
+            token: issued.token,
+        }
+    }
 }
 
 #[tonic::async_trait]
@@ -47,27 +1113,15 @@ impl Auth for AuthService {
     ) -> Result<Response<AuthenticatedUserResponse>, Status> {
         println!("Got register_user request from {:?}", request.remote_addr());
 
-        let mut conn = self.pool.conn().await?;
-        let inner_request = request.into_inner();
-        let account = conn
-            .register_new_account(&AccountRegister {
-                given_name: inner_request.given_name,
-                email: inner_request.email,
-                password: Some(inner_request.password),
-            })
-            .await?;
+        self.check_rate_limit("RegisterUser", &request)?;
 
-        let jwt = jwt::generate::create_token(account.id, &account.email)?;
-        let refresh_token = conn.issue_refresh_token(account.id).await?;
+        let mut event = self.audit_event(AuditEventKind::Registration, &request);
+        let result = self
+            .register_with_password(request.into_inner(), &mut event)
+            .await;
+        self.record_sign_in(event, &result).await;
 
-        Ok(Response::new(AuthenticatedUserResponse {
-            jwt,
-            refresh_token: Some(ProtoRefreshToken {
-                issued_at: refresh_token.issued_at.timestamp(),
-                expires: refresh_token.expires.timestamp(),
-                token: refresh_token.token,
-            }),
-        }))
+        Ok(Response::new(result?))
     }
 
     async fn authenticate_user(
@@ -79,25 +1133,1200 @@ impl Auth for AuthService {
             request.remote_addr()
         );
 
-        let mut conn = self.pool.conn().await?;
+        self.check_rate_limit("AuthenticateUser", &request)?;
+
+        let mut event = self.audit_event(AuditEventKind::Login, &request);
+        let client_ip = self.rate_limiter.request_client_ip(&request);
+        let result = self
+            .sign_in_with_password(request.into_inner(), client_ip, &mut event)
+            .await;
+        self.record_sign_in(event, &result).await;
+
+        Ok(Response::new(result?))
+    }
+
+    async fn authenticate_with_google(
+        &self,
+        request: Request<GoogleAuthenticationRequest>,
+    ) -> Result<Response<AuthenticatedUserResponse>, Status> {
+        println!(
+            "Got authenticate_with_google request from {:?}",
+            request.remote_addr()
+        );
+
+        self.check_rate_limit("AuthenticateWithGoogle", &request)?;
+
+        let mut event = self.audit_event(AuditEventKind::Login, &request);
         let inner_request = request.into_inner();
-        let account = conn
-            .authenticate_account(&AccountAuthenticate {
-                email: inner_request.email,
-                password: inner_request.password,
-            })
+        let result = match self
+            .providers
+            .get(GOOGLE_PROVIDER)?
+            .verify_id_token(&inner_request.id_token, None)
+            .await
+        {
+            Ok(identity) => {
+                self.sign_in_external(identity, &inner_request.token, &mut event)
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        self.record_sign_in(event, &result).await;
+
+        Ok(Response::new(result?))
+    }
+
+    async fn get_authorization_url(
+        &self,
+        request: Request<AuthorizationUrlRequest>,
+    ) -> Result<Response<AuthorizationUrlResponse>, Status> {
+        println!(
+            "Got get_authorization_url request from {:?}",
+            request.remote_addr()
+        );
+
+        self.check_rate_limit("GetAuthorizationUrl", &request)?;
+
+        let inner_request = request.into_inner();
+        let url = self
+            .providers
+            .get(&inner_request.provider)?
+            .authorization_url(
+                &inner_request.redirect_uri,
+                &inner_request.state,
+                &inner_request.nonce,
+            )
             .await?;
 
-        let jwt = jwt::generate::create_token(account.id, &account.email)?;
-        let refresh_token = conn.issue_refresh_token(account.id).await?;
+        Ok(Response::new(AuthorizationUrlResponse { url }))
+    }
 
-        Ok(Response::new(AuthenticatedUserResponse {
-            jwt,
-            refresh_token: Some(ProtoRefreshToken {
-                issued_at: refresh_token.issued_at.timestamp(),
-                expires: refresh_token.expires.timestamp(),
-                token: refresh_token.token,
-            }),
-        }))
+    async fn authenticate_with_provider(
+        &self,
+        request: Request<ProviderAuthenticationRequest>,
+    ) -> Result<Response<AuthenticatedUserResponse>, Status> {
+        println!(
+            "Got authenticate_with_provider request from {:?}",
+            request.remote_addr()
+        );
+
+        self.check_rate_limit("AuthenticateWithProvider", &request)?;
+
+        let mut event = self.audit_event(AuditEventKind::Login, &request);
+        let inner_request = request.into_inner();
+        let result = match self
+            .providers
+            .get(&inner_request.provider)?
+            .exchange_code(
+                &inner_request.code,
+                &inner_request.redirect_uri,
+                &inner_request.nonce,
+            )
+            .await
+        {
+            Ok(identity) => {
+                self.sign_in_external(identity, &inner_request.token, &mut event)
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        self.record_sign_in(event, &result).await;
+
+        Ok(Response::new(result?))
+    }
+
+    async fn link_identity(
+        &self,
+        request: Request<LinkIdentityRequest>,
+    ) -> Result<Response<ProtoIdentity>, Status> {
+        println!("Got link_identity request from {:?}", request.remote_addr());
+
+        self.check_rate_limit("LinkIdentity", &request)?;
+
+        let claims = self.authorize(&request).await?;
+        let account_id = claims.account_id()?;
+        let inner_request = request.into_inner();
+
+        let identity_create = if inner_request.provider == PASSWORD_PROVIDER {
+            IdentityCreate {
+                account_id,
+                provider: PASSWORD_PROVIDER.to_string(),
+                subject: None,
+            }
+        } else {
+            let provider = self.providers.get(&inner_request.provider)?;
+            let external = if inner_request.id_token.is_empty() {
+                provider
+                    .exchange_code(
+                        &inner_request.code,
+                        &inner_request.redirect_uri,
+                        &inner_request.nonce,
+                    )
+                    .await?
+            } else {
+                provider
+                    .verify_id_token(&inner_request.id_token, None)
+                    .await?
+            };
+
+            IdentityCreate {
+                account_id,
+                provider: external.provider,
+                subject: Some(external.subject),
+            }
+        };
+
+        let mut tx = self.pool.transaction().await?;
+        let identity = link_account_identity(
+            &mut tx,
+            &identity_create,
+            &inner_request.password,
+            &self.password_policy,
+        )
+        .await?;
+        tx.commit().await.map_err(AuthError::from)?;
+
+        Ok(Response::new(identity.into()))
+    }
+
+    async fn unlink_identity(
+        &self,
+        request: Request<UnlinkIdentityRequest>,
+    ) -> Result<Response<UnlinkIdentityResponse>, Status> {
+        println!(
+            "Got unlink_identity request from {:?}",
+            request.remote_addr()
+        );
+
+        self.check_rate_limit("UnlinkIdentity", &request)?;
+
+        let claims = self.authorize(&request).await?;
+        let account_id = claims.account_id()?;
+        let provider = request.into_inner().provider;
+
+        let mut tx = self.pool.transaction().await?;
+        unlink_account_identity(&mut tx, account_id, &provider).await?;
+        tx.commit().await.map_err(AuthError::from)?;
+
+        Ok(Response::new(UnlinkIdentityResponse {}))
+    }
+
+    async fn list_identities(
+        &self,
+        request: Request<ListIdentitiesRequest>,
+    ) -> Result<Response<ListIdentitiesResponse>, Status> {
+        println!(
+            "Got list_identities request from {:?}",
+            request.remote_addr()
+        );
+
+        self.check_rate_limit("ListIdentities", &request)?;
+
+        let claims = self.authorize(&request).await?;
+
+        let mut conn = self.pool.conn().await?;
+        let identities = conn
+            .get_identities_for_account(claims.account_id()?)
+            .await?;
+
+        Ok(Response::new(ListIdentitiesResponse {
+            identities: identities.into_iter().map(ProtoIdentity::from).collect(),
+        }))
+    }
+
+    async fn send_verification_email(
+        &self,
+        request: Request<SendVerificationEmailRequest>,
+    ) -> Result<Response<SendVerificationEmailResponse>, Status> {
+        println!(
+            "Got send_verification_email request from {:?}",
+            request.remote_addr()
+        );
+
+        self.check_rate_limit("SendVerificationEmail", &request)?;
+
+        let claims = self.authorize(&request).await?;
+
+        let mut conn = self.pool.conn().await?;
+        let account = conn.get_account(claims.account_id()?).await?;
+        if account.email_verified_at.is_some() {
+            return Err(AuthError::InvalidRequest(format!(
+                "{} has already been verified",
+                account.email
+            ))
+            .into());
+        }
+
+        self.deliver_verification_email(&account).await?;
+
+        Ok(Response::new(SendVerificationEmailResponse {}))
+    }
+
+    async fn verify_email(
+        &self,
+        request: Request<VerifyEmailRequest>,
+    ) -> Result<Response<VerifyEmailResponse>, Status> {
+        println!("Got verify_email request from {:?}", request.remote_addr());
+
+        self.check_rate_limit("VerifyEmail", &request)?;
+
+        let inner_request = request.into_inner();
+
+        let mut tx = self.pool.transaction().await?;
+        let email_verification = tx.use_email_verification(&inner_request.token).await?;
+        // The token is only valid for the address it was sent to, in case the email has changed.
+        tx.mark_email_verified(email_verification.account_id, &email_verification.email)
+            .await?
+            .ok_or(AuthError::InvalidVerificationToken)?;
+        tx.commit().await.map_err(AuthError::from)?;
+
+        Ok(Response::new(VerifyEmailResponse {}))
+    }
+
+    async fn request_password_reset(
+        &self,
+        request: Request<RequestPasswordResetRequest>,
+    ) -> Result<Response<RequestPasswordResetResponse>, Status> {
+        println!(
+            "Got request_password_reset request from {:?}",
+            request.remote_addr()
+        );
+
+        self.check_rate_limit("RequestPasswordReset", &request)?;
+
+        let inner_request = request.into_inner();
+
+        let mut conn = self.pool.conn().await?;
+        let account = conn.find_account_by_email(&inner_request.email).await?;
+
+        // Only accounts with a password can reset it. The email is sent in the background, so that
+        // the response time does not reveal whether the account exists.
+        if let Some(account) = account.filter(|account| account.hash.is_some()) {
+            let pool = self.pool.clone();
+            let mailer = self.mailer.clone();
+            tokio::spawn(async move {
+                if let Err(e) = send_password_reset_email(pool, mailer, account).await {
+                    println!("Failed to send password reset email: {:?}", e);
+                }
+            });
+        }
+
+        Ok(Response::new(RequestPasswordResetResponse {}))
+    }
+
+    async fn reset_password(
+        &self,
+        request: Request<ResetPasswordRequest>,
+    ) -> Result<Response<ResetPasswordResponse>, Status> {
+        println!(
+            "Got reset_password request from {:?}",
+            request.remote_addr()
+        );
+
+        self.check_rate_limit("ResetPassword", &request)?;
+
+        let mut event = self.audit_event(AuditEventKind::PasswordChange, &request);
+        let result = self
+            .reset_forgotten_password(request.into_inner(), &mut event)
+            .await;
+        let revocation = event
+            .with_kind(AuditEventKind::TokenRevocation)
+            .because("password reset");
+        self.record_audit_event(event.with_result(&result)).await;
+        if result.is_ok() {
+            self.record_audit_event(revocation).await;
+        }
+        result?;
+
+        Ok(Response::new(ResetPasswordResponse {}))
+    }
+
+    async fn change_password(
+        &self,
+        request: Request<ChangePasswordRequest>,
+    ) -> Result<Response<ChangePasswordResponse>, Status> {
+        println!(
+            "Got change_password request from {:?}",
+            request.remote_addr()
+        );
+
+        self.check_rate_limit("ChangePassword", &request)?;
+
+        let claims = self.authorize(&request).await?;
+        let account_id = claims.account_id()?;
+        let mut event = self.audit_event(AuditEventKind::PasswordChange, &request);
+        event.account_id = Some(account_id);
+
+        let result = self
+            .change_known_password(account_id, request.into_inner())
+            .await;
+        let revocation = event
+            .with_kind(AuditEventKind::TokenRevocation)
+            .because("password change");
+        self.record_audit_event(event.with_result(&result)).await;
+        if result? {
+            self.record_audit_event(revocation).await;
+        }
+
+        Ok(Response::new(ChangePasswordResponse {}))
+    }
+
+    async fn refresh_session(
+        &self,
+        request: Request<RefreshSessionRequest>,
+    ) -> Result<Response<AuthenticatedUserResponse>, Status> {
+        println!(
+            "Got refresh_session request from {:?}",
+            request.remote_addr()
+        );
+
+        self.check_rate_limit("RefreshSession", &request)?;
+
+        let mut event = self.audit_event(AuditEventKind::TokenRefresh, &request);
+        let inner_request = request.into_inner();
+        let result = self
+            .rotate_session(&inner_request.refresh_token, &mut event)
+            .await;
+        self.record_audit_event(event.with_result(&result)).await;
+
+        Ok(Response::new(result?))
+    }
+
+    async fn logout(
+        &self,
+        request: Request<LogoutRequest>,
+    ) -> Result<Response<LogoutResponse>, Status> {
+        println!("Got logout request from {:?}", request.remote_addr());
+
+        self.check_rate_limit("Logout", &request)?;
+
+        let claims = self.authorize(&request).await?;
+        let account_id = claims.account_id()?;
+        let mut event = self
+            .audit_event(AuditEventKind::TokenRevocation, &request)
+            .because("logout");
+        event.account_id = Some(account_id);
+
+        let mut conn = self.pool.conn().await?;
+        let inner_request = request.into_inner();
+        let result = match conn.get_refresh_token(&inner_request.refresh_token).await {
+            // Accounts may only end their own sessions.
+            Ok(refresh_token) if refresh_token.account_id != account_id => {
+                Err(AuthError::InvalidRefreshToken)
+            }
+            // Revoking the whole family also covers tokens rotated from this one.
+            Ok(refresh_token) => conn.revoke_token_family(refresh_token.family).await,
+            Err(e) => Err(e),
+        };
+        self.record_audit_event(event.with_result(&result)).await;
+        result?;
+
+        Ok(Response::new(LogoutResponse {}))
+    }
+
+    async fn logout_everywhere(
+        &self,
+        request: Request<LogoutEverywhereRequest>,
+    ) -> Result<Response<LogoutResponse>, Status> {
+        println!(
+            "Got logout_everywhere request from {:?}",
+            request.remote_addr()
+        );
+
+        self.check_rate_limit("LogoutEverywhere", &request)?;
+
+        let claims = self.authorize(&request).await?;
+        let account_id = claims.account_id()?;
+        let mut event = self
+            .audit_event(AuditEventKind::TokenRevocation, &request)
+            .because("logout everywhere");
+        event.account_id = Some(account_id);
+
+        let mut conn = self.pool.conn().await?;
+        let result = conn.revoke_all_tokens_for_account(account_id).await;
+        self.record_audit_event(event.with_result(&result)).await;
+        result?;
+
+        Ok(Response::new(LogoutResponse {}))
+    }
+
+    async fn get_jwks(
+        &self,
+        request: Request<GetJwksRequest>,
+    ) -> Result<Response<ProtoJwkSet>, Status> {
+        println!("Got get_jwks request from {:?}", request.remote_addr());
+
+        self.check_rate_limit("GetJwks", &request)?;
+
+        Ok(Response::new(ProtoJwkSet {
+            keys: self
+                .keys
+                .store()
+                .await
+                .jwks()
+                .keys
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+        }))
+    }
+
+    async fn introspect_token(
+        &self,
+        request: Request<IntrospectTokenRequest>,
+    ) -> Result<Response<IntrospectTokenResponse>, Status> {
+        println!(
+            "Got introspect_token request from {:?}",
+            request.remote_addr()
+        );
+
+        self.check_rate_limit("IntrospectToken", &request)?;
+
+        self.authorize_admin(&request)?;
+
+        let inner_request = request.into_inner();
+        let token = inner_request.token;
+
+        // Refresh tokens are alphanumeric, so can never be mistaken for a JWT.
+        if inner_request.token_type_hint != REFRESH_TOKEN_TYPE && token.contains('.') {
+            let validated = self.validate_token(&token).await;
+            return Ok(Response::new(introspect_jwt(validated)));
+        }
+
+        let mut conn = self.pool.conn().await?;
+        let refresh_token = match conn.get_refresh_token(&token).await {
+            Ok(refresh_token) => refresh_token,
+            Err(AuthError::InvalidRefreshToken) => {
+                return Ok(Response::new(IntrospectTokenResponse {
+                    active: false,
+                    token_type: REFRESH_TOKEN_TYPE.to_string(),
+                    inactive_reason: InactiveReason::NotFound as i32,
+                    ..Default::default()
+                }))
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Response::new(introspect_refresh_token(&refresh_token)))
+    }
+
+    async fn rotate_signing_key(
+        &self,
+        request: Request<RotateSigningKeyRequest>,
+    ) -> Result<Response<RotateSigningKeyResponse>, Status> {
+        println!(
+            "Got rotate_signing_key request from {:?}",
+            request.remote_addr()
+        );
+
+        self.check_rate_limit("RotateSigningKey", &request)?;
+
+        self.authorize_admin(&request)?;
+
+        let inner_request = request.into_inner();
+        self.keys.rotate_now(inner_request.retire_previous).await?;
+
+        Ok(Response::new(RotateSigningKeyResponse {
+            kid: self.keys.store().await.active_key()?.kid.clone(),
+        }))
+    }
+
+    async fn create_invitation(
+        &self,
+        request: Request<CreateInvitationRequest>,
+    ) -> Result<Response<CreateInvitationResponse>, Status> {
+        println!(
+            "Got create_invitation request from {:?}",
+            request.remote_addr()
+        );
+
+        self.check_rate_limit("CreateInvitation", &request)?;
+
+        self.authorize_admin(&request)?;
+
+        let inner_request = request.into_inner();
+        if inner_request.max_uses < 1 {
+            return Err(
+                AuthError::InvalidRequest("max_uses must be at least 1".to_string()).into(),
+            );
+        }
+
+        let invitation_email = match inner_request.email.as_str() {
+            "" => None,
+            invitation_email => Some(email::canonicalize("email", invitation_email)?),
+        };
+
+        let invitation_create = InvitationCreate::new(
+            Some(inner_request.inviter_id).filter(|&inviter_id| inviter_id != 0),
+            invitation_email,
+            inner_request.max_uses,
+            Some(inner_request.expires)
+                .filter(|&expires| expires != 0)
+                .map(|expires| NaiveDateTime::from_timestamp(expires, 0)),
+        );
+
+        let mut conn = self.pool.conn().await?;
+        let issued = conn.create_invitation(invitation_create).await?;
+
+        Ok(Response::new(CreateInvitationResponse {
+            invitation: Some(issued.invitation.into()),
+            code: issued.code,
+        }))
+    }
+
+    async fn list_invitations(
+        &self,
+        request: Request<ListInvitationsRequest>,
+    ) -> Result<Response<ListInvitationsResponse>, Status> {
+        println!(
+            "Got list_invitations request from {:?}",
+            request.remote_addr()
+        );
+
+        self.check_rate_limit("ListInvitations", &request)?;
+
+        self.authorize_admin(&request)?;
+
+        let mut conn = self.pool.conn().await?;
+        let invitations = conn.list_invitations().await?;
+
+        Ok(Response::new(ListInvitationsResponse {
+            invitations: invitations.into_iter().map(ProtoInvitation::from).collect(),
+        }))
+    }
+
+    async fn revoke_invitation(
+        &self,
+        request: Request<RevokeInvitationRequest>,
+    ) -> Result<Response<RevokeInvitationResponse>, Status> {
+        println!(
+            "Got revoke_invitation request from {:?}",
+            request.remote_addr()
+        );
+
+        self.check_rate_limit("RevokeInvitation", &request)?;
+
+        self.authorize_admin(&request)?;
+
+        let mut conn = self.pool.conn().await?;
+        conn.revoke_invitation(request.into_inner().id).await?;
+
+        Ok(Response::new(RevokeInvitationResponse {}))
+    }
+
+    async fn unlock_account(
+        &self,
+        request: Request<UnlockAccountRequest>,
+    ) -> Result<Response<UnlockAccountResponse>, Status> {
+        println!(
+            "Got unlock_account request from {:?}",
+            request.remote_addr()
+        );
+
+        self.check_rate_limit("UnlockAccount", &request)?;
+
+        self.authorize_admin(&request)?;
+
+        let mut conn = self.pool.conn().await?;
+        let account = conn.get_account(request.into_inner().account_id).await?;
+        conn.clear_login_failures(ThrottleScope::Email, &account.email)
+            .await?;
+
+        Ok(Response::new(UnlockAccountResponse {}))
+    }
+
+    async fn list_audit_events(
+        &self,
+        request: Request<ListAuditEventsRequest>,
+    ) -> Result<Response<ListAuditEventsResponse>, Status> {
+        println!(
+            "Got list_audit_events request from {:?}",
+            request.remote_addr()
+        );
+
+        self.check_rate_limit("ListAuditEvents", &request)?;
+
+        self.authorize_admin(&request)?;
+
+        let filter = audit_event_filter(request.get_ref())?;
+        let mut conn = self.pool.conn().await?;
+        let mut events = conn.list_audit_events(&filter).await?;
+
+        // The filter lists one more event than the page size, which is only there if there is
+        // another page.
+        let next_page_token = if events.len() as i64 == filter.limit {
+            events.pop();
+            events
+                .last()
+                .map(|event| event.id.to_string())
+                .unwrap_or_default()
+        } else {
+            String::new()
+        };
+
+        Ok(Response::new(ListAuditEventsResponse {
+            events: events.into_iter().map(ProtoAuditEvent::from).collect(),
+            next_page_token,
+        }))
+    }
+
+    async fn verify_audit_log(
+        &self,
+        request: Request<VerifyAuditLogRequest>,
+    ) -> Result<Response<VerifyAuditLogResponse>, Status> {
+        println!(
+            "Got verify_audit_log request from {:?}",
+            request.remote_addr()
+        );
+
+        self.check_rate_limit("VerifyAuditLog", &request)?;
+
+        self.authorize_admin(&request)?;
+
+        let mut conn = self.pool.conn().await?;
+        let keys = &self.keys;
+        let verification = chain::verify_chain(&mut conn, |kid| keys.load_key(kid)).await?;
+
+        Ok(Response::new(verification.into()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use super::auth::auth_client::AuthClient;
+
+    use crate::database::postgres::{connect, register_test_account, test_transaction};
+    use crate::keys::manager::test_key_manager;
+    use crate::keys::model::KeyAlgorithm;
+    use crate::keys::store::{test_key_store, KeyStore};
+    use crate::rate_limit::{BucketConfig, RateLimitConfig};
+
+    use std::collections::{HashMap, HashSet};
+
+    use jsonwebtoken::{encode, Header};
+    use tonic::transport::Channel;
+
+    /// Signs test claims, changed by `change`, with the key store's active key.
+    fn signed_token(keys: &KeyStore, change: impl FnOnce(&mut Claims)) -> String {
+        let now = Utc::now();
+        let mut claims = Claims {
+            iss: JWT_ISSUER.to_string(),
+            sub: "1".to_string(),
+            iat: now.timestamp(),
+            exp: (now + Duration::hours(1)).timestamp(),
+            email: "test@example.com".to_string(),
+            email_verified: false,
+        };
+        change(&mut claims);
+
+        let key = keys.active_key().unwrap();
+        let mut header = Header::new(key.algorithm.jwt_algorithm());
+        header.kid = Some(key.kid.clone());
+        encode(&header, &claims, key.encoding_key()).unwrap()
+    }
+
+    /// Introspects a JWT, validated against the key store.
+    fn introspect(keys: &KeyStore, token: &str) -> IntrospectTokenResponse {
+        introspect_jwt(jwt::generate::validate_token(keys, token))
+    }
+
+    #[test]
+    fn test_jwts_are_inactive_for_their_reason() {
+        let keys = test_key_store(KeyAlgorithm::ES256);
+
+        let valid = signed_token(&keys, |_| {});
+        let response = introspect(&keys, &valid);
+        assert!(response.active);
+        assert_eq!(response.sub, "1");
+
+        let expired = signed_token(&keys, |claims| {
+            claims.exp = (Utc::now() - Duration::hours(1)).timestamp()
+        });
+        let wrong_issuer = signed_token(&keys, |claims| claims.iss = "other".to_string());
+        // Changing the first character of the signature keeps it decodable, but wrong.
+        let signature_at = valid.rfind('.').unwrap() + 1;
+        let replacement = if valid[signature_at..].starts_with('A') {
+            "B"
+        } else {
+            "A"
+        };
+        let bad_signature = format!(
+            "{}{}{}",
+            &valid[..signature_at],
+            replacement,
+            &valid[signature_at + 1..]
+        );
+        let signed_elsewhere = signed_token(&test_key_store(KeyAlgorithm::ES256), |_| {});
+
+        for (token, reason) in &[
+            (expired, InactiveReason::Expired),
+            (wrong_issuer, InactiveReason::WrongIssuer),
+            (bad_signature, InactiveReason::BadSignature),
+            (signed_elsewhere, InactiveReason::BadSignature),
+            ("not.a.jwt".to_string(), InactiveReason::Malformed),
+        ] {
+            let response = introspect(&keys, token);
+            assert!(!response.active);
+            assert_eq!(response.inactive_reason, *reason as i32);
+            assert!(response.sub.is_empty());
+        }
+    }
+
+    /// Creates an identity from a provider, with a new subject.
+    fn external_identity(email: &str) -> ExternalIdentity {
+        ExternalIdentity {
+            provider: "google".to_string(),
+            subject: uuid::Uuid::new_v4().to_string(),
+            email: email.to_string(),
+            given_name: "Test".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_external_identity_is_not_linked_by_email() {
+        let mut tx = test_transaction().await;
+        let account = register_test_account(&mut tx, Some("Correct7Horse")).await;
+        let identity = external_identity(&account.email.to_uppercase());
+        let subject = identity.subject.clone();
+
+        let result = find_or_register_external(&mut tx, identity, "").await;
+        assert!(matches!(result, Err(AuthError::EmailNotLinked(email)) if email == account.email));
+        assert!(tx
+            .find_identity("google", &subject)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(tx
+            .get_account(account.id)
+            .await
+            .unwrap()
+            .email_verified_at
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_external_identity_registers_a_verified_account_once() {
+        let mut tx = test_transaction().await;
+        let identity = external_identity(&format!("{}@example.com", uuid::Uuid::new_v4()));
+
+        let (account, registered) = find_or_register_external(&mut tx, identity.clone(), "")
+            .await
+            .unwrap();
+        assert!(registered);
+        assert!(account.email_verified_at.is_some());
+
+        let (signed_in, registered) = find_or_register_external(&mut tx, identity, "")
+            .await
+            .unwrap();
+        assert!(!registered);
+        assert_eq!(signed_in.id, account.id);
+    }
+
+    /// Creates a policy that only checks the length of passwords.
+    fn test_password_policy() -> PasswordPolicy {
+        PasswordPolicy::new(8, 128, Vec::new(), HashSet::new(), None)
+    }
+
+    /// Creates an identity for an account.
+    fn identity_create(account: &Account, provider: &str, subject: Option<&str>) -> IdentityCreate {
+        IdentityCreate {
+            account_id: account.id,
+            provider: provider.to_string(),
+            subject: subject.map(str::to_string),
+        }
+    }
+
+    /// Gets the providers of an account's identities.
+    async fn linked_providers(conn: &mut PgConnection, account: &Account) -> Vec<String> {
+        let mut providers: Vec<_> = conn
+            .get_identities_for_account(account.id)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|identity| identity.provider)
+            .collect();
+        providers.sort();
+        providers
+    }
+
+    #[tokio::test]
+    async fn test_identity_is_linked_to_one_account() {
+        let mut tx = test_transaction().await;
+        let policy = test_password_policy();
+        let account = register_test_account(&mut tx, None).await;
+        let other = register_test_account(&mut tx, None).await;
+        let subject = uuid::Uuid::new_v4().to_string();
+
+        let google = identity_create(&account, "google", Some(&subject));
+        let linked = link_account_identity(&mut tx, &google, "", &policy)
+            .await
+            .unwrap();
+        assert_eq!(linked.subject.as_deref(), Some(subject.as_str()));
+        assert_eq!(linked_providers(&mut tx, &account).await, vec!["google"]);
+
+        for duplicate in &[
+            identity_create(&account, "google", Some("another subject")),
+            identity_create(&other, "google", Some(&subject)),
+        ] {
+            assert!(matches!(
+                link_account_identity(&mut tx, duplicate, "", &policy).await,
+                Err(AuthError::IdentityAlreadyLinked(provider)) if provider == "google"
+            ));
+        }
+
+        let password = identity_create(&account, PASSWORD_PROVIDER, None);
+        assert!(matches!(
+            link_account_identity(&mut tx, &password, "short", &policy).await,
+            Err(AuthError::InvalidFields(_))
+        ));
+        link_account_identity(&mut tx, &password, "Correct7Horse", &policy)
+            .await
+            .unwrap();
+        assert_eq!(
+            linked_providers(&mut tx, &account).await,
+            vec!["google", PASSWORD_PROVIDER]
+        );
+        assert!(tx.get_account(account.id).await.unwrap().hash.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_only_identity_cannot_be_unlinked() {
+        let mut tx = test_transaction().await;
+        let account = register_test_account(&mut tx, Some("Correct7Horse")).await;
+        for (provider, subject) in &[(PASSWORD_PROVIDER, None), ("google", Some("subject"))] {
+            let subject = subject.map(|subject| format!("{}-{}", subject, account.id));
+            tx.add_identity(&identity_create(&account, provider, subject.as_deref()))
+                .await
+                .unwrap();
+        }
+
+        assert!(matches!(
+            unlink_account_identity(&mut tx, account.id, "github").await,
+            Err(AuthError::IdentityNotFound(provider)) if provider == "github"
+        ));
+
+        unlink_account_identity(&mut tx, account.id, PASSWORD_PROVIDER)
+            .await
+            .unwrap();
+        assert_eq!(linked_providers(&mut tx, &account).await, vec!["google"]);
+        assert!(tx.get_account(account.id).await.unwrap().hash.is_none());
+
+        assert!(matches!(
+            unlink_account_identity(&mut tx, account.id, "google").await,
+            Err(AuthError::LastIdentity)
+        ));
+        assert_eq!(linked_providers(&mut tx, &account).await, vec!["google"]);
+    }
+
+    /// Makes every insert into `refresh_tokens` fail, until the end of the transaction.
+    async fn fail_refresh_token_inserts(conn: &mut PgConnection) {
+        for statement in &[
+            "CREATE FUNCTION pg_temp.fail_insert() RETURNS trigger AS $$ \
+             BEGIN RAISE EXCEPTION 'insert failed'; END $$ LANGUAGE plpgsql",
+            "CREATE TRIGGER fail_insert BEFORE INSERT ON refresh_tokens \
+             FOR EACH ROW EXECUTE PROCEDURE pg_temp.fail_insert()",
+        ] {
+            sqlx::query(statement).execute(&mut *conn).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_registration_leaves_nothing_behind() {
+        let tx = test_transaction().await;
+        let account_register = AccountRegister {
+            given_name: "Test".to_string(),
+            email: format!("{}@example.com", uuid::Uuid::new_v4()),
+            password: Some("Correct7Horse".to_string()),
+        };
+
+        // Registration fails in a savepoint, which is rolled back like a failed transaction.
+        let mut savepoint = tx.begin().await.unwrap();
+        fail_refresh_token_inserts(&mut savepoint).await;
+        let result = register_password_account(&mut savepoint, &account_register, "").await;
+        assert!(matches!(result, Err(AuthError::DatabaseError(_))));
+        let mut tx = savepoint.rollback().await.unwrap();
+
+        assert!(tx
+            .find_account_by_email(&account_register.email)
+            .await
+            .unwrap()
+            .is_none());
+
+        // The same email address can then be registered.
+        let (account, refresh_token) = register_password_account(&mut tx, &account_register, "")
+            .await
+            .unwrap();
+        assert_eq!(refresh_token.refresh_token.account_id, account.id);
+        let identities = tx.get_identities_for_account(account.id).await.unwrap();
+        assert_eq!(identities.len(), 1);
+        assert_eq!(identities[0].provider, PASSWORD_PROVIDER);
+    }
+
+    #[tokio::test]
+    async fn test_password_reset_is_single_use_and_revokes_every_session() {
+        let mut tx = test_transaction().await;
+        let account = register_test_account(&mut tx, Some("Correct7Horse")).await;
+        let sessions = vec![
+            tx.issue_refresh_token(account.id).await.unwrap(),
+            tx.issue_refresh_token(account.id).await.unwrap(),
+        ];
+        let reset_request = ResetPasswordRequest {
+            token: tx.issue_password_reset(account.id).await.unwrap(),
+            password: "Battery9Staple".to_string(),
+        };
+        let mut event = AuditEventCreate::new(AuditEventKind::PasswordChange, None, None);
+
+        reset_password_with_token(&mut tx, &reset_request, &test_password_policy(), &mut event)
+            .await
+            .unwrap();
+        assert_eq!(event.account_id, Some(account.id));
+        let hash = tx.get_account(account.id).await.unwrap().hash.unwrap();
+        assert!(Argon2id::verify_password("Battery9Staple", &hash).unwrap());
+        // The account had no password identity, so one is added.
+        assert_eq!(
+            linked_providers(&mut tx, &account).await,
+            vec![PASSWORD_PROVIDER]
+        );
+        for session in &sessions {
+            let refresh_token = tx.get_refresh_token(&session.token).await.unwrap();
+            assert!(refresh_token.revoked);
+        }
+
+        let reused = ResetPasswordRequest {
+            password: "Another8Password".to_string(),
+            ..reset_request
+        };
+        assert!(matches!(
+            reset_password_with_token(&mut tx, &reused, &test_password_policy(), &mut event).await,
+            Err(AuthError::InvalidPasswordResetToken)
+        ));
+        let hash = tx.get_account(account.id).await.unwrap().hash.unwrap();
+        assert!(Argon2id::verify_password("Battery9Staple", &hash).unwrap());
+    }
+
+    /// Creates a request to change a password, keeping the session of a refresh token.
+    fn change_request(
+        current_password: &str,
+        new_password: &str,
+        keep: &str,
+    ) -> ChangePasswordRequest {
+        ChangePasswordRequest {
+            current_password: current_password.to_string(),
+            new_password: new_password.to_string(),
+            revoke_other_sessions: !keep.is_empty(),
+            refresh_token: keep.to_string(),
+        }
+    }
+
+    /// Creates lockout rules that lock an email address out after its first failure.
+    fn strict_throttle_policy() -> LoginThrottlePolicy {
+        LoginThrottlePolicy::new(
+            0,
+            10,
+            Duration::minutes(1),
+            Duration::minutes(5),
+            Duration::days(1),
+        )
+    }
+
+    /// Checks whether a password is an account's current password.
+    async fn has_password(conn: &mut PgConnection, account: &Account, password: &str) -> bool {
+        let hash = conn.get_account(account.id).await.unwrap().hash.unwrap();
+        Argon2id::verify_password(password, &hash).unwrap()
+    }
+
+    #[test]
+    fn test_sign_ins_behind_a_proxy_are_counted_by_their_client() {
+        let rate_limiter = RateLimiter::new(RateLimitConfig {
+            trusted_proxies: vec!["10.0.0.1".parse().unwrap()],
+            default: None,
+            methods: HashMap::new(),
+        });
+        let ip_key = |peer_ip: &str, forwarded_for: Option<&str>| {
+            let client_ip = rate_limiter.client_ip(Some(peer_ip.parse().unwrap()), forwarded_for);
+            login_throttle_keys(
+                "user@example.com",
+                login_throttle_ip(&rate_limiter, client_ip),
+            )
+            .into_iter()
+            .find(|(scope, _)| *scope == ThrottleScope::Ip)
+            .map(|(_, key)| key)
+        };
+
+        assert_eq!(
+            ip_key("10.0.0.1", Some("203.0.113.7")),
+            Some("203.0.113.7".to_string())
+        );
+        assert_eq!(ip_key("203.0.113.8", None), Some("203.0.113.8".to_string()));
+        // Counting by the proxy's own address would lock out everyone behind it.
+        assert_eq!(ip_key("10.0.0.1", None), None);
+    }
+
+    #[tokio::test]
+    async fn test_wrong_current_password_is_counted_as_a_failed_sign_in() {
+        let mut tx = test_transaction().await;
+        let account = register_test_account(&mut tx, Some("Correct7Horse")).await;
+        let (policy, throttle_policy) = (test_password_policy(), strict_throttle_policy());
+
+        let wrong = change_request("Wrong7Horse", "Battery9Staple", "");
+        assert!(matches!(
+            change_account_password(&mut tx, account.id, &wrong, &policy, &throttle_policy).await,
+            Err(AuthError::InvalidUsernameOrPassword)
+        ));
+        let (scope, key) = &login_throttle_keys(&account.email, None)[0];
+        assert!(tx.get_lockout(*scope, key).await.unwrap().is_some());
+
+        // While the email address is locked out, even the right password is refused.
+        let right = change_request("Correct7Horse", "Battery9Staple", "");
+        assert!(matches!(
+            change_account_password(&mut tx, account.id, &right, &policy, &throttle_policy).await,
+            Err(AuthError::TooManyAttempts(_))
+        ));
+        assert!(has_password(&mut tx, &account, "Correct7Horse").await);
+    }
+
+    #[tokio::test]
+    async fn test_new_password_must_meet_the_policy() {
+        let mut tx = test_transaction().await;
+        let account = register_test_account(&mut tx, Some("Correct7Horse")).await;
+        let (policy, throttle_policy) = (test_password_policy(), strict_throttle_policy());
+
+        let too_short = change_request("Correct7Horse", "short", "");
+        assert!(matches!(
+            change_account_password(&mut tx, account.id, &too_short, &policy, &throttle_policy)
+                .await,
+            Err(AuthError::InvalidFields(_))
+        ));
+        assert!(has_password(&mut tx, &account, "Correct7Horse").await);
+
+        let valid = change_request("Correct7Horse", "Battery9Staple", "");
+        let revoked =
+            change_account_password(&mut tx, account.id, &valid, &policy, &throttle_policy)
+                .await
+                .unwrap();
+        assert!(!revoked);
+        assert!(has_password(&mut tx, &account, "Battery9Staple").await);
+    }
+
+    #[tokio::test]
+    async fn test_password_change_revokes_only_other_sessions() {
+        let mut tx = test_transaction().await;
+        let account = register_test_account(&mut tx, Some("Correct7Horse")).await;
+        let other_account = register_test_account(&mut tx, None).await;
+        let caller = tx.issue_refresh_token(account.id).await.unwrap();
+        let other = tx.issue_refresh_token(account.id).await.unwrap();
+        let elsewhere = tx.issue_refresh_token(other_account.id).await.unwrap();
+        let (policy, throttle_policy) = (test_password_policy(), strict_throttle_policy());
+
+        // Another account's session cannot be the one kept.
+        let request = change_request("Correct7Horse", "Battery9Staple", &elsewhere.token);
+        assert!(matches!(
+            change_account_password(&mut tx, account.id, &request, &policy, &throttle_policy).await,
+            Err(AuthError::InvalidRefreshToken)
+        ));
+
+        let request = change_request("Correct7Horse", "Battery9Staple", &caller.token);
+        let revoked =
+            change_account_password(&mut tx, account.id, &request, &policy, &throttle_policy)
+                .await
+                .unwrap();
+        assert!(revoked);
+        assert!(!tx.get_refresh_token(&caller.token).await.unwrap().revoked);
+        assert!(tx.get_refresh_token(&other.token).await.unwrap().revoked);
+        assert!(
+            !tx.get_refresh_token(&elsewhere.token)
+                .await
+                .unwrap()
+                .revoked
+        );
+    }
+
+    #[tokio::test]
+    async fn test_revoked_refresh_token_is_inactive() {
+        let mut tx = test_transaction().await;
+        let account = register_test_account(&mut tx, None).await;
+        let issued = tx.issue_refresh_token(account.id).await.unwrap();
+
+        let response = introspect_refresh_token(&issued.refresh_token);
+        assert!(response.active);
+        assert_eq!(response.inactive_reason, InactiveReason::None as i32);
+
+        tx.revoke_all_tokens_for_account(account.id).await.unwrap();
+        let revoked = tx.get_refresh_token(&issued.token).await.unwrap();
+        let response = introspect_refresh_token(&revoked);
+        assert!(!response.active && response.revoked);
+        assert_eq!(response.inactive_reason, InactiveReason::Revoked as i32);
+    }
+
+    /// Creates a service without identity providers or a mail API, checked against a rate limiter.
+    async fn test_service(rate_limiter: Arc<RateLimiter>) -> AuthService {
+        let database_url = dotenv::var("DATABASE_URL").expect("DATABASE_URL must be set");
+
+        AuthService::new(
+            connect(&database_url)
+                .await
+                .expect("failed to connect to the test database"),
+            Arc::new(test_key_manager().await),
+            ProviderRegistry::new(Vec::new()).unwrap(),
+            Arc::new(Mailer::new(None)),
+            test_password_policy(),
+            strict_throttle_policy(),
+            rate_limiter,
+            "admin key",
+        )
+    }
+
+    /// Serves a service on a free local port, and connects a client to it.
+    async fn serve(service: AuthService) -> AuthClient<Channel> {
+        // The port is found by binding to it, then freed for the server.
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap();
+        tokio::spawn(async move {
+            service.run_server(addr).await.ok();
+        });
+
+        for _ in 0..50 {
+            if let Ok(client) = AuthClient::connect(format!("http://{}", addr)).await {
+                return client;
+            }
+            tokio::time::delay_for(std::time::Duration::from_millis(20)).await;
+        }
+        panic!("the test server did not start on {}", addr);
+    }
+
+    #[tokio::test]
+    async fn test_clients_behind_a_proxy_have_their_own_rate_limits() {
+        // The test client connects from the loopback address, which is trusted as a proxy.
+        let mut methods = HashMap::new();
+        methods.insert(
+            "GetJwks".to_string(),
+            BucketConfig {
+                burst: 1,
+                per_minute: 1,
+            },
+        );
+        let rate_limiter = Arc::new(RateLimiter::new(RateLimitConfig {
+            trusted_proxies: vec!["127.0.0.1".parse().unwrap()],
+            default: None,
+            methods,
+        }));
+        let mut client = serve(test_service(rate_limiter).await).await;
+        let get_jwks = |client_ip: &str| {
+            let mut request = Request::new(GetJwksRequest {});
+            request
+                .metadata_mut()
+                .insert("x-forwarded-for", client_ip.parse().unwrap());
+            request
+        };
+
+        assert!(client.get_jwks(get_jwks("203.0.113.7")).await.is_ok());
+        let limited = client.get_jwks(get_jwks("203.0.113.7")).await.unwrap_err();
+        assert_eq!(limited.code(), tonic::Code::ResourceExhausted);
+
+        // Without the peer's address, the header would be ignored and every client would share
+        // one bucket.
+        assert!(client.get_jwks(get_jwks("203.0.113.8")).await.is_ok());
     }
 }